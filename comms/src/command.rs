@@ -1,5 +1,42 @@
 use serde::{Deserialize, Serialize};
 
+use crate::event::RoomRole;
+
+/// User Command for logging in with an existing account.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginCommand {
+    // The name of the account to log in with.
+    #[serde(rename = "u")]
+    pub username: String,
+    // The password of the account.
+    #[serde(rename = "p")]
+    pub password: String,
+}
+
+/// User Command for creating a new account and logging in with it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegisterCommand {
+    // The name of the account to create.
+    #[serde(rename = "u")]
+    pub username: String,
+    // The password of the account.
+    #[serde(rename = "p")]
+    pub password: String,
+}
+
+/// User Command for resuming a session whose connection has been lost, instead of logging in again.
+/// Events are not numbered on the wire, the client counts every event it receives after logging in or resuming,
+/// starting at 1, and the server replays the ones it has sent after the last one the client has seen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeCommand {
+    // The session id handed out with the login successful event.
+    #[serde(rename = "s")]
+    pub session_id: String,
+    // The number of the last event the client has received, zero if it has received none.
+    #[serde(rename = "sq")]
+    pub last_seen_seq: u64,
+}
+
 /// User Command for joining a room.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct JoinRoomCommand {
@@ -27,6 +64,180 @@ pub struct SendMessageCommand {
     pub content: String,
 }
 
+/// User Command for sending a private message to a single user.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SendDirectMessageCommand {
+    // The user to send the message to.
+    #[serde(rename = "to")]
+    pub to_user_id: String,
+    // The content of the message.
+    #[serde(rename = "c")]
+    pub content: String,
+}
+
+/// User Command for fetching a page of older messages from a room's history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchHistoryCommand {
+    // The room to fetch the history of.
+    #[serde(rename = "r")]
+    pub room: String,
+    // Only messages older than this message are fetched, the most recent messages are fetched if empty.
+    #[serde(rename = "b")]
+    pub before_message_id: Option<u64>,
+    // The maximum number of messages to fetch, the server may cap it further.
+    #[serde(rename = "l")]
+    pub limit: usize,
+}
+
+/// User Command for creating a new room on the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CreateRoomCommand {
+    // The slug of the new room, must be unique across the server.
+    #[serde(rename = "r")]
+    pub room: String,
+    // The description of the new room.
+    #[serde(rename = "d")]
+    pub description: String,
+}
+
+/// User Command for deleting a room and its history from the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeleteRoomCommand {
+    // The room to delete.
+    #[serde(rename = "r")]
+    pub room: String,
+}
+
+/// User Command for removing a user from a room, they can join again later.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KickCommand {
+    // The room to kick the user from.
+    #[serde(rename = "r")]
+    pub room: String,
+    // The user to kick.
+    #[serde(rename = "u")]
+    pub user_id: String,
+}
+
+/// User Command for removing a user from a room and preventing them from joining it again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BanCommand {
+    // The room to ban the user from.
+    #[serde(rename = "r")]
+    pub room: String,
+    // The user to ban.
+    #[serde(rename = "u")]
+    pub user_id: String,
+}
+
+/// User Command for allowing a banned user to join a room again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnbanCommand {
+    // The room to unban the user from.
+    #[serde(rename = "r")]
+    pub room: String,
+    // The user to unban.
+    #[serde(rename = "u")]
+    pub user_id: String,
+}
+
+/// User Command for preventing a user from sending messages to a room for a while.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MuteCommand {
+    // The room to mute the user in.
+    #[serde(rename = "r")]
+    pub room: String,
+    // The user to mute.
+    #[serde(rename = "u")]
+    pub user_id: String,
+    // How long the user stays muted in seconds, zero lifts an existing mute.
+    #[serde(rename = "d")]
+    pub duration_secs: u64,
+}
+
+/// User Command for promoting a user to an operator of a room or demoting them back to a member.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SetRoleCommand {
+    // The room to change the role of the user in.
+    #[serde(rename = "r")]
+    pub room: String,
+    // The user to change the role of.
+    #[serde(rename = "u")]
+    pub user_id: String,
+    // The new role of the user, a room can not be handed over to another owner.
+    #[serde(rename = "ro")]
+    pub role: RoomRole,
+}
+
+/// The encoding of the commands and events exchanged over a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// JSON, one command or event per line, easy to read and debug
+    #[default]
+    Json,
+    /// MessagePack, each command or event prefixed with its length, more compact and cheaper to encode
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+/// User Command for switching the encoding of the connection.
+/// Only valid as the very first command of a connection, before logging in,
+/// both sides switch to the codec once the server has replied with [crate::event::Event::CodecSelected].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SelectCodecCommand {
+    // The codec the client wants to use for the rest of the connection.
+    #[serde(rename = "cd")]
+    pub codec: Codec,
+}
+
+/// The version of the protocol spoken by this library, bumped whenever a change breaks older peers
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// An optional feature of the protocol, which a client has to switch on for its connection
+/// by listing it in its [HelloCommand]. Clients which never say hello get every feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Sending and receiving direct messages
+    DirectMessages,
+    /// Fetching older messages of a room
+    History,
+    /// Moderating rooms and being notified about moderation
+    Moderation,
+    /// Receiving the whole room list whenever the server reloads it
+    RoomList,
+    /// A capability of a newer peer which this library does not know about
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    /// Every capability known to this library
+    pub const ALL: [Capability; 4] = [
+        Capability::DirectMessages,
+        Capability::History,
+        Capability::Moderation,
+        Capability::RoomList,
+    ];
+}
+
+/// User Command for introducing the client to the server.
+/// Only valid as the first command of a session, the server replies with [crate::event::Event::Hello]
+/// and ends the session if it does not speak the protocol version of the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HelloCommand {
+    // The protocol version the client speaks.
+    #[serde(rename = "pv")]
+    pub protocol_version: u32,
+    // The name of the client e.g. for logging.
+    #[serde(rename = "cn")]
+    pub client_name: String,
+    // The optional features the client wants to use.
+    #[serde(rename = "cp")]
+    pub capabilities: Vec<Capability>,
+}
+
 /// User Command for quitting the whole chat session.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct QuitCommand;
@@ -36,12 +247,63 @@ pub struct QuitCommand;
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "_ct", rename_all = "snake_case")]
 pub enum UserCommand {
+    SelectCodec(SelectCodecCommand),
+    Hello(HelloCommand),
+    Login(LoginCommand),
+    Register(RegisterCommand),
+    Resume(ResumeCommand),
     JoinRoom(JoinRoomCommand),
     LeaveRoom(LeaveRoomCommand),
     SendMessage(SendMessageCommand),
+    SendDirectMessage(SendDirectMessageCommand),
+    FetchHistory(FetchHistoryCommand),
+    CreateRoom(CreateRoomCommand),
+    DeleteRoom(DeleteRoomCommand),
+    Kick(KickCommand),
+    Ban(BanCommand),
+    Unban(UnbanCommand),
+    Mute(MuteCommand),
+    SetRole(SetRoleCommand),
     Quit(QuitCommand),
 }
 
+/// A [UserCommand] alongside with an optional id chosen by the client to correlate the reply of the server.
+/// Commands with a request id are answered with an [crate::event::Event::Ack] once they are processed,
+/// or with an [crate::event::Event::Nack] if they have failed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserCommandEnvelope {
+    #[serde(flatten)]
+    pub command: UserCommand,
+    // The id chosen by the client, the server does not interpret it in any way.
+    #[serde(rename = "rid", default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u64>,
+}
+
+impl UserCommand {
+    /// The capability a connection needs to send this command, [None] if every connection can send it
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            UserCommand::SendDirectMessage(_) => Some(Capability::DirectMessages),
+            UserCommand::FetchHistory(_) => Some(Capability::History),
+            UserCommand::Kick(_)
+            | UserCommand::Ban(_)
+            | UserCommand::Unban(_)
+            | UserCommand::Mute(_)
+            | UserCommand::SetRole(_) => Some(Capability::Moderation),
+            _ => None,
+        }
+    }
+}
+
+impl From<UserCommand> for UserCommandEnvelope {
+    fn from(command: UserCommand) -> Self {
+        UserCommandEnvelope {
+            command,
+            request_id: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -54,6 +316,36 @@ mod tests {
         assert_eq!(deserialized, *command);
     }
 
+    #[test]
+    fn test_login_command() {
+        let command = UserCommand::Login(LoginCommand {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"login","u":"user","p":"secret"}"#);
+    }
+
+    #[test]
+    fn test_register_command() {
+        let command = UserCommand::Register(RegisterCommand {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"register","u":"user","p":"secret"}"#);
+    }
+
+    #[test]
+    fn test_resume_command() {
+        let command = UserCommand::Resume(ResumeCommand {
+            session_id: "session-id-1".to_string(),
+            last_seen_seq: 42,
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"resume","s":"session-id-1","sq":42}"#);
+    }
+
     #[test]
     fn test_join_command() {
         let command = UserCommand::JoinRoom(JoinRoomCommand {
@@ -82,10 +374,179 @@ mod tests {
         assert_command_serialization(&command, r#"{"_ct":"send_message","r":"test","c":"test"}"#);
     }
 
+    #[test]
+    fn test_direct_message_command() {
+        let command = UserCommand::SendDirectMessage(SendDirectMessageCommand {
+            to_user_id: "test".to_string(),
+            content: "test".to_string(),
+        });
+
+        assert_command_serialization(
+            &command,
+            r#"{"_ct":"send_direct_message","to":"test","c":"test"}"#,
+        );
+    }
+
+    #[test]
+    fn test_fetch_history_command() {
+        let command = UserCommand::FetchHistory(FetchHistoryCommand {
+            room: "test".to_string(),
+            before_message_id: Some(42),
+            limit: 10,
+        });
+
+        assert_command_serialization(
+            &command,
+            r#"{"_ct":"fetch_history","r":"test","b":42,"l":10}"#,
+        );
+    }
+
+    #[test]
+    fn test_create_room_command() {
+        let command = UserCommand::CreateRoom(CreateRoomCommand {
+            room: "test".to_string(),
+            description: "test".to_string(),
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"create_room","r":"test","d":"test"}"#);
+    }
+
+    #[test]
+    fn test_delete_room_command() {
+        let command = UserCommand::DeleteRoom(DeleteRoomCommand {
+            room: "test".to_string(),
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"delete_room","r":"test"}"#);
+    }
+
+    #[test]
+    fn test_kick_command() {
+        let command = UserCommand::Kick(KickCommand {
+            room: "test".to_string(),
+            user_id: "test".to_string(),
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"kick","r":"test","u":"test"}"#);
+    }
+
+    #[test]
+    fn test_ban_command() {
+        let command = UserCommand::Ban(BanCommand {
+            room: "test".to_string(),
+            user_id: "test".to_string(),
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"ban","r":"test","u":"test"}"#);
+    }
+
+    #[test]
+    fn test_unban_command() {
+        let command = UserCommand::Unban(UnbanCommand {
+            room: "test".to_string(),
+            user_id: "test".to_string(),
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"unban","r":"test","u":"test"}"#);
+    }
+
+    #[test]
+    fn test_mute_command() {
+        let command = UserCommand::Mute(MuteCommand {
+            room: "test".to_string(),
+            user_id: "test".to_string(),
+            duration_secs: 60,
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"mute","r":"test","u":"test","d":60}"#);
+    }
+
+    #[test]
+    fn test_set_role_command() {
+        let command = UserCommand::SetRole(SetRoleCommand {
+            room: "test".to_string(),
+            user_id: "test".to_string(),
+            role: RoomRole::Operator,
+        });
+
+        assert_command_serialization(
+            &command,
+            r#"{"_ct":"set_role","r":"test","u":"test","ro":"operator"}"#,
+        );
+    }
+
+    #[test]
+    fn test_select_codec_command() {
+        let command = UserCommand::SelectCodec(SelectCodecCommand {
+            codec: Codec::MessagePack,
+        });
+
+        assert_command_serialization(&command, r#"{"_ct":"select_codec","cd":"msgpack"}"#);
+    }
+
+    #[test]
+    fn test_hello_command() {
+        let command = UserCommand::Hello(HelloCommand {
+            protocol_version: 1,
+            client_name: "tui".to_string(),
+            capabilities: vec![Capability::History, Capability::RoomList],
+        });
+
+        assert_command_serialization(
+            &command,
+            r#"{"_ct":"hello","pv":1,"cn":"tui","cp":["history","room_list"]}"#,
+        );
+    }
+
+    #[test]
+    fn test_hello_command_with_unknown_capability() {
+        let command: UserCommand =
+            serde_json::from_str(r#"{"_ct":"hello","pv":2,"cn":"tui","cp":["typing","history"]}"#)
+                .unwrap();
+
+        assert_eq!(
+            command,
+            UserCommand::Hello(HelloCommand {
+                protocol_version: 2,
+                client_name: "tui".to_string(),
+                capabilities: vec![Capability::Unknown, Capability::History],
+            })
+        );
+    }
+
     #[test]
     fn test_quit_command() {
         let command = UserCommand::Quit(QuitCommand);
 
         assert_command_serialization(&command, r#"{"_ct":"quit"}"#);
     }
+
+    #[test]
+    fn test_command_envelope() {
+        let envelope = UserCommandEnvelope {
+            command: UserCommand::SendMessage(SendMessageCommand {
+                room: "test".to_string(),
+                content: "test".to_string(),
+            }),
+            request_id: Some(7),
+        };
+
+        let serialized = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(
+            serialized,
+            r#"{"_ct":"send_message","r":"test","c":"test","rid":7}"#
+        );
+        let deserialized: UserCommandEnvelope = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, envelope);
+    }
+
+    #[test]
+    fn test_command_envelope_without_request_id() {
+        let envelope = UserCommandEnvelope::from(UserCommand::Quit(QuitCommand));
+
+        let serialized = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(serialized, r#"{"_ct":"quit"}"#);
+        let deserialized: UserCommandEnvelope = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, envelope);
+    }
 }
@@ -1,5 +1,8 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::command::{Capability, Codec, UserCommand};
+
 /// The detail of a given room
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RoomDetail {
@@ -11,6 +14,17 @@ pub struct RoomDetail {
     pub description: String,
 }
 
+/// The server has greeted the client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HelloReplyEvent {
+    /// The protocol versions the server speaks, the session ends if the version of the client is not one of them
+    #[serde(rename = "pvs")]
+    pub supported_versions: Vec<u32>,
+    /// The capabilities switched on for the connection, those asked for by the client which the server supports
+    #[serde(rename = "cp")]
+    pub capabilities: Vec<Capability>,
+}
+
 /// A user has successfully logged in
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LoginSuccessfulReplyEvent {
@@ -25,6 +39,37 @@ pub struct LoginSuccessfulReplyEvent {
     pub rooms: Vec<RoomDetail>,
 }
 
+/// A session has been resumed over a new connection, instead of logging in again
+/// The events sent after the last one the client has seen follow right after, the ones the server no longer keeps
+/// are skipped and the client continues counting from [ResumedReplyEvent::seq].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumedReplyEvent {
+    /// The session id of the resumed session, which stays the same
+    #[serde(rename = "s")]
+    pub session_id: String,
+    /// The id of the user of the session
+    #[serde(rename = "u")]
+    pub user_id: String,
+    /// The list of rooms the user can participate, unique and ordered
+    #[serde(rename = "rs")]
+    pub rooms: Vec<RoomDetail>,
+    /// The rooms the session is still in, unique and ordered
+    #[serde(rename = "jr")]
+    pub joined_rooms: Vec<String>,
+    /// The number of the last event before the replayed ones,
+    /// greater than the last seen one of the client if some of the missed events could not be replayed
+    #[serde(rename = "sq")]
+    pub seq: u64,
+}
+
+/// A user could not log in or register, the session stays unauthenticated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginFailedReplyEvent {
+    /// Human readable reason of the failure
+    #[serde(rename = "rn")]
+    pub reason: String,
+}
+
 /// Users new room participation status
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -56,6 +101,9 @@ pub struct UserJoinedRoomReplyEvent {
     /// The users currently in the room, unique and ordered
     #[serde(rename = "us")]
     pub users: Vec<String>,
+    /// The most recent messages sent to the room before the user joined, from oldest to newest
+    #[serde(rename = "ms")]
+    pub messages: Vec<UserMessageBroadcastEvent>,
 }
 
 /// A user has sent a message to a room
@@ -64,6 +112,12 @@ pub struct UserMessageBroadcastEvent {
     /// The slug of the room the user has sent the message to
     #[serde(rename = "r")]
     pub room: String,
+    /// The id of the message, unique and increasing within the room
+    #[serde(rename = "i")]
+    pub id: u64,
+    /// The time the server has received the message
+    #[serde(rename = "t")]
+    pub timestamp: DateTime<Utc>,
     /// The id of the user that has sent the message
     #[serde(rename = "u")]
     pub user_id: String,
@@ -72,21 +126,279 @@ pub struct UserMessageBroadcastEvent {
     pub content: String,
 }
 
+/// A user has sent a private message to another user
+/// Sent to every session of both the sender and the recipient
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DirectMessageEvent {
+    /// The id of the user that has sent the message
+    #[serde(rename = "f")]
+    pub from_user_id: String,
+    /// The id of the user the message is sent to
+    #[serde(rename = "to")]
+    pub to_user_id: String,
+    /// The time the server has received the message
+    #[serde(rename = "t")]
+    pub timestamp: DateTime<Utc>,
+    /// The content of the message
+    #[serde(rename = "c")]
+    pub content: String,
+}
+
+/// A reply to the user with a page of older messages from a room's history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPageReplyEvent {
+    /// The slug of the room the messages belong to
+    #[serde(rename = "r")]
+    pub room: String,
+    /// The messages of the page, from oldest to newest
+    #[serde(rename = "ms")]
+    pub messages: Vec<UserMessageBroadcastEvent>,
+    /// Whether there are even older messages to fetch
+    #[serde(rename = "hm")]
+    pub has_more: bool,
+}
+
+/// A new room has been created on the server, sent to every session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomCreatedBroadcastEvent {
+    /// The slug of the new room
+    #[serde(rename = "r")]
+    pub room: String,
+    /// The description of the new room
+    #[serde(rename = "d")]
+    pub description: String,
+}
+
+/// A room has been deleted from the server, sent to every session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomDeletedBroadcastEvent {
+    /// The slug of the deleted room
+    #[serde(rename = "r")]
+    pub room: String,
+}
+
+/// The set of rooms has been reloaded on the server, sent to every session
+/// Rooms which are no longer listed have been closed, and are announced with [RoomDeletedBroadcastEvent]s beforehand
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomListBroadcastEvent {
+    /// The list of rooms the user can participate, unique and ordered
+    #[serde(rename = "rs")]
+    pub rooms: Vec<RoomDetail>,
+}
+
+/// The role of a user within a room, roles are ordered from the least to the most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomRole {
+    Member,
+    /// Can kick, ban, unban and mute members
+    Operator,
+    /// Created the room, can additionally promote members to operators and delete the room
+    Owner,
+}
+
+/// What has been done to a user by a room operator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "k", rename_all = "snake_case")]
+pub enum ModerationAction {
+    Kicked,
+    Banned,
+    Unbanned,
+    Muted {
+        /// The time the mute is lifted
+        #[serde(rename = "un")]
+        until: DateTime<Utc>,
+    },
+    Unmuted,
+    RoleChanged {
+        /// The new role of the user
+        #[serde(rename = "ro")]
+        role: RoomRole,
+    },
+}
+
+/// A room operator has moderated a user, sent to the room and to the affected user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModerationBroadcastEvent {
+    /// The slug of the room the moderation happened in
+    #[serde(rename = "r")]
+    pub room: String,
+    /// The id of the affected user
+    #[serde(rename = "u")]
+    pub user_id: String,
+    /// The id of the operator who moderated the user
+    #[serde(rename = "b")]
+    pub by_user_id: String,
+    /// What has been done to the user
+    #[serde(rename = "a")]
+    pub action: ModerationAction,
+}
+
+/// The kind of failure a command has run into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The command could not be parsed
+    InvalidCommand,
+    RoomNotFound,
+    RoomAlreadyExists,
+    /// The name or the description of a new room is not acceptable
+    InvalidRoom,
+    AlreadyJoined,
+    /// The command targets a room the user has not joined
+    NotJoined,
+    /// The user is not allowed to perform the command
+    Forbidden,
+    /// The server has run into a problem while processing the command
+    Internal,
+    /// The protocol version of the client is not spoken by the server
+    UnsupportedProtocolVersion,
+    /// The command belongs to a capability which has not been switched on for the connection
+    CapabilityNotEnabled,
+    /// The content of a message is longer than the server accepts
+    MessageTooLong,
+    /// A frame sent by the client is longer than the server accepts, the connection is closed
+    FrameTooLong,
+    Banned,
+    Muted,
+    UserNotInRoom,
+    UserOffline,
+    /// The session has fallen behind and events sent directly to it, such as direct messages, have been dropped
+    MissedEvents,
+}
+
+/// A command could not be processed, the session stays alive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorReplyEvent {
+    /// The kind of the failure
+    #[serde(rename = "co")]
+    pub code: ErrorCode,
+    /// Human readable description of the failure
+    #[serde(rename = "m")]
+    pub message: String,
+    /// The command that has failed, empty if the command could not be parsed
+    #[serde(rename = "cm", skip_serializing_if = "Option::is_none", default)]
+    pub command: Option<UserCommand>,
+}
+
+/// A command with a request id has been processed successfully
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AckReplyEvent {
+    /// The request id of the command
+    #[serde(rename = "rid")]
+    pub request_id: u64,
+    /// The id the message has been stored with, only for sent room messages
+    #[serde(rename = "i", default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<u64>,
+}
+
+/// A command with a request id has failed, the session stays alive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NackReplyEvent {
+    /// The request id of the command
+    #[serde(rename = "rid")]
+    pub request_id: u64,
+    /// The kind of the failure
+    #[serde(rename = "co")]
+    pub code: ErrorCode,
+    /// Human readable description of the failure
+    #[serde(rename = "m")]
+    pub message: String,
+}
+
+/// A user has sent messages faster than the server allows, the message has been dropped.
+/// It answers the command instead of an [Event::Ack] or an [Event::Nack].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitedReplyEvent {
+    /// The request id of the dropped command, if it had one
+    #[serde(rename = "rid", default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u64>,
+    /// How long to wait before the next message is accepted, in milliseconds
+    #[serde(rename = "ra")]
+    pub retry_after_millis: u64,
+    /// The time the user is muted until for flooding repeatedly, across all of their sessions
+    #[serde(rename = "mu", default, skip_serializing_if = "Option::is_none")]
+    pub muted_until: Option<DateTime<Utc>>,
+    /// The user keeps flooding, the session is closed right after this event
+    #[serde(rename = "dc", default, skip_serializing_if = "std::ops::Not::not")]
+    pub disconnected: bool,
+}
+
+/// The session has fallen too far behind a room and its events have been dropped.
+/// The missed messages which are still in the history are replayed, later events of the room follow as usual.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomLaggedReplyEvent {
+    /// The slug of the room the session has fallen behind
+    #[serde(rename = "r")]
+    pub room: String,
+    /// How many events of the room have been dropped, messages as well as participation and moderation events
+    #[serde(rename = "n")]
+    pub missed_events: u64,
+    /// The missed messages, from oldest to newest
+    #[serde(rename = "ms")]
+    pub messages: Vec<UserMessageBroadcastEvent>,
+    /// Whether even more messages have been missed than replayed, they can only be fetched as history
+    #[serde(rename = "hm")]
+    pub has_more: bool,
+}
+
+/// The server has switched the connection to the codec, which is sent encoded with the previous codec
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CodecSelectedReplyEvent {
+    /// The codec used for the rest of the connection, JSON if the requested one is not supported
+    #[serde(rename = "cd")]
+    pub codec: Codec,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "_et", rename_all = "snake_case")]
 /// Events that can be sent to the client
 /// Events maybe related to different users and rooms, the receipient is a single chat session
 pub enum Event {
+    CodecSelected(CodecSelectedReplyEvent),
+    Hello(HelloReplyEvent),
     LoginSuccessful(LoginSuccessfulReplyEvent),
+    LoginFailed(LoginFailedReplyEvent),
+    Resumed(ResumedReplyEvent),
     RoomParticipation(RoomParticipationBroacastEvent),
     UserJoinedRoom(UserJoinedRoomReplyEvent),
     UserMessage(UserMessageBroadcastEvent),
+    DirectMessage(DirectMessageEvent),
+    HistoryPage(HistoryPageReplyEvent),
+    RoomCreated(RoomCreatedBroadcastEvent),
+    RoomDeleted(RoomDeletedBroadcastEvent),
+    RoomList(RoomListBroadcastEvent),
+    Moderation(ModerationBroadcastEvent),
+    Error(ErrorReplyEvent),
+    Ack(AckReplyEvent),
+    Nack(NackReplyEvent),
+    RateLimited(RateLimitedReplyEvent),
+    RoomLagged(RoomLaggedReplyEvent),
+}
+
+impl Event {
+    /// The capability a connection needs to receive this event, [None] if every connection receives it
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            Event::DirectMessage(_) => Some(Capability::DirectMessages),
+            Event::HistoryPage(_) => Some(Capability::History),
+            Event::Moderation(_) => Some(Capability::Moderation),
+            Event::RoomList(_) => Some(Capability::RoomList),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use chrono::TimeZone;
+
     use super::*;
 
+    fn test_timestamp() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 9, 1, 12, 30, 0).unwrap()
+    }
+
     // given an event enum, and an expect string, asserts that event is serialized / deserialized appropiately
     fn assert_event_serialization(event: &Event, expected: &str) {
         let serialized = serde_json::to_string(&event).unwrap();
@@ -95,6 +407,25 @@ mod tests {
         assert_eq!(deserialized, *event);
     }
 
+    #[test]
+    fn test_codec_selected_event() {
+        let event = Event::CodecSelected(CodecSelectedReplyEvent {
+            codec: Codec::MessagePack,
+        });
+
+        assert_event_serialization(&event, r#"{"_et":"codec_selected","cd":"msgpack"}"#);
+    }
+
+    #[test]
+    fn test_hello_event() {
+        let event = Event::Hello(HelloReplyEvent {
+            supported_versions: vec![1],
+            capabilities: vec![Capability::History],
+        });
+
+        assert_event_serialization(&event, r#"{"_et":"hello","pvs":[1],"cp":["history"]}"#);
+    }
+
     #[test]
     fn test_login_successful_event() {
         let event = Event::LoginSuccessful(LoginSuccessfulReplyEvent {
@@ -112,6 +443,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_login_failed_event() {
+        let event = Event::LoginFailed(LoginFailedReplyEvent {
+            reason: "invalid username or password".to_string(),
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"login_failed","rn":"invalid username or password"}"#,
+        );
+    }
+
+    #[test]
+    fn test_resumed_event() {
+        let event = Event::Resumed(ResumedReplyEvent {
+            session_id: "session-id-1".to_string(),
+            user_id: "user-id-1".to_string(),
+            rooms: vec![RoomDetail {
+                name: "room-1".to_string(),
+                description: "some description".to_string(),
+            }],
+            joined_rooms: vec!["room-1".to_string()],
+            seq: 42,
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"resumed","s":"session-id-1","u":"user-id-1","rs":[{"n":"room-1","d":"some description"}],"jr":["room-1"],"sq":42}"#,
+        );
+    }
+
     #[test]
     fn test_room_participation_join_event() {
         let event = Event::RoomParticipation(RoomParticipationBroacastEvent {
@@ -145,11 +507,18 @@ mod tests {
         let event = Event::UserJoinedRoom(UserJoinedRoomReplyEvent {
             room: "test".to_string(),
             users: vec!["test".to_string()],
+            messages: vec![UserMessageBroadcastEvent {
+                room: "test".to_string(),
+                id: 1,
+                timestamp: test_timestamp(),
+                user_id: "test".to_string(),
+                content: "test".to_string(),
+            }],
         });
 
         assert_event_serialization(
             &event,
-            r#"{"_et":"user_joined_room","r":"test","us":["test"]}"#,
+            r#"{"_et":"user_joined_room","r":"test","us":["test"],"ms":[{"r":"test","i":1,"t":"2023-09-01T12:30:00Z","u":"test","c":"test"}]}"#,
         );
     }
 
@@ -157,13 +526,206 @@ mod tests {
     fn test_user_message_event() {
         let event = Event::UserMessage(UserMessageBroadcastEvent {
             room: "test".to_string(),
+            id: 1,
+            timestamp: test_timestamp(),
             user_id: "test".to_string(),
             content: "test".to_string(),
         });
 
         assert_event_serialization(
             &event,
-            r#"{"_et":"user_message","r":"test","u":"test","c":"test"}"#,
+            r#"{"_et":"user_message","r":"test","i":1,"t":"2023-09-01T12:30:00Z","u":"test","c":"test"}"#,
+        );
+    }
+
+    #[test]
+    fn test_direct_message_event() {
+        let event = Event::DirectMessage(DirectMessageEvent {
+            from_user_id: "test-1".to_string(),
+            to_user_id: "test-2".to_string(),
+            timestamp: test_timestamp(),
+            content: "test".to_string(),
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"direct_message","f":"test-1","to":"test-2","t":"2023-09-01T12:30:00Z","c":"test"}"#,
+        );
+    }
+
+    #[test]
+    fn test_history_page_event() {
+        let event = Event::HistoryPage(HistoryPageReplyEvent {
+            room: "test".to_string(),
+            messages: vec![UserMessageBroadcastEvent {
+                room: "test".to_string(),
+                id: 0,
+                timestamp: test_timestamp(),
+                user_id: "test".to_string(),
+                content: "test".to_string(),
+            }],
+            has_more: false,
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"history_page","r":"test","ms":[{"r":"test","i":0,"t":"2023-09-01T12:30:00Z","u":"test","c":"test"}],"hm":false}"#,
+        );
+    }
+
+    #[test]
+    fn test_room_created_event() {
+        let event = Event::RoomCreated(RoomCreatedBroadcastEvent {
+            room: "test".to_string(),
+            description: "test".to_string(),
+        });
+
+        assert_event_serialization(&event, r#"{"_et":"room_created","r":"test","d":"test"}"#);
+    }
+
+    #[test]
+    fn test_room_deleted_event() {
+        let event = Event::RoomDeleted(RoomDeletedBroadcastEvent {
+            room: "test".to_string(),
+        });
+
+        assert_event_serialization(&event, r#"{"_et":"room_deleted","r":"test"}"#);
+    }
+
+    #[test]
+    fn test_room_list_event() {
+        let event = Event::RoomList(RoomListBroadcastEvent {
+            rooms: vec![RoomDetail {
+                name: "room-1".to_string(),
+                description: "some description".to_string(),
+            }],
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"room_list","rs":[{"n":"room-1","d":"some description"}]}"#,
+        );
+    }
+
+    #[test]
+    fn test_moderation_kicked_event() {
+        let event = Event::Moderation(ModerationBroadcastEvent {
+            room: "test".to_string(),
+            user_id: "test-1".to_string(),
+            by_user_id: "test-2".to_string(),
+            action: ModerationAction::Kicked,
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"moderation","r":"test","u":"test-1","b":"test-2","a":{"k":"kicked"}}"#,
+        );
+    }
+
+    #[test]
+    fn test_moderation_muted_event() {
+        let event = Event::Moderation(ModerationBroadcastEvent {
+            room: "test".to_string(),
+            user_id: "test-1".to_string(),
+            by_user_id: "test-2".to_string(),
+            action: ModerationAction::Muted {
+                until: test_timestamp(),
+            },
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"moderation","r":"test","u":"test-1","b":"test-2","a":{"k":"muted","un":"2023-09-01T12:30:00Z"}}"#,
+        );
+    }
+
+    #[test]
+    fn test_error_event() {
+        let event = Event::Error(ErrorReplyEvent {
+            code: ErrorCode::RoomNotFound,
+            message: "room 'test' not found".to_string(),
+            command: Some(UserCommand::JoinRoom(crate::command::JoinRoomCommand {
+                room: "test".to_string(),
+            })),
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"error","co":"room_not_found","m":"room 'test' not found","cm":{"_ct":"join_room","r":"test"}}"#,
+        );
+    }
+
+    #[test]
+    fn test_error_event_without_command() {
+        let event = Event::Error(ErrorReplyEvent {
+            code: ErrorCode::InvalidCommand,
+            message: "invalid command".to_string(),
+            command: None,
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"error","co":"invalid_command","m":"invalid command"}"#,
+        );
+    }
+
+    #[test]
+    fn test_ack_event() {
+        let event = Event::Ack(AckReplyEvent {
+            request_id: 7,
+            message_id: Some(42),
+        });
+
+        assert_event_serialization(&event, r#"{"_et":"ack","rid":7,"i":42}"#);
+    }
+
+    #[test]
+    fn test_nack_event() {
+        let event = Event::Nack(NackReplyEvent {
+            request_id: 7,
+            code: ErrorCode::Muted,
+            message: "you are muted in room 'test'".to_string(),
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"nack","rid":7,"co":"muted","m":"you are muted in room 'test'"}"#,
+        );
+    }
+
+    #[test]
+    fn test_rate_limited_event() {
+        let event = Event::RateLimited(RateLimitedReplyEvent {
+            request_id: Some(7),
+            retry_after_millis: 200,
+            muted_until: Some(test_timestamp()),
+            disconnected: false,
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"rate_limited","rid":7,"ra":200,"mu":"2023-09-01T12:30:00Z"}"#,
+        );
+    }
+
+    #[test]
+    fn test_room_lagged_event() {
+        let event = Event::RoomLagged(RoomLaggedReplyEvent {
+            room: "test".to_string(),
+            missed_events: 3,
+            messages: vec![UserMessageBroadcastEvent {
+                room: "test".to_string(),
+                id: 41,
+                timestamp: test_timestamp(),
+                user_id: "user-1".to_string(),
+                content: "hello".to_string(),
+            }],
+            has_more: false,
+        });
+
+        assert_event_serialization(
+            &event,
+            r#"{"_et":"room_lagged","r":"test","n":3,"ms":[{"r":"test","i":41,"t":"2023-09-01T12:30:00Z","u":"user-1","c":"hello"}],"hm":false}"#,
         );
     }
 }
@@ -1,13 +1,19 @@
 use anyhow::Context;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{tcp::OwnedWriteHalf, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
 };
-use tokio_stream::{wrappers::LinesStream, StreamExt};
+use tokio_stream::StreamExt;
 
-use crate::{command, event};
+use crate::{
+    command::{self, Codec},
+    event,
+};
+
+use super::common::{self, BoxedStream, Frame, FrameWriter, Negotiation};
 
-use super::common::{BoxedStream, NEW_LINE};
+/// Events are not limited in length, the client trusts the server it connects to
+const MAX_FRAME_LENGTH: usize = usize::MAX;
 
 /// [EventStream] is a stream of [crate::event::Event]s sent by the server
 ///
@@ -17,32 +23,29 @@ use super::common::{BoxedStream, NEW_LINE};
 /// without the risk of missing events.
 pub type EventStream = BoxedStream<anyhow::Result<event::Event>>;
 
-/// [CommandWriter] is a wrapper around a [TcpStream] which writes [crate::command::UserCommand]s to the server
+/// [CommandWriter] is a wrapper around the write half of a stream which writes [crate::command::UserCommandEnvelope]s to the server
 pub struct CommandWriter {
-    writer: OwnedWriteHalf,
+    writer: FrameWriter,
 }
 
 impl CommandWriter {
-    pub fn new(writer: OwnedWriteHalf) -> Self {
-        Self { writer }
+    pub fn new(writer: impl AsyncWrite + Send + 'static) -> Self {
+        Self {
+            writer: FrameWriter::new(writer, Codec::Json),
+        }
     }
 
-    /// Send a [crate::command::UserCommand] to the backing [TcpStream]
+    /// Send a [crate::command::UserCommandEnvelope] to the backing stream
     ///
     /// # Cancel Safety
     ///
     /// This method is not cancellation safe. If it is used as the event
     /// in a [tokio::select!] statement and some other
-    /// branch completes first, then the provided [crate::command::UserCommand] may have been
+    /// branch completes first, then the provided [crate::command::UserCommandEnvelope] may have been
     /// partially written, but future calls to `write` will start over
     /// from the beginning of the buffer. Causing undefined behaviour.
-    pub async fn write(&mut self, command: &command::UserCommand) -> anyhow::Result<()> {
-        let mut serialized_bytes = serde_json::to_vec(command)?;
-        serialized_bytes.extend_from_slice(NEW_LINE);
-
-        self.writer.write_all(serialized_bytes.as_slice()).await?;
-
-        Ok(())
+    pub async fn write(&mut self, command: &command::UserCommandEnvelope) -> anyhow::Result<()> {
+        self.writer.write(command).await
     }
 }
 
@@ -54,16 +57,111 @@ impl CommandWriter {
 pub fn split_tcp_stream(stream: TcpStream) -> (EventStream, CommandWriter) {
     let (reader, writer) = stream.into_split();
 
-    (
-        Box::pin(
-            LinesStream::new(BufReader::new(reader).lines()).map(|line| {
-                line.context("could not read line from the server")
-                    .and_then(|line| {
-                        serde_json::from_str::<event::Event>(&line)
-                            .context("failed to deserialize event from the server")
-                    })
-            }),
+    into_parts(common::split_lines(reader, writer, MAX_FRAME_LENGTH))
+}
+
+/// Splits any bidirectional byte stream into a stream of events and a command writer, events are delimited by new lines
+/// the same as over TCP. Useful for Unix sockets, encrypted streams or in-memory pipes like [tokio::io::duplex].
+///
+/// # Arguments
+///
+/// - `stream` - A stream to split, which is both [AsyncRead] and [AsyncWrite]
+pub fn split_stream<S>(stream: S) -> (EventStream, CommandWriter)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+
+    into_parts(common::split_lines(reader, writer, MAX_FRAME_LENGTH))
+}
+
+/// Connects over any bidirectional byte stream with the given [Codec] and splits it into a stream of events and a command writer.
+///
+/// Any codec other than JSON is selected with the very first command, the returned parts are ready once the server
+/// has confirmed it. The stream has to be accepted with [super::server::accept_stream].
+///
+/// # Arguments
+///
+/// - `stream` - A stream to connect over, which is both [AsyncRead] and [AsyncWrite]
+/// - `codec` - The [Codec] to serialize commands and events with
+pub async fn connect_stream<S>(
+    stream: S,
+    codec: Codec,
+) -> anyhow::Result<(EventStream, CommandWriter)>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    if codec == Codec::Json {
+        return Ok(split_stream(stream));
+    }
+
+    let (reader, writer) = tokio::io::split(stream);
+    let mut negotiation = Negotiation::new(reader, MAX_FRAME_LENGTH);
+    let mut command_writer = CommandWriter::new(writer);
+
+    command_writer
+        .write(&command::UserCommand::SelectCodec(command::SelectCodecCommand { codec }).into())
+        .await?;
+    match negotiation.next().await.map(decode_event).transpose()? {
+        Some(event::Event::CodecSelected(reply)) if reply.codec == codec => {}
+        Some(event) => anyhow::bail!(
+            "the server did not select the codec, replied with {:?}",
+            event
         ),
-        CommandWriter::new(writer),
-    )
+        None => anyhow::bail!("the server closed the connection before selecting the codec"),
+    }
+    let FrameWriter::Lines(writer) = command_writer.writer else {
+        unreachable!(
+            "the command writer of a byte stream writes lines until the codec is selected"
+        );
+    };
+
+    Ok(into_parts((
+        negotiation.into_frames(codec),
+        FrameWriter::new(writer, codec),
+    )))
+}
+
+/// Splits a TLS stream connected with [super::tls::connector] into a stream of events and a command writer.
+///
+/// # Arguments
+///
+/// - `stream` - A [tokio_rustls::client::TlsStream] to split, the handshake has already been completed
+#[cfg(feature = "tls")]
+pub fn split_tls_stream(
+    stream: tokio_rustls::client::TlsStream<TcpStream>,
+) -> (EventStream, CommandWriter) {
+    split_stream(stream)
+}
+
+/// Splits a WebSocket stream connected with [tokio_tungstenite::client_async] into a stream of events and a command writer.
+/// Every text message carries a single JSON serialized event, the same as a line of a TCP stream.
+///
+/// # Arguments
+///
+/// - `stream` - A [tokio_tungstenite::WebSocketStream] to split, over a plain or an encrypted stream
+#[cfg(feature = "websocket")]
+pub fn split_websocket_stream<S>(
+    stream: tokio_tungstenite::WebSocketStream<S>,
+) -> (EventStream, CommandWriter)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    into_parts(common::split_websocket(stream))
+}
+
+fn into_parts(
+    (frames, writer): (BoxedStream<std::io::Result<Frame>>, FrameWriter),
+) -> (EventStream, CommandWriter) {
+    (Box::pin(frames.map(decode_event)), CommandWriter { writer })
+}
+
+fn decode_event(frame: std::io::Result<Frame>) -> anyhow::Result<event::Event> {
+    frame
+        .context("could not read message from the server")
+        .and_then(|frame| {
+            frame
+                .decode::<event::Event>()
+                .context("failed to deserialize event from the server")
+        })
 }
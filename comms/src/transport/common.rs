@@ -1,7 +1,314 @@
-use std::pin::Pin;
+use std::{fmt, pin::Pin};
 
-use tokio_stream::Stream;
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::codec::{
+    FramedRead, LengthDelimitedCodec, LengthDelimitedCodecError, LinesCodec, LinesCodecError,
+};
+
+use crate::command::Codec;
 
 pub const NEW_LINE: &[u8; 2] = b"\r\n";
 
 pub type BoxedStream<Item> = Pin<Box<dyn Stream<Item = Item> + Send>>;
+
+pub type BoxedReader = Pin<Box<dyn AsyncRead + Send>>;
+
+pub type BoxedWriter = Pin<Box<dyn AsyncWrite + Send>>;
+
+#[cfg(feature = "websocket")]
+pub type BoxedWebSocketSink = Pin<
+    Box<
+        dyn futures_util::Sink<
+                tokio_tungstenite::tungstenite::Message,
+                Error = tokio_tungstenite::tungstenite::Error,
+            > + Send,
+    >,
+>;
+
+/// [FrameTooLongError] is returned instead of a frame which is longer than the limit of the reading side
+///
+/// The rest of the frame is never buffered, a peer sending one is either broken or hostile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTooLongError {
+    /// The maximum length of a frame in bytes
+    pub max_length: usize,
+}
+
+impl FrameTooLongError {
+    /// The frame length error carried by an error of a stream read with [read_frames], if any
+    pub fn from_io_error(e: &std::io::Error) -> Option<FrameTooLongError> {
+        e.get_ref()?.downcast_ref::<FrameTooLongError>().copied()
+    }
+
+    fn into_io_error(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, self)
+    }
+}
+
+impl fmt::Display for FrameTooLongError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame is longer than {} bytes", self.max_length)
+    }
+}
+
+impl std::error::Error for FrameTooLongError {}
+
+/// A single message read from a transport, which has not been deserialized yet
+pub enum Frame {
+    Json(String),
+    MessagePack(BytesMut),
+}
+
+impl Frame {
+    pub fn decode<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        match self {
+            Frame::Json(text) => Ok(serde_json::from_str(text)?),
+            Frame::MessagePack(bytes) => Ok(rmp_serde::from_slice(bytes)?),
+        }
+    }
+}
+
+/// [FrameWriter] serializes messages and writes them to the write half of a transport, one frame per message
+pub enum FrameWriter {
+    /// Messages are written to a byte stream as JSON, each followed by a new line
+    Lines(BoxedWriter),
+    /// Messages are written to a byte stream as MessagePack, each prefixed with its length as a big endian u32
+    LengthDelimited(BoxedWriter),
+    /// Every message is sent as a single WebSocket text message holding JSON
+    #[cfg(feature = "websocket")]
+    WebSocket(BoxedWebSocketSink),
+}
+
+impl FrameWriter {
+    pub fn new(writer: impl AsyncWrite + Send + 'static, codec: Codec) -> Self {
+        match codec {
+            Codec::Json => FrameWriter::Lines(Box::pin(writer)),
+            Codec::MessagePack => FrameWriter::LengthDelimited(Box::pin(writer)),
+        }
+    }
+
+    /// The codec the messages are serialized with
+    pub fn codec(&self) -> Codec {
+        match self {
+            FrameWriter::Lines(_) => Codec::Json,
+            FrameWriter::LengthDelimited(_) => Codec::MessagePack,
+            #[cfg(feature = "websocket")]
+            FrameWriter::WebSocket(_) => Codec::Json,
+        }
+    }
+
+    /// Serialize and write a single message
+    pub async fn write(&mut self, message: &impl Serialize) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "websocket")]
+            FrameWriter::WebSocket(sink) => {
+                use futures_util::SinkExt;
+
+                sink.send(tokio_tungstenite::tungstenite::Message::Text(
+                    serde_json::to_string(message)?,
+                ))
+                .await?;
+
+                Ok(())
+            }
+            _ => {
+                let frame = encode_frame(message, self.codec())?;
+
+                self.write_frame(&frame).await
+            }
+        }
+    }
+
+    /// Write a single message which has already been serialized with [encode_frame] for the [FrameWriter::codec]
+    pub async fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        match self {
+            FrameWriter::Lines(writer) | FrameWriter::LengthDelimited(writer) => {
+                write_flushed(writer, frame).await?;
+            }
+            #[cfg(feature = "websocket")]
+            FrameWriter::WebSocket(sink) => {
+                use futures_util::SinkExt;
+
+                // the message delimits the frame, the trailing new line is not part of it
+                let text = frame.strip_suffix(NEW_LINE).unwrap_or(frame);
+                sink.send(tokio_tungstenite::tungstenite::Message::Text(
+                    String::from_utf8(text.to_vec())?,
+                ))
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes a message into a complete frame of the codec, which can be written as is to a byte stream
+///
+/// JSON frames are followed by a new line, MessagePack frames are prefixed with their length as a big endian u32.
+pub fn encode_frame(message: &impl Serialize, codec: Codec) -> anyhow::Result<Bytes> {
+    match codec {
+        Codec::Json => {
+            let mut serialized_bytes = serde_json::to_vec(message)?;
+            serialized_bytes.extend_from_slice(NEW_LINE);
+
+            Ok(Bytes::from(serialized_bytes))
+        }
+        Codec::MessagePack => {
+            // reserve the length prefix up front to write the frame with a single call
+            let mut frame = BytesMut::zeroed(4).writer();
+            rmp_serde::encode::write_named(&mut frame, message)?;
+            let mut frame = frame.into_inner();
+            let length = u32::try_from(frame.len() - 4)?;
+            frame[..4].copy_from_slice(&length.to_be_bytes());
+
+            Ok(frame.freeze())
+        }
+    }
+}
+
+async fn write_flushed(writer: &mut BoxedWriter, bytes: &[u8]) -> std::io::Result<()> {
+    writer.write_all(bytes).await?;
+    // encrypted streams buffer the written bytes until they are flushed
+    writer.flush().await
+}
+
+/// Reads the frames of the given codec from a byte stream, frames longer than `max_frame_length` bytes fail with a [FrameTooLongError]
+pub fn read_frames(
+    reader: impl AsyncRead + Send + 'static,
+    codec: Codec,
+    max_frame_length: usize,
+) -> BoxedStream<std::io::Result<Frame>> {
+    match codec {
+        Codec::Json => Box::pin(
+            FramedRead::new(reader, LinesCodec::new_with_max_length(max_frame_length))
+                .map(move |line| into_json_frame(line, max_frame_length)),
+        ),
+        Codec::MessagePack => Box::pin(
+            FramedRead::new(
+                reader,
+                LengthDelimitedCodec::builder()
+                    .max_frame_length(max_frame_length)
+                    .new_codec(),
+            )
+            .map(move |frame| match frame {
+                Ok(frame) => Ok(Frame::MessagePack(frame)),
+                Err(e)
+                    if e.get_ref()
+                        .is_some_and(|e| e.is::<LengthDelimitedCodecError>()) =>
+                {
+                    Err(FrameTooLongError {
+                        max_length: max_frame_length,
+                    }
+                    .into_io_error())
+                }
+                Err(e) => Err(e),
+            }),
+        ),
+    }
+}
+
+fn into_json_frame(
+    line: Result<String, LinesCodecError>,
+    max_frame_length: usize,
+) -> std::io::Result<Frame> {
+    match line {
+        Ok(line) => Ok(Frame::Json(line)),
+        Err(LinesCodecError::Io(e)) => Err(e),
+        Err(LinesCodecError::MaxLineLengthExceeded) => Err(FrameTooLongError {
+            max_length: max_frame_length,
+        }
+        .into_io_error()),
+    }
+}
+
+/// Splits a byte stream into the JSON frames it carries, one per line, and a [FrameWriter]
+pub fn split_lines(
+    reader: impl AsyncRead + Send + 'static,
+    writer: impl AsyncWrite + Send + 'static,
+    max_frame_length: usize,
+) -> (BoxedStream<std::io::Result<Frame>>, FrameWriter) {
+    (
+        read_frames(reader, Codec::Json, max_frame_length),
+        FrameWriter::new(writer, Codec::Json),
+    )
+}
+
+/// Reads JSON lines from a byte stream until the codec of the connection has been negotiated
+///
+/// The first line is read on its own, so the rest of the stream can be read with another codec
+/// without losing any bytes which were read ahead.
+pub struct Negotiation {
+    lines: FramedRead<BoxedReader, LinesCodec>,
+    max_frame_length: usize,
+}
+
+impl Negotiation {
+    pub fn new(reader: impl AsyncRead + Send + 'static, max_frame_length: usize) -> Self {
+        Negotiation {
+            lines: FramedRead::new(
+                Box::pin(reader),
+                LinesCodec::new_with_max_length(max_frame_length),
+            ),
+            max_frame_length,
+        }
+    }
+
+    /// Reads the next JSON line, [None] if the stream has ended
+    pub async fn next(&mut self) -> Option<std::io::Result<Frame>> {
+        let max_frame_length = self.max_frame_length;
+
+        self.lines
+            .next()
+            .await
+            .map(|line| into_json_frame(line, max_frame_length))
+    }
+
+    /// The frames of the rest of the stream, read with the given codec
+    pub fn into_frames(self, codec: Codec) -> BoxedStream<std::io::Result<Frame>> {
+        let parts = self.lines.into_parts();
+        let reader = std::io::Cursor::new(parts.read_buf).chain(parts.io);
+
+        read_frames(reader, codec, self.max_frame_length)
+    }
+}
+
+/// Splits a WebSocket stream into the JSON frames it carries, one per data message, and a [FrameWriter]
+///
+/// Control messages are answered by the WebSocket stream itself and never show up in the returned stream,
+/// a failure of the WebSocket stream is reported as an [std::io::Error] the same way a broken byte stream is.
+#[cfg(feature = "websocket")]
+pub fn split_websocket<S>(
+    stream: tokio_tungstenite::WebSocketStream<S>,
+) -> (BoxedStream<std::io::Result<Frame>>, FrameWriter)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    use tokio_tungstenite::tungstenite::{error::CapacityError, Error, Message};
+
+    let (sink, stream) = futures_util::StreamExt::split(stream);
+
+    (
+        Box::pin(stream.filter_map(|message| {
+            match message {
+                Ok(Message::Text(text)) => Some(Ok(Frame::Json(text))),
+                // invalid UTF-8 fails to deserialize like any other malformed message
+                Ok(Message::Binary(bytes)) => Some(Ok(Frame::Json(
+                    String::from_utf8_lossy(&bytes).into_owned(),
+                ))),
+                Ok(_) => None,
+                // the limits are configured when the WebSocket stream is created
+                Err(Error::Capacity(CapacityError::MessageTooLong { max_size, .. })) => {
+                    Some(Err(FrameTooLongError {
+                        max_length: max_size,
+                    }
+                    .into_io_error()))
+                }
+                Err(e) => Some(Err(std::io::Error::other(e))),
+            }
+        })),
+        FrameWriter::WebSocket(Box::pin(sink)),
+    )
+}
@@ -1,8 +1,14 @@
-/// Transport over TCP implementation for a client to be able to interact with the server
+/// Transport over TCP and WebSocket implementation for a client to be able to interact with the server
 #[cfg(feature = "client")]
 pub mod client;
 #[cfg(any(feature = "client", feature = "server"))]
 mod common;
-/// Transport over TCP implementation for a server to interact with a single client TCP Stream
+#[cfg(any(feature = "client", feature = "server"))]
+pub use common::FrameTooLongError;
+/// Transport over TCP and WebSocket implementation for a server to interact with a single client stream
 #[cfg(feature = "server")]
 pub mod server;
+/// TLS configuration for the client and the server, streams are wrapped with rustls
+/// Requires 'tls' feature alongside with 'server' or 'client' features
+#[cfg(all(feature = "tls", any(feature = "client", feature = "server")))]
+pub mod tls;
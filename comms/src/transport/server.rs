@@ -1,33 +1,94 @@
+use std::sync::{Arc, OnceLock};
+
 use anyhow::Context;
+use bytes::Bytes;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{tcp::OwnedWriteHalf, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_stream::StreamExt;
+
+use crate::{
+    command::{self, Codec},
+    event,
 };
-use tokio_stream::{wrappers::LinesStream, StreamExt};
 
-use crate::{command, event};
+use super::common::{
+    self, encode_frame, BoxedStream, Frame, FrameTooLongError, FrameWriter, Negotiation,
+};
 
-use super::common::{BoxedStream, NEW_LINE};
+/// The longest command a client may send in bytes unless configured otherwise, longer ones end the connection
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 64 * 1024;
 
-/// [CommandStream] is a stream of [crate::command::UserCommand]s sent by the client
+/// [CommandStream] is a stream of [crate::command::UserCommandEnvelope]s sent by the client
 ///
 /// # Cancel Safety
 ///
 /// This stream is cancel-safe, meaning that it can be used in [tokio::select!]
 /// without the risk of missing commands.
-pub type CommandStream = BoxedStream<anyhow::Result<command::UserCommand>>;
+pub type CommandStream = BoxedStream<anyhow::Result<command::UserCommandEnvelope>>;
+
+/// [SharedEvent] is an [event::Event] written to many clients, such as a message broadcast to a room
+///
+/// The event is serialized at most once per [Codec], however many clients it is written to.
+/// Cloning it only bumps a reference count, so it is cheap to send through channels.
+#[derive(Debug, Clone)]
+pub struct SharedEvent(Arc<SharedEventInner>);
+
+#[derive(Debug)]
+struct SharedEventInner {
+    event: event::Event,
+    json_frame: OnceLock<Bytes>,
+    message_pack_frame: OnceLock<Bytes>,
+}
+
+impl SharedEvent {
+    pub fn new(event: event::Event) -> Self {
+        SharedEvent(Arc::new(SharedEventInner {
+            event,
+            json_frame: OnceLock::new(),
+            message_pack_frame: OnceLock::new(),
+        }))
+    }
 
-/// [EventWriter] is a wrapper around a [TcpStream] which writes [crate::event::Event]s to the client
+    pub fn event(&self) -> &event::Event {
+        &self.0.event
+    }
+
+    /// The event serialized with the codec, the first client talking the codec serializes it for everyone else
+    fn frame(&self, codec: Codec) -> anyhow::Result<&Bytes> {
+        let frame = match codec {
+            Codec::Json => &self.0.json_frame,
+            Codec::MessagePack => &self.0.message_pack_frame,
+        };
+        if let Some(frame) = frame.get() {
+            return Ok(frame);
+        }
+
+        let encoded = encode_frame(&self.0.event, codec)?;
+        Ok(frame.get_or_init(|| encoded))
+    }
+}
+
+impl From<event::Event> for SharedEvent {
+    fn from(event: event::Event) -> Self {
+        SharedEvent::new(event)
+    }
+}
+
+/// [EventWriter] is a wrapper around the write half of a stream which writes [crate::event::Event]s to the client
 pub struct EventWriter {
-    writer: OwnedWriteHalf,
+    writer: FrameWriter,
 }
 
 impl EventWriter {
-    pub fn new(writer: OwnedWriteHalf) -> Self {
-        Self { writer }
+    pub fn new(writer: impl AsyncWrite + Send + 'static) -> Self {
+        Self {
+            writer: FrameWriter::new(writer, Codec::Json),
+        }
     }
 
-    /// Send a [crate::event::Event] to the backing [TcpStream]
+    /// Send a [crate::event::Event] to the backing stream
     ///
     /// # Cancel Safety
     ///
@@ -37,12 +98,19 @@ impl EventWriter {
     /// partially written, but future calls to `write` will start over
     /// from the beginning of the buffer. Causing undefined behaviour.
     pub async fn write(&mut self, event: &event::Event) -> anyhow::Result<()> {
-        let mut serialized_bytes = serde_json::to_vec(event)?;
-        serialized_bytes.extend_from_slice(NEW_LINE);
+        self.writer.write(event).await
+    }
 
-        self.writer.write_all(serialized_bytes.as_slice()).await?;
+    /// Send a [SharedEvent] to the backing stream, reusing its frame if it has already been serialized
+    /// for another client talking the same codec
+    ///
+    /// # Cancel Safety
+    ///
+    /// This method is not cancellation safe, for the same reasons as [EventWriter::write].
+    pub async fn write_shared(&mut self, event: &SharedEvent) -> anyhow::Result<()> {
+        let frame = event.frame(self.writer.codec())?;
 
-        Ok(())
+        self.writer.write_frame(frame).await
     }
 }
 
@@ -54,16 +122,126 @@ impl EventWriter {
 pub fn split_tcp_stream(stream: TcpStream) -> (CommandStream, EventWriter) {
     let (reader, writer) = stream.into_split();
 
-    (
-        Box::pin(
-            LinesStream::new(BufReader::new(reader).lines()).map(|line| {
-                line.context("could not read line from the client")
-                    .and_then(|line| {
-                        serde_json::from_str::<command::UserCommand>(&line)
-                            .context("failed to deserialize command from client")
-                    })
-            }),
-        ),
-        EventWriter::new(writer),
-    )
+    into_parts(common::split_lines(
+        reader,
+        writer,
+        DEFAULT_MAX_FRAME_LENGTH,
+    ))
+}
+
+/// Splits any bidirectional byte stream into a stream of commands and an event writer, commands are delimited by new lines
+/// the same as over TCP. Useful for Unix sockets, encrypted streams or in-memory pipes like [tokio::io::duplex].
+///
+/// # Arguments
+///
+/// - `stream` - A stream to split, which is both [AsyncRead] and [AsyncWrite]
+pub fn split_stream<S>(stream: S) -> (CommandStream, EventWriter)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+
+    into_parts(common::split_lines(
+        reader,
+        writer,
+        DEFAULT_MAX_FRAME_LENGTH,
+    ))
+}
+
+/// Accepts any bidirectional byte stream, negotiating its codec, and splits it into a stream of commands and an event writer.
+///
+/// A client may select a [Codec] with a [command::UserCommand::SelectCodec] as its very first command, which is confirmed
+/// with a [event::Event::CodecSelected] before both sides switch to it. Otherwise the connection stays on JSON lines and
+/// the first command is served like any other.
+///
+/// # Arguments
+///
+/// - `stream` - A stream to accept, which is both [AsyncRead] and [AsyncWrite]
+/// - `max_frame_length` - The longest command the client may send in bytes, see [FrameTooLongError]
+pub async fn accept_stream<S>(
+    stream: S,
+    max_frame_length: usize,
+) -> anyhow::Result<(CommandStream, EventWriter)>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut negotiation = Negotiation::new(reader, max_frame_length);
+    let mut event_writer = EventWriter::new(writer);
+
+    let first_command = negotiation.next().await.map(decode_command);
+    let codec = match &first_command {
+        Some(Ok(command::UserCommandEnvelope {
+            command: command::UserCommand::SelectCodec(cmd),
+            ..
+        })) => cmd.codec,
+        _ => {
+            let commands = tokio_stream::iter(first_command)
+                .chain(negotiation.into_frames(Codec::Json).map(decode_command));
+
+            return Ok((Box::pin(commands), event_writer));
+        }
+    };
+
+    // the reply is the last JSON line, the client switches to the codec once it has read it
+    event_writer
+        .write(&event::Event::CodecSelected(
+            event::CodecSelectedReplyEvent { codec },
+        ))
+        .await?;
+    let FrameWriter::Lines(writer) = event_writer.writer else {
+        unreachable!("the event writer of a byte stream writes lines until the codec is selected");
+    };
+
+    Ok(into_parts((
+        negotiation.into_frames(codec),
+        FrameWriter::new(writer, codec),
+    )))
+}
+
+/// Splits a TLS stream accepted by [super::tls::acceptor] into a stream of commands and an event writer.
+///
+/// # Arguments
+///
+/// - `stream` - A [tokio_rustls::server::TlsStream] to split, the handshake has already been completed
+#[cfg(feature = "tls")]
+pub fn split_tls_stream(
+    stream: tokio_rustls::server::TlsStream<TcpStream>,
+) -> (CommandStream, EventWriter) {
+    split_stream(stream)
+}
+
+/// Splits a WebSocket stream accepted with [tokio_tungstenite::accept_async] into a stream of commands and an event writer.
+/// Every text message carries a single JSON serialized command, the same as a line of a TCP stream.
+///
+/// # Arguments
+///
+/// - `stream` - A [tokio_tungstenite::WebSocketStream] to split, over a plain or an encrypted stream
+#[cfg(feature = "websocket")]
+pub fn split_websocket_stream<S>(
+    stream: tokio_tungstenite::WebSocketStream<S>,
+) -> (CommandStream, EventWriter)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    into_parts(common::split_websocket(stream))
+}
+
+fn into_parts(
+    (frames, writer): (BoxedStream<std::io::Result<Frame>>, FrameWriter),
+) -> (CommandStream, EventWriter) {
+    (Box::pin(frames.map(decode_command)), EventWriter { writer })
+}
+
+/// Oversized frames fail with a bare [FrameTooLongError], so they are told apart from broken connections
+fn decode_command(frame: std::io::Result<Frame>) -> anyhow::Result<command::UserCommandEnvelope> {
+    match frame {
+        Ok(frame) => frame
+            .decode::<command::UserCommandEnvelope>()
+            .context("failed to deserialize command from client"),
+        Err(e) => match FrameTooLongError::from_io_error(&e) {
+            Some(e) => Err(e.into()),
+            None => Err(e).context("could not read message from the client"),
+        },
+    }
 }
@@ -0,0 +1,175 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// How the client decides whether the certificate presented by the server can be trusted
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerTrust {
+    /// Certificates issued by one of the CA certificates in the given PEM file are trusted,
+    /// they have to be issued for the name or address the client connects to
+    CustomCa(PathBuf),
+    /// Only the certificate in the given PEM file is trusted regardless of its issuer and names,
+    /// which suits self-signed certificates
+    PinnedCertificate(PathBuf),
+}
+
+/// Creates a [TlsAcceptor] for the server from PEM files holding the certificate chain and its private key
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let certs = read_certs(cert_path)?;
+    let key = read_private_key(key_path)?;
+
+    let config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("the private key does not match the certificate")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Creates a [TlsConnector] for the client which trusts the server certificate as described by [ServerTrust]
+pub fn connector(trust: &ServerTrust) -> anyhow::Result<TlsConnector> {
+    let builder = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?;
+
+    let config = match trust {
+        ServerTrust::CustomCa(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+            }
+
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        ServerTrust::PinnedCertificate(path) => {
+            let certificate = read_certs(path)?.swap_remove(0);
+
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertificateVerifier {
+                    certificate,
+                    provider: provider(),
+                }))
+                .with_no_client_auth()
+        }
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// The name the certificate of the server is checked against, taken from the host part of a `host:port` address
+pub fn server_name(addr: &str) -> anyhow::Result<ServerName<'static>> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    // IPv6 addresses are written within brackets when they are followed by a port
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    ServerName::try_from(host.to_string())
+        .with_context(|| format!("'{}' is not a valid server name", host))
+}
+
+/// Streams are always encrypted with ring, whatever providers other dependencies bring in
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path)
+        .with_context(|| format!("could not open certificate file {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("could not parse certificate file {}", path.display()))?;
+
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "no certificate found in {}",
+            path.display()
+        ));
+    }
+
+    Ok(certs)
+}
+
+fn read_private_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = File::open(path)
+        .with_context(|| format!("could not open private key file {}", path.display()))?;
+
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("could not parse private key file {}", path.display()))?
+        .ok_or_else(|| anyhow::anyhow!("no private key found in {}", path.display()))
+}
+
+/// Accepts the pinned certificate only, signatures of the handshake are still verified
+/// so the server has to own the private key of the pinned certificate
+#[derive(Debug)]
+struct PinnedCertificateVerifier {
+    certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,145 @@
+use comms::{
+    command::{self, Codec, UserCommand, UserCommandEnvelope},
+    event::{self, ErrorCode, Event},
+    transport,
+};
+use tokio_stream::StreamExt;
+
+/// Sends the commands from the client and the events from the server over an in-memory pipe
+/// negotiated with the given codec, returning what each side has read
+async fn exchange(
+    codec: Codec,
+    commands: &[UserCommandEnvelope],
+    events: &[Event],
+) -> (Vec<UserCommandEnvelope>, Vec<Event>) {
+    let (client_io, server_io) = tokio::io::duplex(64);
+
+    let server = async {
+        let (mut command_stream, mut event_writer) = transport::server::accept_stream(
+            server_io,
+            transport::server::DEFAULT_MAX_FRAME_LENGTH,
+        )
+        .await?;
+
+        let mut collected_commands = Vec::new();
+        while collected_commands.len() < commands.len() {
+            match command_stream.next().await {
+                Some(command) => collected_commands.push(command?),
+                None => return Err(anyhow::anyhow!("client closed the connection")),
+            }
+        }
+        for event in events {
+            event_writer.write(event).await?;
+        }
+
+        anyhow::Ok(collected_commands)
+    };
+    let client = async {
+        let (mut event_stream, mut command_writer) =
+            transport::client::connect_stream(client_io, codec).await?;
+
+        for command in commands {
+            command_writer.write(command).await?;
+        }
+        let mut collected_events = Vec::new();
+        while collected_events.len() < events.len() {
+            match event_stream.next().await {
+                Some(event) => collected_events.push(event?),
+                None => return Err(anyhow::anyhow!("server closed the connection")),
+            }
+        }
+
+        anyhow::Ok(collected_events)
+    };
+
+    tokio::try_join!(server, client).unwrap()
+}
+
+fn commands() -> Vec<UserCommandEnvelope> {
+    vec![
+        UserCommandEnvelope::from(UserCommand::Login(command::LoginCommand {
+            username: "user-1".into(),
+            password: "password-1".into(),
+        })),
+        UserCommandEnvelope {
+            command: UserCommand::SendMessage(command::SendMessageCommand {
+                room: "room-1".into(),
+                content: "content-1".into(),
+            }),
+            request_id: Some(1),
+        },
+        UserCommandEnvelope::from(UserCommand::Quit(command::QuitCommand)),
+    ]
+}
+
+fn events() -> Vec<Event> {
+    vec![
+        Event::LoginSuccessful(event::LoginSuccessfulReplyEvent {
+            user_id: "user-id-1".into(),
+            session_id: "session-id-1".into(),
+            rooms: vec![event::RoomDetail {
+                name: "room-1".into(),
+                description: "description-1".into(),
+            }],
+        }),
+        Event::Nack(event::NackReplyEvent {
+            request_id: 1,
+            code: ErrorCode::Forbidden,
+            message: "message-1".into(),
+        }),
+    ]
+}
+
+#[tokio::test]
+async fn assert_message_pack_codec() {
+    let (collected_commands, collected_events) =
+        exchange(Codec::MessagePack, &commands(), &events()).await;
+
+    assert_eq!(collected_commands, commands());
+    assert_eq!(collected_events, events());
+}
+
+#[tokio::test]
+async fn assert_json_codec_keeps_first_command() {
+    let (collected_commands, collected_events) =
+        exchange(Codec::Json, &commands(), &events()).await;
+
+    assert_eq!(collected_commands, commands());
+    assert_eq!(collected_events, events());
+}
+
+#[tokio::test]
+async fn assert_shared_event_is_read_with_every_codec() {
+    let shared_event = transport::server::SharedEvent::from(events().remove(0));
+
+    for codec in [Codec::Json, Codec::MessagePack, Codec::Json] {
+        let (client_io, server_io) = tokio::io::duplex(64);
+
+        let server = async {
+            let (_, mut event_writer) = transport::server::accept_stream(
+                server_io,
+                transport::server::DEFAULT_MAX_FRAME_LENGTH,
+            )
+            .await?;
+
+            // the frame serialized for the previous connection of the same codec is written as is
+            event_writer.write_shared(&shared_event).await?;
+
+            anyhow::Ok(event_writer)
+        };
+        let client = async {
+            let (mut event_stream, mut command_writer) =
+                transport::client::connect_stream(client_io, codec).await?;
+            // the server is only done accepting once it has read the first command
+            command_writer.write(&commands()[0]).await?;
+
+            match event_stream.next().await {
+                Some(event) => event,
+                None => Err(anyhow::anyhow!("server closed the connection")),
+            }
+        };
+
+        let (_, received_event) = tokio::try_join!(server, client).unwrap();
+        assert_eq!(&received_event, shared_event.event());
+    }
+}
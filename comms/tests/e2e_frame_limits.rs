@@ -0,0 +1,125 @@
+use comms::{
+    command::{self, UserCommand, UserCommandEnvelope},
+    transport::{self, server::DEFAULT_MAX_FRAME_LENGTH, FrameTooLongError},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+use tokio_stream::StreamExt;
+
+/// Accepts a single client over TCP, writes the given bytes from the client side,
+/// and returns the results read by the server until the client is done
+async fn feed_server(bytes: Vec<u8>) -> Vec<anyhow::Result<UserCommandEnvelope>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = async {
+        let (tcp_stream, _) = listener.accept().await?;
+        let (mut command_stream, _event_writer) = transport::server::split_tcp_stream(tcp_stream);
+
+        let mut results = Vec::new();
+        while let Some(result) = command_stream.next().await {
+            let is_too_long = matches!(&result, Err(e) if e.is::<FrameTooLongError>());
+            results.push(result);
+            // like the server, stop reading from a client which has sent an oversized frame
+            if is_too_long {
+                break;
+            }
+        }
+
+        anyhow::Ok(results)
+    };
+    let client = async {
+        let mut tcp_stream = TcpStream::connect(addr).await?;
+        // the server may stop reading before everything has been written
+        let _ = tcp_stream.write_all(&bytes).await;
+        let _ = tcp_stream.shutdown().await;
+
+        anyhow::Ok(())
+    };
+
+    tokio::try_join!(server, client).unwrap().0
+}
+
+fn serialized_command(content: &str) -> Vec<u8> {
+    let mut bytes = serde_json::to_vec(&UserCommandEnvelope::from(UserCommand::SendMessage(
+        command::SendMessageCommand {
+            room: "room-1".into(),
+            content: content.into(),
+        },
+    )))
+    .unwrap();
+    bytes.extend_from_slice(b"\r\n");
+
+    bytes
+}
+
+#[tokio::test]
+async fn assert_unterminated_line_is_refused() {
+    // a hostile client never ends its line, the server must not wait for the end of it
+    let bytes = vec![b'a'; DEFAULT_MAX_FRAME_LENGTH * 4];
+
+    let results = feed_server(bytes).await;
+
+    assert_eq!(results.len(), 1);
+    let e = results.into_iter().next().unwrap().unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<FrameTooLongError>(),
+        Some(&FrameTooLongError {
+            max_length: DEFAULT_MAX_FRAME_LENGTH
+        })
+    );
+}
+
+#[tokio::test]
+async fn assert_oversized_command_is_refused_after_valid_ones() {
+    let mut bytes = serialized_command("content-1");
+    bytes.extend(serialized_command(&"a".repeat(DEFAULT_MAX_FRAME_LENGTH)));
+    bytes.extend(serialized_command("content-2"));
+
+    let results = feed_server(bytes).await;
+
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    assert!(matches!(&results[1], Err(e) if e.is::<FrameTooLongError>()));
+}
+
+#[tokio::test]
+async fn assert_command_within_limit_is_accepted() {
+    // leaves room for the rest of the command around the content
+    let content = "a".repeat(DEFAULT_MAX_FRAME_LENGTH - 128);
+
+    let results = feed_server(serialized_command(&content)).await;
+
+    assert_eq!(results.len(), 1);
+    let UserCommand::SendMessage(cmd) = results.into_iter().next().unwrap().unwrap().command else {
+        panic!("expected the send message command");
+    };
+    assert_eq!(cmd.content, content);
+}
+
+#[tokio::test]
+async fn assert_oversized_length_prefix_is_refused() {
+    let (mut client_io, server_io) = tokio::io::duplex(1024);
+
+    let server = async {
+        let (mut command_stream, _event_writer) =
+            transport::server::accept_stream(server_io, 1024).await?;
+
+        anyhow::Ok(command_stream.next().await)
+    };
+    let client = async {
+        client_io
+            .write_all(b"{\"_ct\":\"select_codec\",\"cd\":\"msgpack\"}\r\n")
+            .await?;
+        // a frame claiming to be 4 GiB long, which is never allocated
+        client_io.write_all(&u32::MAX.to_be_bytes()).await?;
+
+        anyhow::Ok(client_io)
+    };
+
+    let (result, _client_io) = tokio::try_join!(server, client).unwrap();
+
+    assert!(matches!(result, Some(Err(e)) if e.is::<FrameTooLongError>()));
+}
@@ -1,5 +1,5 @@
 use comms::{
-    command::{self, UserCommand},
+    command::{self, UserCommand, UserCommandEnvelope},
     event::{self, Event},
     transport,
 };
@@ -19,13 +19,16 @@ async fn assert_server_client_transport() {
     assert_eq!(
         server_collected_commands.unwrap(),
         vec![
-            UserCommand::JoinRoom(command::JoinRoomCommand {
+            UserCommandEnvelope::from(UserCommand::JoinRoom(command::JoinRoomCommand {
                 room: "room-1".into(),
-            }),
-            UserCommand::SendMessage(command::SendMessageCommand {
-                room: "room-1".into(),
-                content: "content-1".into(),
-            }),
+            })),
+            UserCommandEnvelope {
+                command: UserCommand::SendMessage(command::SendMessageCommand {
+                    room: "room-1".into(),
+                    content: "content-1".into(),
+                }),
+                request_id: Some(1),
+            },
         ]
     );
 
@@ -39,7 +42,7 @@ async fn assert_server_client_transport() {
     );
 }
 
-async fn execute_server() -> anyhow::Result<Vec<command::UserCommand>> {
+async fn execute_server() -> anyhow::Result<Vec<command::UserCommandEnvelope>> {
     // bind to the example port to wait for client connection
     let listener = TcpListener::bind(format!("0.0.0.0:{}", PORT))
         .await
@@ -104,16 +107,22 @@ async fn execute_client() -> anyhow::Result<Vec<event::Event>> {
 
     // send some commands to the server
     command_writer
-        .write(&UserCommand::JoinRoom(command::JoinRoomCommand {
-            room: "room-1".into(),
-        }))
+        .write(
+            &UserCommand::JoinRoom(command::JoinRoomCommand {
+                room: "room-1".into(),
+            })
+            .into(),
+        )
         .await?;
 
     command_writer
-        .write(&UserCommand::SendMessage(command::SendMessageCommand {
-            room: "room-1".into(),
-            content: "content-1".into(),
-        }))
+        .write(&UserCommandEnvelope {
+            command: UserCommand::SendMessage(command::SendMessageCommand {
+                room: "room-1".into(),
+                content: "content-1".into(),
+            }),
+            request_id: Some(1),
+        })
         .await?;
 
     Ok(collected_events)
@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use comms::{
+    command::{self, UserCommand, UserCommandEnvelope},
+    event::{self, Event},
+    transport::{
+        self,
+        tls::{self, ServerTrust},
+    },
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::StreamExt;
+
+/// A self-signed certificate and its private key written to a temporary directory
+struct GeneratedCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+fn generate_cert(name: &str) -> GeneratedCert {
+    let dir = std::env::temp_dir().join(format!("comms-tls-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    std::fs::write(&cert_path, certified_key.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified_key.key_pair.serialize_pem()).unwrap();
+
+    GeneratedCert {
+        cert_path,
+        key_path,
+    }
+}
+
+#[tokio::test]
+async fn assert_tls_transport_with_custom_ca() {
+    let cert = generate_cert("custom-ca");
+
+    let result = exchange(&cert, ServerTrust::CustomCa(cert.cert_path.clone())).await;
+
+    assert!(result.is_ok(), "{:?}", result);
+}
+
+#[tokio::test]
+async fn assert_tls_transport_with_pinned_certificate() {
+    let cert = generate_cert("pinned");
+
+    let result = exchange(
+        &cert,
+        ServerTrust::PinnedCertificate(cert.cert_path.clone()),
+    )
+    .await;
+
+    assert!(result.is_ok(), "{:?}", result);
+}
+
+#[tokio::test]
+async fn assert_tls_transport_rejects_other_certificate() {
+    let cert = generate_cert("served");
+    let other_cert = generate_cert("other");
+
+    let result = exchange(&cert, ServerTrust::PinnedCertificate(other_cert.cert_path)).await;
+
+    assert!(result.is_err());
+}
+
+/// Serves the given certificate, then sends a single event and a single command over the encrypted stream
+async fn exchange(cert: &GeneratedCert, trust: ServerTrust) -> anyhow::Result<()> {
+    let acceptor = tls::acceptor(&cert.cert_path, &cert.key_path)?;
+    let connector = tls::connector(&trust)?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = format!("localhost:{}", listener.local_addr()?.port());
+    let welcome = Event::LoginSuccessful(event::LoginSuccessfulReplyEvent {
+        user_id: "user-id-1".into(),
+        session_id: "session-id-1".into(),
+        rooms: Vec::default(),
+    });
+    let join_room = UserCommandEnvelope::from(UserCommand::JoinRoom(command::JoinRoomCommand {
+        room: "room-1".into(),
+    }));
+
+    let server = async {
+        let (tcp_stream, _) = listener.accept().await?;
+        let (mut command_stream, mut event_writer) =
+            transport::server::split_tls_stream(acceptor.accept(tcp_stream).await?);
+
+        event_writer.write(&welcome).await?;
+
+        match command_stream.next().await {
+            Some(command) => command,
+            None => Err(anyhow::anyhow!("client closed the connection")),
+        }
+    };
+    let client = async {
+        let tcp_stream = TcpStream::connect(&addr).await?;
+        let tls_stream = connector
+            .connect(tls::server_name(&addr)?, tcp_stream)
+            .await?;
+        let (mut event_stream, mut command_writer) =
+            transport::client::split_tls_stream(tls_stream);
+
+        command_writer.write(&join_room).await?;
+
+        match event_stream.next().await {
+            Some(event) => event,
+            None => Err(anyhow::anyhow!("server closed the connection")),
+        }
+    };
+
+    // a rejected handshake fails the client, the server is not waited for in that case
+    let (received_command, received_event) = tokio::try_join!(server, client)?;
+
+    assert_eq!(received_command, join_room);
+    assert_eq!(received_event, welcome);
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use comms::{
+    command::{self, UserCommand, UserCommandEnvelope},
+    event::{self, Event},
+    transport,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::StreamExt;
+
+#[tokio::test]
+async fn assert_websocket_transport() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let welcome = Event::LoginSuccessful(event::LoginSuccessfulReplyEvent {
+        user_id: "user-id-1".into(),
+        session_id: "session-id-1".into(),
+        rooms: Vec::default(),
+    });
+    let commands = vec![
+        UserCommandEnvelope::from(UserCommand::JoinRoom(command::JoinRoomCommand {
+            room: "room-1".into(),
+        })),
+        UserCommandEnvelope {
+            command: UserCommand::SendMessage(command::SendMessageCommand {
+                room: "room-1".into(),
+                content: "content-1".into(),
+            }),
+            request_id: Some(1),
+        },
+    ];
+
+    let server = async {
+        let (tcp_stream, _) = listener.accept().await?;
+        let websocket_stream = tokio_tungstenite::accept_async(tcp_stream).await?;
+        let (mut command_stream, mut event_writer) =
+            transport::server::split_websocket_stream(websocket_stream);
+
+        event_writer.write(&welcome).await?;
+        // a pre-encoded frame is sent as a text message the same way
+        event_writer
+            .write_shared(&transport::server::SharedEvent::from(welcome.clone()))
+            .await?;
+
+        // the client drops the connection without a closing handshake, only read what it sends
+        let mut collected_commands = Vec::new();
+        while collected_commands.len() < commands.len() {
+            match command_stream.next().await {
+                Some(command) => collected_commands.push(command?),
+                None => return Err(anyhow::anyhow!("client closed the connection")),
+            }
+        }
+
+        anyhow::Ok(collected_commands)
+    };
+    let client = async {
+        let tcp_stream = TcpStream::connect(addr).await?;
+        let (websocket_stream, _) =
+            tokio_tungstenite::client_async(format!("ws://{}/", addr), tcp_stream).await?;
+        let (mut event_stream, mut command_writer) =
+            transport::client::split_websocket_stream(websocket_stream);
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            match event_stream.next().await {
+                Some(event) => events.push(event?),
+                None => return Err(anyhow::anyhow!("server closed the connection")),
+            }
+        }
+        for command in commands.iter() {
+            command_writer.write(command).await?;
+        }
+
+        anyhow::Ok(events)
+    };
+
+    let (collected_commands, received_events) = tokio::try_join!(server, client).unwrap();
+
+    assert_eq!(collected_commands, commands);
+    assert_eq!(received_events, vec![welcome.clone(), welcome]);
+}
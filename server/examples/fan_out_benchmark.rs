@@ -0,0 +1,126 @@
+use std::{
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use comms::{
+    command::{Codec, QuitCommand, SelectCodecCommand, UserCommand, UserCommandEnvelope},
+    event::{Event, UserMessageBroadcastEvent},
+    transport::{
+        self,
+        server::{EventWriter, SharedEvent},
+    },
+};
+use nanoid::nanoid;
+
+// Fan-Out Benchmark for the Chat Server
+//
+// Measures the CPU time spent writing room messages to the sessions of a room, the way the server does it
+// under the load generated by the stress test. Every message is either serialized for every single session,
+// or serialized once and shared by all of them. Writes go to a sink, so serialization is all that is measured.
+//
+// Run it in release mode: `cargo run --release --example fan_out_benchmark`
+
+const CHAT_ROOMS_METADATAS: &str = include_str!("../resources/chat_rooms_metadatas.json");
+
+/// Benchmark Configuration, matching the peak of the stress test
+// The number of users connected to the server
+const USER_COUNT: usize = 2400;
+// How many rooms a user joins
+const NUMBER_OF_ROOMS_TO_JOIN: usize = 5;
+// How many milliseconds a user waits between each message
+const USER_CHAT_DELAY_MILLIS: u64 = 10_000;
+// How many seconds of the load are replayed
+const LOAD_DURATION_SECS: u64 = 10;
+
+/// Accepts a connection which does not go anywhere, talking the given codec
+async fn accept_sink(codec: Codec) -> anyhow::Result<EventWriter> {
+    let first_command = match codec {
+        Codec::Json => UserCommandEnvelope::from(UserCommand::Quit(QuitCommand)),
+        Codec::MessagePack => {
+            UserCommandEnvelope::from(UserCommand::SelectCodec(SelectCodecCommand { codec }))
+        }
+    };
+    let mut first_line = serde_json::to_vec(&first_command)?;
+    first_line.push(b'\n');
+
+    let stream = tokio::io::join(Cursor::new(first_line), tokio::io::sink());
+    let (_, event_writer) =
+        transport::server::accept_stream(stream, transport::server::DEFAULT_MAX_FRAME_LENGTH)
+            .await?;
+
+    Ok(event_writer)
+}
+
+/// Writes every message to every session of the room, returning the time it took
+async fn fan_out(
+    events: &[Event],
+    event_writers: &mut [EventWriter],
+    is_shared: bool,
+) -> anyhow::Result<Duration> {
+    let started_at = Instant::now();
+
+    for event in events {
+        if is_shared {
+            let event = SharedEvent::from(event.clone());
+            for event_writer in event_writers.iter_mut() {
+                event_writer.write_shared(&event).await?;
+            }
+        } else {
+            for event_writer in event_writers.iter_mut() {
+                event_writer.write(event).await?;
+            }
+        }
+    }
+
+    Ok(started_at.elapsed())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let room_count = serde_json::from_str::<Vec<serde_json::Value>>(CHAT_ROOMS_METADATAS)?.len();
+    let users_per_room = USER_COUNT * NUMBER_OF_ROOMS_TO_JOIN / room_count;
+    let messages_per_room = (USER_COUNT as u64 * LOAD_DURATION_SECS * 1000 / USER_CHAT_DELAY_MILLIS)
+        as usize
+        / room_count;
+
+    let events = (0..messages_per_room)
+        .map(|id| {
+            Event::UserMessage(UserMessageBroadcastEvent {
+                room: "room-1".to_string(),
+                id: id as u64,
+                timestamp: Utc::now(),
+                user_id: nanoid!(),
+                content: nanoid!(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    println!(
+        "fanning out {} messages to {} sessions of a single room, {} writes in total",
+        messages_per_room,
+        users_per_room,
+        messages_per_room * users_per_room
+    );
+
+    for codec in [Codec::Json, Codec::MessagePack] {
+        let mut event_writers = Vec::with_capacity(users_per_room);
+        for _ in 0..users_per_room {
+            event_writers.push(accept_sink(codec).await?);
+        }
+
+        let per_session = fan_out(&events, &mut event_writers, false).await?;
+        let shared = fan_out(&events, &mut event_writers, true).await?;
+
+        println!(
+            "{:?}: serialized per session {:.2?}, serialized once {:.2?}, {:.1}x faster",
+            codec,
+            per_session,
+            shared,
+            per_session.as_secs_f64() / shared.as_secs_f64()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt, StreamMap};
+
+// Room Multiplexing Benchmark for the Chat Server
+//
+// Compares two ways of receiving the events of every joined room within a session, under the load of the stress test:
+// a forwarding task per joined room copying from the room's broadcast channel into the session's channel,
+// and the session polling the broadcast channels of all of its rooms directly through a StreamMap.
+// Each approach runs in its own process, so their memory usage does not affect each other.
+//
+// Run it in release mode on Linux: `cargo run --release --example room_multiplexing_benchmark`
+
+/// Benchmark Configuration, matching the peak of the stress test
+// The number of sessions connected to the server
+const SESSION_COUNT: usize = 2400;
+// The number of rooms on the server
+const ROOM_COUNT: usize = 24;
+// How many rooms a session joins
+const NUMBER_OF_ROOMS_TO_JOIN: usize = 5;
+// How many messages are sent to every room
+const MESSAGES_PER_ROOM: usize = 100;
+
+#[derive(Debug, Clone, Copy)]
+enum Approach {
+    TaskPerRoom,
+    StreamMap,
+}
+
+/// The resident memory of the process in KiB, read from procfs
+fn resident_memory_kib() -> usize {
+    let statm = std::fs::read_to_string("/proc/self/statm").unwrap_or_default();
+    let resident_pages = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse::<usize>().ok())
+        .unwrap_or_default();
+
+    resident_pages * 4
+}
+
+/// Spawns a session receiving the events of the given rooms, counting every received event
+fn spawn_session(
+    approach: Approach,
+    receivers: Vec<(usize, broadcast::Receiver<u64>)>,
+    received: Arc<AtomicUsize>,
+) {
+    match approach {
+        Approach::TaskPerRoom => {
+            let (mpsc_tx, mut mpsc_rx) = mpsc::channel(100);
+            for (_, mut broadcast_rx) in receivers {
+                let mpsc_tx = mpsc_tx.clone();
+                tokio::spawn(async move {
+                    while let Ok(event) = broadcast_rx.recv().await {
+                        let _ = mpsc_tx.send(event).await;
+                    }
+                });
+            }
+
+            tokio::spawn(async move {
+                while mpsc_rx.recv().await.is_some() {
+                    received.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+        Approach::StreamMap => {
+            let mut room_events = StreamMap::new();
+            for (room, broadcast_rx) in receivers {
+                room_events.insert(room, BroadcastStream::new(broadcast_rx));
+            }
+
+            tokio::spawn(async move {
+                while room_events.next().await.is_some() {
+                    received.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+    }
+}
+
+async fn run(approach: Approach) {
+    let baseline_kib = resident_memory_kib();
+    let rooms = (0..ROOM_COUNT)
+        .map(|_| broadcast::channel::<u64>(MESSAGES_PER_ROOM).0)
+        .collect::<Vec<_>>();
+    let received = Arc::new(AtomicUsize::new(0));
+
+    for session in 0..SESSION_COUNT {
+        let receivers = (0..NUMBER_OF_ROOMS_TO_JOIN)
+            .map(|i| {
+                let room = (session + i) % ROOM_COUNT;
+                (room, rooms[room].subscribe())
+            })
+            .collect();
+
+        spawn_session(approach, receivers, Arc::clone(&received));
+    }
+    // let every task reach its first await, where it stays while idle
+    tokio::task::yield_now().await;
+
+    let alive_tasks = tokio::runtime::Handle::current()
+        .metrics()
+        .num_alive_tasks();
+    let idle_kib = resident_memory_kib().saturating_sub(baseline_kib);
+
+    let started_at = Instant::now();
+    let expected = SESSION_COUNT * NUMBER_OF_ROOMS_TO_JOIN * MESSAGES_PER_ROOM;
+    for message in 0..MESSAGES_PER_ROOM {
+        for room in rooms.iter() {
+            let _ = room.send(message as u64);
+        }
+    }
+    while received.load(Ordering::Relaxed) < expected {
+        tokio::task::yield_now().await;
+    }
+
+    println!(
+        "{:?}: {} tasks, {} KiB while idle, delivered {} events in {:.2?}",
+        approach,
+        alive_tasks,
+        idle_kib,
+        expected,
+        started_at.elapsed()
+    );
+}
+
+fn main() {
+    let approach = match std::env::args().nth(1).as_deref() {
+        Some("task-per-room") => Approach::TaskPerRoom,
+        Some("stream-map") => Approach::StreamMap,
+        _ => {
+            // run every approach in a fresh process of this very example
+            let exe = std::env::current_exe().expect("could not find the benchmark executable");
+            for approach in ["task-per-room", "stream-map"] {
+                std::process::Command::new(&exe)
+                    .arg(approach)
+                    .status()
+                    .expect("could not run the benchmark");
+            }
+            return;
+        }
+    };
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("could not build the runtime")
+        .block_on(run(approach));
+}
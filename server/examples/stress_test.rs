@@ -1,7 +1,17 @@
-use std::time::Duration;
+// the description below predates the lint and documents the whole example, not the first constant
+#![allow(clippy::empty_line_after_doc_comments)]
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use comms::{
-    command::{JoinRoomCommand, UserCommand},
+    command::{Codec, JoinRoomCommand, RegisterCommand, UserCommand, UserCommandEnvelope},
     event::Event,
     transport,
 };
@@ -27,10 +37,19 @@ const LOAD_INCREMENTS: &str = r#"[
     { "user_count": 1200, "after": { "secs": 60, "nanos": 0 }, "steps": 60 },
     { "user_count": 2400, "after": { "secs": 120, "nanos": 0 }, "steps": 60 }
 ]"#;
-// How many rooms a user should join, this affects the total tokio task count
+// How many rooms a user should join, this affects the number of broadcast receivers every session polls
 const NUMBER_OF_ROOMS_TO_JOIN: usize = 5;
 // How many milliseconds to wait between each user message
 const USER_CHAT_DELAY_MILLIS: u64 = 10_000;
+// The codec synthetic users talk to the server with
+const CODEC: Codec = Codec::MessagePack;
+// How often the round-trip latency of the acknowledged messages is reported
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of messages acknowledged by the server since the last report
+static ACKED_MESSAGES: AtomicU64 = AtomicU64::new(0);
+/// Sum of the round-trip latencies of the acknowledged messages since the last report
+static ACKED_LATENCY_MICROS: AtomicU64 = AtomicU64::new(0);
 
 /// [RotatingIterator] is a simple iterator that rotates through a list of items
 /// and starts from the beginning when the end is reached.
@@ -81,7 +100,19 @@ async fn spawn_single_user(rooms_to_join: Vec<String>) -> anyhow::Result<()> {
 
 async fn spawn_single_user_raw(rooms_to_join: Vec<String>) -> anyhow::Result<()> {
     let tcp_stream = TcpStream::connect(SERVER_ADDR).await?;
-    let (mut event_stream, mut command_writer) = transport::client::split_tcp_stream(tcp_stream);
+    let (mut event_stream, mut command_writer) =
+        transport::client::connect_stream(tcp_stream, CODEC).await?;
+
+    // every synthetic user registers a fresh account
+    command_writer
+        .write(
+            &UserCommand::Register(RegisterCommand {
+                username: nanoid!(),
+                password: nanoid!(),
+            })
+            .into(),
+        )
+        .await?;
 
     let _login_event = match event_stream.next().await {
         Some(Ok(Event::LoginSuccessful(login_event))) => login_event,
@@ -90,13 +121,20 @@ async fn spawn_single_user_raw(rooms_to_join: Vec<String>) -> anyhow::Result<()>
 
     for room_name in rooms_to_join.iter() {
         command_writer
-            .write(&UserCommand::JoinRoom(JoinRoomCommand {
-                room: String::from(room_name),
-            }))
+            .write(
+                &UserCommand::JoinRoom(JoinRoomCommand {
+                    room: String::from(room_name),
+                })
+                .into(),
+            )
             .await?;
     }
 
+    // send times of the messages which are not acknowledged yet, by request id
+    let sent_at: Arc<Mutex<HashMap<u64, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+
     let join_handle = tokio::spawn({
+        let sent_at = Arc::clone(&sent_at);
         let mut rng = StdRng::from_entropy();
         let mut rooms_iterator = RotatingIterator::new(rooms_to_join);
         let to_sleep = Duration::from_millis(USER_CHAT_DELAY_MILLIS);
@@ -108,15 +146,17 @@ async fn spawn_single_user_raw(rooms_to_join: Vec<String>) -> anyhow::Result<()>
             ))
             .await;
 
-            loop {
+            for request_id in 0.. {
                 let room_name = rooms_iterator.next().unwrap();
+                sent_at.lock().unwrap().insert(request_id, Instant::now());
                 let _ = command_writer
-                    .write(&UserCommand::SendMessage(
-                        comms::command::SendMessageCommand {
+                    .write(&UserCommandEnvelope {
+                        command: UserCommand::SendMessage(comms::command::SendMessageCommand {
                             room: room_name,
                             content: nanoid!(),
-                        },
-                    ))
+                        }),
+                        request_id: Some(request_id),
+                    })
                     .await;
 
                 tokio::time::sleep(to_sleep).await;
@@ -124,7 +164,15 @@ async fn spawn_single_user_raw(rooms_to_join: Vec<String>) -> anyhow::Result<()>
         }
     });
 
-    while event_stream.next().await.is_some() {}
+    while let Some(event) = event_stream.next().await {
+        if let Ok(Event::Ack(ack)) = event {
+            if let Some(sent_at) = sent_at.lock().unwrap().remove(&ack.request_id) {
+                ACKED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+                ACKED_LATENCY_MICROS
+                    .fetch_add(sent_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+            }
+        }
+    }
 
     join_handle.abort();
     Ok(())
@@ -140,6 +188,24 @@ async fn main() {
     let mut room_iterator = RotatingIterator::new(chat_room_metadatas);
     let mut join_set: JoinSet<anyhow::Result<()>> = JoinSet::new();
 
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(LATENCY_REPORT_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let acked = ACKED_MESSAGES.swap(0, Ordering::Relaxed);
+            let latency_micros = ACKED_LATENCY_MICROS.swap(0, Ordering::Relaxed);
+            if acked > 0 {
+                println!(
+                    "acked messages: {}, average round-trip latency: {:.2}ms",
+                    acked,
+                    latency_micros as f64 / acked as f64 / 1000.0
+                );
+            }
+        }
+    });
+
     let mut current: usize = 0;
     for li in load_increments {
         let diff = li.user_count - current;
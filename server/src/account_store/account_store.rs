@@ -0,0 +1,263 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use tokio::sync::Mutex;
+
+const MAX_USERNAME_LENGTH: usize = 32;
+const MIN_PASSWORD_LENGTH: usize = 4;
+
+/// Outcome of an authentication attempt which did not fail because of an internal error
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthOutcome {
+    /// The user is authenticated, carries the user id to use for the session
+    Authenticated(String),
+    /// The user is not authenticated, carries a reason that can be shown to the user
+    Rejected(String),
+}
+
+/// [AccountStore] keeps the credentials of registered users in a local JSON file
+///
+/// Passwords are never stored, only their argon2 hashes in PHC string format.
+/// The whole file is rewritten whenever a new account is registered.
+#[derive(Debug, Clone)]
+pub struct AccountStore {
+    path: PathBuf,
+    /// Username to password hash
+    accounts: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl AccountStore {
+    /// Loads the accounts from the given file, starting with no accounts if the file does not exist
+    pub async fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let accounts = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("could not parse accounts file {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("could not read accounts file {}", path.display()))
+            }
+        };
+
+        Ok(AccountStore {
+            path,
+            accounts: Arc::new(Mutex::new(accounts)),
+        })
+    }
+
+    /// Creates a new account, rejecting invalid or already taken usernames
+    ///
+    /// Every registration rewrites the whole accounts file while holding the store lock, so registrations are
+    /// serialized and each one costs time proportional to the number of accounts. This is fine for thousands of
+    /// accounts, a larger server would need an append-only log or a database instead.
+    pub async fn register(&self, username: &str, password: &str) -> anyhow::Result<AuthOutcome> {
+        if let Err(reason) = validate_credentials(username, password) {
+            return Ok(AuthOutcome::Rejected(reason));
+        }
+
+        if self.accounts.lock().await.contains_key(username) {
+            return Ok(AuthOutcome::Rejected(format!(
+                "username '{}' is already taken",
+                username
+            )));
+        }
+
+        // hashing is deliberately slow, keep it off the async worker threads
+        let password_hash = tokio::task::spawn_blocking({
+            let password = String::from(password);
+
+            move || {
+                let salt = SaltString::generate(&mut OsRng);
+
+                Argon2::default()
+                    .hash_password(password.as_bytes(), &salt)
+                    .map(|hash| hash.to_string())
+                    .map_err(|err| anyhow::anyhow!("could not hash the password: {}", err))
+            }
+        })
+        .await??;
+
+        let mut accounts = self.accounts.lock().await;
+        // the lock was released while hashing, someone else may have taken the username
+        if accounts.contains_key(username) {
+            return Ok(AuthOutcome::Rejected(format!(
+                "username '{}' is already taken",
+                username
+            )));
+        }
+
+        accounts.insert(String::from(username), password_hash);
+        // an account which could not be saved does not exist, it would be gone after a restart
+        if let Err(err) = self.persist(&accounts).await {
+            accounts.remove(username);
+
+            return Err(err);
+        }
+
+        Ok(AuthOutcome::Authenticated(String::from(username)))
+    }
+
+    /// Checks the given credentials against the stored password hash
+    pub async fn login(&self, username: &str, password: &str) -> anyhow::Result<AuthOutcome> {
+        let Some(password_hash) = self.accounts.lock().await.get(username).cloned() else {
+            return Ok(AuthOutcome::Rejected(String::from(
+                "invalid username or password",
+            )));
+        };
+
+        let is_valid = tokio::task::spawn_blocking({
+            let password = String::from(password);
+
+            move || {
+                let parsed_hash = PasswordHash::new(&password_hash)
+                    .map_err(|err| anyhow::anyhow!("stored password hash is invalid: {}", err))?;
+
+                Ok::<_, anyhow::Error>(
+                    Argon2::default()
+                        .verify_password(password.as_bytes(), &parsed_hash)
+                        .is_ok(),
+                )
+            }
+        })
+        .await??;
+
+        Ok(if is_valid {
+            AuthOutcome::Authenticated(String::from(username))
+        } else {
+            AuthOutcome::Rejected(String::from("invalid username or password"))
+        })
+    }
+
+    /// Writes the accounts to a temporary file and moves it over the original one,
+    /// so a crash while writing never leaves a truncated accounts file behind
+    async fn persist(&self, accounts: &HashMap<String, String>) -> anyhow::Result<()> {
+        let serialized = serde_json::to_vec_pretty(accounts)?;
+        let tmp_path = self.path.with_extension("tmp");
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&tmp_path, serialized)
+            .await
+            .with_context(|| format!("could not write accounts file {}", tmp_path.display()))?;
+        tokio::fs::rename(&tmp_path, &self.path)
+            .await
+            .with_context(|| format!("could not replace accounts file {}", self.path.display()))?;
+
+        Ok(())
+    }
+}
+
+fn validate_credentials(username: &str, password: &str) -> Result<(), String> {
+    if username.is_empty() || username.len() > MAX_USERNAME_LENGTH {
+        return Err(format!(
+            "username must be between 1 and {} characters",
+            MAX_USERNAME_LENGTH
+        ));
+    }
+
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(String::from(
+            "username may only contain letters, digits, '-' and '_'",
+        ));
+    }
+
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_register_login_and_reopen() {
+        let dir = std::env::temp_dir().join(nanoid::nanoid!());
+        let path = dir.join("accounts.json");
+
+        let account_store = AccountStore::open(&path).await.unwrap();
+        assert_eq!(
+            account_store.register("user-1", "password").await.unwrap(),
+            AuthOutcome::Authenticated("user-1".to_string())
+        );
+        assert!(matches!(
+            account_store
+                .register("user-1", "other-password")
+                .await
+                .unwrap(),
+            AuthOutcome::Rejected(_)
+        ));
+        assert!(matches!(
+            account_store.register("user 2", "password").await.unwrap(),
+            AuthOutcome::Rejected(_)
+        ));
+        assert!(matches!(
+            account_store.register("user-2", "pw").await.unwrap(),
+            AuthOutcome::Rejected(_)
+        ));
+
+        assert_eq!(
+            account_store.login("user-1", "password").await.unwrap(),
+            AuthOutcome::Authenticated("user-1".to_string())
+        );
+        assert!(matches!(
+            account_store
+                .login("user-1", "wrong-password")
+                .await
+                .unwrap(),
+            AuthOutcome::Rejected(_)
+        ));
+        assert!(matches!(
+            account_store.login("user-2", "password").await.unwrap(),
+            AuthOutcome::Rejected(_)
+        ));
+
+        // passwords are never stored in the clear
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("user-1"));
+        assert!(!contents.contains("\"password\""));
+
+        let account_store = AccountStore::open(&path).await.unwrap();
+        assert_eq!(
+            account_store.login("user-1", "password").await.unwrap(),
+            AuthOutcome::Authenticated("user-1".to_string())
+        );
+        assert!(matches!(
+            account_store.register("user-1", "password").await.unwrap(),
+            AuthOutcome::Rejected(_)
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_register_forgets_unsaved_accounts() {
+        // the accounts file can not be written, a directory is in the way of its temporary file
+        let dir = std::env::temp_dir().join(nanoid::nanoid!());
+        let path = dir.join("accounts.json");
+        std::fs::create_dir_all(path.with_extension("tmp")).unwrap();
+
+        let account_store = AccountStore::open(&path).await.unwrap();
+        assert!(account_store.register("user-1", "password").await.is_err());
+        assert!(matches!(
+            account_store.login("user-1", "password").await.unwrap(),
+            AuthOutcome::Rejected(_)
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,4 @@
+pub use self::account_store::{AccountStore, AuthOutcome};
+
+#[allow(clippy::module_inception)]
+mod account_store;
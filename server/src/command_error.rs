@@ -0,0 +1,30 @@
+use std::fmt;
+
+use comms::event::ErrorCode;
+
+#[derive(Debug)]
+/// [CommandError] is a failure of a user command caused by the user
+///
+/// It is reported back to the user with its own code and message, while any other error
+/// is logged and reported as an internal error. Neither ends the user session.
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        CommandError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CommandError {}
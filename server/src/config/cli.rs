@@ -0,0 +1,57 @@
+use std::{net::IpAddr, path::PathBuf};
+
+use clap::Parser;
+
+/// Command line flags of the server, every flag overrides its counterpart in the configuration file
+#[derive(Debug, Default, Parser)]
+#[command(version, about = "Chat server of the rust-chat-server project")]
+pub struct Cli {
+    /// Path of a TOML configuration file
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on for user connections
+    #[arg(long, value_name = "ADDR")]
+    pub bind_address: Option<IpAddr>,
+    /// Port to listen on for user connections
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Port to listen on for WebSocket connections, WebSockets are disabled without it
+    #[arg(long)]
+    pub websocket_port: Option<u16>,
+    /// Path of a Unix domain socket to listen on for local connections, its file permissions control who can connect
+    #[arg(long, value_name = "PATH")]
+    pub unix_socket: Option<PathBuf>,
+    /// File the set of rooms is kept in, seeded with the default rooms if it does not exist
+    #[arg(long, value_name = "FILE")]
+    pub rooms_file: Option<PathBuf>,
+    /// Directory the message history and moderation of every room is kept in
+    #[arg(long, value_name = "DIR")]
+    pub history_dir: Option<PathBuf>,
+    /// File the user accounts are kept in
+    #[arg(long, value_name = "FILE")]
+    pub accounts_file: Option<PathBuf>,
+    /// Maximum number of concurrent user connections, further connections are refused
+    #[arg(long, value_name = "COUNT")]
+    pub max_connections: Option<usize>,
+    /// Longest frame a client may send in bytes, the connection is closed once it is exceeded
+    #[arg(long, value_name = "BYTES")]
+    pub max_frame_length: Option<usize>,
+    /// Longest content of a room or direct message in bytes
+    #[arg(long, value_name = "BYTES")]
+    pub max_message_length: Option<usize>,
+    /// One of off, error, warn, info, debug and trace
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// Seconds a session whose connection has been lost can be resumed for, zero ends sessions with their connection
+    #[arg(long, value_name = "SECS")]
+    pub resume_grace_secs: Option<u64>,
+    /// Most recent events of a session kept to replay them once it is resumed
+    #[arg(long, value_name = "COUNT")]
+    pub resume_buffer_events: Option<usize>,
+    /// PEM file holding the certificate chain, enables TLS for user connections
+    #[arg(long, value_name = "FILE")]
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM file holding the private key of the certificate
+    #[arg(long, value_name = "FILE")]
+    pub tls_key_file: Option<PathBuf>,
+}
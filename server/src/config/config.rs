@@ -0,0 +1,311 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use comms::transport;
+use log::LevelFilter;
+use serde::Deserialize;
+
+use super::Cli;
+
+/// [ServerConfig] holds every setting of the server
+///
+/// Settings are read from an optional TOML file, then overridden by the command line flags.
+/// Settings which are given nowhere fall back to their defaults.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    /// Port to listen on for WebSocket connections, which are not accepted when it is not given
+    pub websocket_port: Option<u16>,
+    /// Path of a Unix domain socket to listen on for local connections, in addition to TCP
+    pub unix_socket: Option<PathBuf>,
+    pub rooms_file: PathBuf,
+    pub history_dir: PathBuf,
+    pub accounts_file: PathBuf,
+    pub max_connections: usize,
+    /// The longest frame a client may send in bytes, the connection is closed once it is exceeded
+    pub max_frame_length: usize,
+    /// The longest content of a room or direct message in bytes
+    pub max_message_length: usize,
+    pub log_level: String,
+    /// How long a session whose connection has been lost can be resumed, before it leaves its rooms.
+    /// Sessions end with their connection when it is zero
+    pub resume_grace_secs: u64,
+    /// How many of the most recent events of a session are kept to replay them once it is resumed
+    pub resume_buffer_events: usize,
+    /// PEM file holding the certificate chain, user connections are encrypted with TLS when it is given
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM file holding the private key of the certificate
+    pub tls_key_file: Option<PathBuf>,
+    /// Limits on how fast users may send messages, only configurable in the file
+    pub rate_limit: RateLimitConfig,
+}
+
+/// [RateLimitConfig] holds the flood protection settings, given in the `[rate_limit]` table of the configuration file
+///
+/// Every session and every user across all of their sessions may send a burst of messages,
+/// after which messages are accepted at a constant rate. Users who keep hitting the limits
+/// are muted for a while, then disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub session_messages_per_second: f64,
+    pub session_burst: u32,
+    pub user_messages_per_second: f64,
+    pub user_burst: u32,
+    /// Rate limited messages in a row, without a minute of rest in between, before the user is muted
+    pub mute_after_strikes: u32,
+    pub mute_secs: u64,
+    /// Rate limited messages in a row before the session is closed, counted the same way as for muting
+    pub disconnect_after_strikes: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            session_messages_per_second: 5.0,
+            session_burst: 10,
+            user_messages_per_second: 10.0,
+            user_burst: 20,
+            mute_after_strikes: 10,
+            mute_secs: 60,
+            disconnect_after_strikes: 30,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, rate) in [
+            (
+                "session_messages_per_second",
+                self.session_messages_per_second,
+            ),
+            ("user_messages_per_second", self.user_messages_per_second),
+        ] {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(anyhow::anyhow!("rate_limit.{} must be positive", name));
+            }
+        }
+
+        for (name, value) in [
+            ("session_burst", self.session_burst),
+            ("user_burst", self.user_burst),
+            ("mute_after_strikes", self.mute_after_strikes),
+        ] {
+            if value == 0 {
+                return Err(anyhow::anyhow!("rate_limit.{} must be at least 1", name));
+            }
+        }
+
+        if self.disconnect_after_strikes <= self.mute_after_strikes {
+            return Err(anyhow::anyhow!(
+                "rate_limit.disconnect_after_strikes must be greater than mute_after_strikes {}",
+                self.mute_after_strikes
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8080,
+            websocket_port: None,
+            unix_socket: None,
+            rooms_file: PathBuf::from("data/rooms.json"),
+            history_dir: PathBuf::from("data/history"),
+            accounts_file: PathBuf::from("data/accounts.json"),
+            max_connections: 10_000,
+            max_frame_length: transport::server::DEFAULT_MAX_FRAME_LENGTH,
+            max_message_length: 4096,
+            log_level: String::from("info"),
+            resume_grace_secs: 60,
+            resume_buffer_events: 256,
+            tls_cert_file: None,
+            tls_key_file: None,
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds the configuration from the file given in the command line flags, if any, and the flags themselves
+    pub fn load(cli: Cli) -> anyhow::Result<Self> {
+        let config = match cli.config.as_ref() {
+            Some(path) => Self::from_file(path)?,
+            None => ServerConfig::default(),
+        };
+
+        let config = config.with_overrides(cli);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+
+        toml::from_str(&contents).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    fn with_overrides(self, cli: Cli) -> Self {
+        ServerConfig {
+            bind_address: cli.bind_address.unwrap_or(self.bind_address),
+            port: cli.port.unwrap_or(self.port),
+            websocket_port: cli.websocket_port.or(self.websocket_port),
+            unix_socket: cli.unix_socket.or(self.unix_socket),
+            rooms_file: cli.rooms_file.unwrap_or(self.rooms_file),
+            history_dir: cli.history_dir.unwrap_or(self.history_dir),
+            accounts_file: cli.accounts_file.unwrap_or(self.accounts_file),
+            max_connections: cli.max_connections.unwrap_or(self.max_connections),
+            max_frame_length: cli.max_frame_length.unwrap_or(self.max_frame_length),
+            max_message_length: cli.max_message_length.unwrap_or(self.max_message_length),
+            log_level: cli.log_level.unwrap_or(self.log_level),
+            resume_grace_secs: cli.resume_grace_secs.unwrap_or(self.resume_grace_secs),
+            resume_buffer_events: cli
+                .resume_buffer_events
+                .unwrap_or(self.resume_buffer_events),
+            tls_cert_file: cli.tls_cert_file.or(self.tls_cert_file),
+            tls_key_file: cli.tls_key_file.or(self.tls_key_file),
+            rate_limit: self.rate_limit,
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.max_connections == 0 {
+            return Err(anyhow::anyhow!("max_connections must be at least 1"));
+        }
+
+        if self.max_message_length == 0 {
+            return Err(anyhow::anyhow!("max_message_length must be at least 1"));
+        }
+
+        // a message has to fit into a frame alongside with the rest of its command
+        if self.max_message_length >= self.max_frame_length {
+            return Err(anyhow::anyhow!(
+                "max_message_length must be less than max_frame_length {}",
+                self.max_frame_length
+            ));
+        }
+
+        for (name, path) in [
+            ("rooms_file", &self.rooms_file),
+            ("history_dir", &self.history_dir),
+            ("accounts_file", &self.accounts_file),
+        ] {
+            if path.as_os_str().is_empty() {
+                return Err(anyhow::anyhow!("{} must not be empty", name));
+            }
+        }
+
+        if self.websocket_port == Some(self.port) {
+            return Err(anyhow::anyhow!(
+                "websocket_port must be different from port {}",
+                self.port
+            ));
+        }
+
+        if cfg!(not(unix)) && self.unix_socket.is_some() {
+            return Err(anyhow::anyhow!(
+                "unix_socket is only supported on unix platforms"
+            ));
+        }
+
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            return Err(anyhow::anyhow!(
+                "tls_cert_file and tls_key_file must be given together"
+            ));
+        }
+
+        self.rate_limit.validate()?;
+        self.log_level_filter()?;
+
+        Ok(())
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    pub fn websocket_socket_addr(&self) -> Option<SocketAddr> {
+        self.websocket_port
+            .map(|port| SocketAddr::new(self.bind_address, port))
+    }
+
+    pub fn log_level_filter(&self) -> anyhow::Result<LevelFilter> {
+        self.log_level.parse().map_err(|_| {
+            anyhow::anyhow!(
+                "log_level must be one of off, error, warn, info, debug and trace, got '{}'",
+                self.log_level
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_override_config_file() {
+        let path = std::env::temp_dir().join(format!("{}.toml", nanoid::nanoid!()));
+        std::fs::write(
+            &path,
+            "port = 9000\nmax_connections = 5\nlog_level = \"debug\"\n\n[rate_limit]\nuser_burst = 50\n",
+        )
+        .unwrap();
+
+        let config = ServerConfig::load(Cli {
+            config: Some(path),
+            max_connections: Some(10),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.max_connections, 10);
+        assert_eq!(config.log_level_filter().unwrap(), LevelFilter::Debug);
+        assert_eq!(config.rooms_file, ServerConfig::default().rooms_file);
+        assert_eq!(config.rate_limit.user_burst, 50);
+        assert_eq!(
+            config.rate_limit.session_burst,
+            RateLimitConfig::default().session_burst
+        );
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        let path = std::env::temp_dir().join(format!("{}.toml", nanoid::nanoid!()));
+        std::fs::write(&path, "prot = 9000\n").unwrap();
+
+        assert!(ServerConfig::load(Cli {
+            config: Some(path),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(ServerConfig::load(Cli {
+            log_level: Some("loud".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(ServerConfig::load(Cli {
+            max_message_length: Some(ServerConfig::default().max_frame_length),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(ServerConfig::load(Cli {
+            tls_cert_file: Some(PathBuf::from("cert.pem")),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,6 @@
+pub use self::cli::Cli;
+pub use self::config::{RateLimitConfig, ServerConfig};
+
+mod cli;
+#[allow(clippy::module_inception)]
+mod config;
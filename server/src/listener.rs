@@ -0,0 +1,318 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use comms::transport::{
+    self,
+    server::{CommandStream, EventWriter},
+    tls::TlsAcceptor,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+use crate::config::ServerConfig;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The transport a TCP connection has been accepted for
+#[derive(Debug, Clone, Copy)]
+pub enum Transport {
+    /// Newline delimited JSON over a raw stream
+    Tcp,
+    /// One JSON message per WebSocket text message
+    WebSocket,
+}
+
+/// A user connection which has been accepted, but whose handshakes have not been performed yet
+pub enum Connection {
+    Tcp(TcpStream, Transport),
+    /// Local connections are never encrypted, the permissions of the socket file control who can connect
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Connection {
+    /// Performs the TLS and WebSocket handshakes as needed, then splits the connection into commands and events
+    ///
+    /// Byte streams negotiate their codec with the first command, which is bound by the handshake timeout
+    /// as well, clients send it as soon as they are connected.
+    pub async fn split(
+        self,
+        tls_acceptor: Option<TlsAcceptor>,
+        max_frame_length: usize,
+    ) -> anyhow::Result<(CommandStream, EventWriter)> {
+        with_handshake_timeout(async move {
+            match self {
+                Connection::Tcp(socket, Transport::Tcp) => match tls_acceptor {
+                    Some(tls_acceptor) => {
+                        let stream = accept_tls(&tls_acceptor, socket).await?;
+                        transport::server::accept_stream(stream, max_frame_length).await
+                    }
+                    None => transport::server::accept_stream(socket, max_frame_length).await,
+                },
+                Connection::Tcp(socket, Transport::WebSocket) => match tls_acceptor {
+                    Some(tls_acceptor) => Ok(transport::server::split_websocket_stream(
+                        accept_websocket(
+                            accept_tls(&tls_acceptor, socket).await?,
+                            max_frame_length,
+                        )
+                        .await?,
+                    )),
+                    None => Ok(transport::server::split_websocket_stream(
+                        accept_websocket(socket, max_frame_length).await?,
+                    )),
+                },
+                #[cfg(unix)]
+                Connection::Unix(socket) => {
+                    transport::server::accept_stream(socket, max_frame_length).await
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// Bounds the handshakes of a connection, a client which never completes them must not hold its connection permit forever
+async fn with_handshake_timeout<T>(
+    handshakes: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshakes)
+        .await
+        .context("handshake timed out")?
+}
+
+/// [Listeners] accepts user connections on every enabled listener
+pub struct Listeners {
+    tcp: TcpListener,
+    websocket: Option<TcpListener>,
+    #[cfg(unix)]
+    unix: Option<unix::UnixSocketListener>,
+}
+
+impl Listeners {
+    /// Binds every listener enabled in the configuration
+    pub async fn bind(config: &ServerConfig) -> anyhow::Result<Self> {
+        let tcp = bind_tcp(config.socket_addr()).await?;
+        let websocket = match config.websocket_socket_addr() {
+            Some(addr) => Some(bind_tcp(addr).await?),
+            None => None,
+        };
+        #[cfg(unix)]
+        let unix = match config.unix_socket.as_ref() {
+            Some(path) => Some(unix::UnixSocketListener::bind(path)?),
+            None => None,
+        };
+
+        Ok(Listeners {
+            tcp,
+            websocket,
+            #[cfg(unix)]
+            unix,
+        })
+    }
+
+    /// Waits for the next user connection on any of the listeners
+    ///
+    /// # Returns
+    ///
+    /// - The connection alongside with a description of the peer for logging purposes
+    ///
+    /// # Cancel Safety
+    ///
+    /// This method is cancel-safe, no connection is lost if another branch of a [tokio::select!] completes first.
+    pub async fn accept(&self) -> std::io::Result<(Connection, String)> {
+        tokio::select! {
+            result = self.tcp.accept() => result.map(|(socket, addr)| {
+                (Connection::Tcp(socket, Transport::Tcp), addr.to_string())
+            }),
+            result = accept_optional(self.websocket.as_ref()) => result.map(|(socket, addr)| {
+                (Connection::Tcp(socket, Transport::WebSocket), addr.to_string())
+            }),
+            result = self.accept_unix() => result,
+        }
+    }
+
+    #[cfg(unix)]
+    async fn accept_unix(&self) -> std::io::Result<(Connection, String)> {
+        match self.unix.as_ref() {
+            Some(unix) => unix.accept().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn accept_unix(&self) -> std::io::Result<(Connection, String)> {
+        std::future::pending().await
+    }
+}
+
+async fn bind_tcp(addr: std::net::SocketAddr) -> anyhow::Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("could not bind to {}", addr))
+}
+
+/// Accepts from the listener if there is one, never completes otherwise
+async fn accept_optional(
+    listener: Option<&TcpListener>,
+) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn accept_tls(
+    tls_acceptor: &TlsAcceptor,
+    socket: TcpStream,
+) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + 'static> {
+    tls_acceptor
+        .accept(socket)
+        .await
+        .context("TLS handshake failed")
+}
+
+async fn accept_websocket<S>(
+    stream: S,
+    max_frame_length: usize,
+) -> anyhow::Result<tokio_tungstenite::WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // a message may be split into many frames, both are bound to the same limit as a line over TCP
+    let config = WebSocketConfig {
+        max_message_size: Some(max_frame_length),
+        max_frame_size: Some(max_frame_length),
+        ..Default::default()
+    };
+
+    tokio_tungstenite::accept_async_with_config(stream, Some(config))
+        .await
+        .context("WebSocket handshake failed")
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        path::{Path, PathBuf},
+    };
+
+    use anyhow::Context;
+    use tokio::net::UnixListener;
+
+    use super::Connection;
+
+    /// Only the owner and the group of the server process can connect to the socket
+    const SOCKET_FILE_MODE: u32 = 0o660;
+    /// The socket is created in a directory only the server process can enter
+    const PRIVATE_DIR_MODE: u32 = 0o700;
+
+    /// [UnixSocketListener] removes its socket file when it is dropped
+    pub struct UnixSocketListener {
+        listener: UnixListener,
+        path: PathBuf,
+    }
+
+    impl UnixSocketListener {
+        /// Binds to the given path, replacing the socket file a previous run may have left behind
+        pub fn bind(path: &Path) -> anyhow::Result<Self> {
+            match std::fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+                    .with_context(|| {
+                        format!("could not remove stale unix socket {}", path.display())
+                    })?,
+                Ok(_) => {
+                    return Err(anyhow::anyhow!(
+                        "{} already exists and is not a unix socket",
+                        path.display()
+                    ))
+                }
+                Err(_) => {}
+            }
+
+            // the socket is created with the umask of the process, it is only moved into place once its permissions
+            // are restricted so that nobody else can connect in between
+            let private_dir = path
+                .parent()
+                .unwrap_or(Path::new(""))
+                .join(format!(".{}", nanoid::nanoid!(8)));
+            std::fs::DirBuilder::new()
+                .mode(PRIVATE_DIR_MODE)
+                .create(&private_dir)
+                .with_context(|| {
+                    format!(
+                        "could not create directory {} for the unix socket",
+                        private_dir.display()
+                    )
+                })?;
+            let listener = bind_privately(&private_dir, path);
+            let _ = std::fs::remove_dir_all(&private_dir);
+
+            Ok(UnixSocketListener {
+                listener: listener?,
+                path: path.to_path_buf(),
+            })
+        }
+
+        pub async fn accept(&self) -> std::io::Result<(Connection, String)> {
+            let (socket, _) = self.listener.accept().await?;
+
+            Ok((Connection::Unix(socket), self.path.display().to_string()))
+        }
+    }
+
+    /// Binds to a socket within the given directory, then moves it to the given path with its final permissions
+    fn bind_privately(private_dir: &Path, path: &Path) -> anyhow::Result<UnixListener> {
+        let private_path = private_dir.join("socket");
+        let listener = UnixListener::bind(&private_path)
+            .with_context(|| format!("could not bind to unix socket {}", path.display()))?;
+        std::fs::set_permissions(
+            &private_path,
+            std::fs::Permissions::from_mode(SOCKET_FILE_MODE),
+        )
+        .with_context(|| {
+            format!(
+                "could not set the permissions of unix socket {}",
+                path.display()
+            )
+        })?;
+        std::fs::rename(&private_path, path)
+            .with_context(|| format!("could not move unix socket to {}", path.display()))?;
+
+        Ok(listener)
+    }
+
+    impl Drop for UnixSocketListener {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_socket_is_created_with_restricted_permissions() {
+            let dir = std::env::temp_dir().join(nanoid::nanoid!());
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("chat.sock");
+
+            let listener = UnixSocketListener::bind(&path).unwrap();
+
+            let metadata = std::fs::symlink_metadata(&path).unwrap();
+            assert!(metadata.file_type().is_socket());
+            assert_eq!(metadata.permissions().mode() & 0o777, SOCKET_FILE_MODE);
+            // nothing is left behind but the socket
+            assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+            tokio::net::UnixStream::connect(&path).await.unwrap();
+
+            drop(listener);
+            assert!(!path.exists());
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use chrono::Utc;
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// [StderrLogger] writes every log record of the enabled levels to stderr
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} {:<5} {}: {}",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger for the whole process, records below the given level are discarded
+pub fn init(level: LevelFilter) -> anyhow::Result<()> {
+    log::set_logger(&LOGGER).map_err(|e| anyhow::anyhow!("could not install the logger: {}", e))?;
+    log::set_max_level(level);
+
+    Ok(())
+}
@@ -1,50 +1,187 @@
 use std::sync::Arc;
 
 use anyhow::Context;
+use clap::Parser;
+use comms::transport;
 use room_manager::RoomManagerBuilder;
-use tokio::{net::TcpListener, signal::ctrl_c, sync::broadcast, task::JoinSet};
+use tokio::{
+    signal::ctrl_c,
+    sync::{broadcast, Semaphore},
+    task::JoinSet,
+};
 
-use crate::room_manager::ChatRoomMetadata;
+use crate::{
+    account_store::AccountStore,
+    config::{Cli, ServerConfig},
+    listener::Listeners,
+    metrics::Metrics,
+    session::{ResumableSessions, SessionRegistry},
+};
 
+mod account_store;
+mod command_error;
+mod config;
+mod listener;
+mod logger;
+mod metrics;
+mod room_catalogue;
 mod room_manager;
 mod session;
 
-const PORT: u16 = 8080;
-const CHAT_ROOMS_METADATAS: &str = include_str!("../resources/chat_rooms_metadatas.json");
+/// How often the metrics are logged while the server is running
+const METRICS_REPORT_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() {
-    let chat_room_metadatas: Vec<ChatRoomMetadata> = serde_json::from_str(CHAT_ROOMS_METADATAS)
-        .expect("could not parse the chat rooms metadatas");
+    // invalid configuration or unusable data files are reported once instead of panicking
+    let result = ServerConfig::load(Cli::parse()).and_then(|config| {
+        logger::init(config.log_level_filter()?)?;
+
+        Ok(config)
+    });
+    let result = match result {
+        Ok(config) => run(config).await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(config: ServerConfig) -> anyhow::Result<()> {
     let room_manager = Arc::new(
-        chat_room_metadatas
+        room_catalogue::load(&config.rooms_file)?
             .into_iter()
-            .fold(RoomManagerBuilder::new(), |builder, metadata| {
-                builder.create_room(metadata)
-            })
+            .try_fold(
+                RoomManagerBuilder::new(&config.history_dir, &config.rooms_file),
+                |builder, metadata| builder.create_room(metadata),
+            )
+            .context("could not create the chat rooms")?
             .build(),
     );
+    let account_store = AccountStore::open(&config.accounts_file)
+        .await
+        .with_context(|| {
+            format!(
+                "could not open accounts file {}",
+                config.accounts_file.display()
+            )
+        })?;
+    let session_registry = SessionRegistry::new();
+    let resumable_sessions = ResumableSessions::new(
+        std::time::Duration::from_secs(config.resume_grace_secs),
+        config.resume_buffer_events,
+    );
+    let metrics = Metrics::new();
+    let tls_acceptor = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert_file), Some(key_file)) => Some(
+            transport::tls::acceptor(cert_file, key_file)
+                .context("could not load the TLS certificate")?,
+        ),
+        _ => None,
+    };
 
     let mut join_set: JoinSet<anyhow::Result<()>> = JoinSet::new();
-    let server = TcpListener::bind(format!("0.0.0.0:{}", PORT))
-        .await
-        .expect("could not bind to the port");
+    let listeners = Listeners::bind(&config).await?;
+    // every connection holds a permit until its session ends
+    let connection_permits = Arc::new(Semaphore::new(config.max_connections));
     let (quit_tx, quit_rx) = broadcast::channel::<()>(1);
 
-    println!("Listening on port {}", PORT);
+    // the rooms file can be edited while the server is running, `kill -HUP` applies the changes
+    #[cfg(unix)]
+    tokio::spawn(room_catalogue::reload_on_hangup(
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .context("could not listen for SIGHUP")?,
+        config.rooms_file.clone(),
+        Arc::clone(&room_manager),
+        session_registry.clone(),
+        quit_rx.resubscribe(),
+    ));
+
+    tokio::spawn(
+        metrics
+            .clone()
+            .report_periodically(METRICS_REPORT_PERIOD, quit_rx.resubscribe()),
+    );
+
+    let tls_suffix = if tls_acceptor.is_some() {
+        " with TLS"
+    } else {
+        ""
+    };
+    log::info!("Listening on {}{}", config.socket_addr(), tls_suffix);
+    if let Some(addr) = config.websocket_socket_addr() {
+        log::info!("Listening for WebSockets on {}{}", addr, tls_suffix);
+    }
+    if let Some(path) = config.unix_socket.as_ref() {
+        log::info!("Listening on unix socket {}", path.display());
+    }
+
+    let limits = session::SessionLimits {
+        max_message_length: config.max_message_length,
+        rate_limiter: session::RateLimiter::new(config.rate_limit),
+    };
+    let max_frame_length = config.max_frame_length;
     loop {
         tokio::select! {
             Ok(_) = ctrl_c() => {
-                println!("Server interrupted. Gracefully shutting down.");
-                quit_tx.send(()).context("failed to send quit signal").unwrap();
+                log::info!("Server interrupted. Gracefully shutting down.");
+                quit_tx.send(()).context("failed to send quit signal")?;
                 break;
             }
-            Ok((socket, _)) = server.accept() => {
-                join_set.spawn(session::handle_user_session(Arc::clone(&room_manager), quit_rx.resubscribe(), socket));
+            Ok((connection, addr)) = listeners.accept() => {
+                let Ok(permit) = Arc::clone(&connection_permits).try_acquire_owned() else {
+                    log::warn!("Refusing connection from {}, the server is at its limit of {} connections", addr, config.max_connections);
+                    continue;
+                };
+                let room_manager = Arc::clone(&room_manager);
+                let account_store = account_store.clone();
+                let session_registry = session_registry.clone();
+                let resumable_sessions = resumable_sessions.clone();
+                let mut quit_rx = quit_rx.resubscribe();
+                let tls_acceptor = tls_acceptor.clone();
+                let limits = limits.clone();
+                let metrics = metrics.clone();
+
+                join_set.spawn(async move {
+                    // the connection may wait for its first command, which must not hold up the shutdown
+                    let split = tokio::select! {
+                        split = connection.split(tls_acceptor, max_frame_length) => split,
+                        _ = quit_rx.recv() => return Ok(()),
+                    };
+                    let result = match split {
+                        Ok(connection) => session::handle_user_session(room_manager, account_store, session_registry, resumable_sessions, limits, metrics, quit_rx, connection).await,
+                        Err(e) => Err(e.context(format!("could not accept connection from {}", addr))),
+                    };
+                    drop(permit);
+
+                    result
+                });
+            }
+            Some(result) = join_set.join_next() => {
+                log_session_result(result);
             }
         }
     }
 
-    while join_set.join_next().await.is_some() {}
-    println!("Server shut down");
+    while let Some(result) = join_set.join_next().await {
+        log_session_result(result);
+    }
+    log::info!(
+        "Server shut down, {} sessions fell behind a room and missed {} events",
+        metrics.lagged_sessions(),
+        metrics.missed_events()
+    );
+
+    Ok(())
+}
+
+fn log_session_result(result: Result<anyhow::Result<()>, tokio::task::JoinError>) {
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::debug!("User session ended with an error: {:#}", e),
+        Err(e) => log::warn!("User session task failed: {}", e),
+    }
 }
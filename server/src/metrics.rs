@@ -0,0 +1,69 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::broadcast;
+
+#[derive(Debug, Default)]
+struct Counters {
+    lagged_sessions: AtomicU64,
+    missed_events: AtomicU64,
+}
+
+/// [Metrics] counts what happens across every session of the server, cheap to clone and share
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a session falling behind a room for the first time
+    pub fn record_lagged_session(&self) {
+        self.0.lagged_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records events of a room, or sent directly to a session, dropped before a lagging session could receive them
+    pub fn record_missed_events(&self, count: u64) {
+        self.0.missed_events.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// How many sessions have fallen behind a room at least once
+    pub fn lagged_sessions(&self) -> u64 {
+        self.0.lagged_sessions.load(Ordering::Relaxed)
+    }
+
+    /// How many events have been dropped for lagging sessions in total
+    pub fn missed_events(&self) -> u64 {
+        self.0.missed_events.load(Ordering::Relaxed)
+    }
+
+    /// Logs the counters every period while the server is running, until it shuts down
+    /// Periods in which nothing has changed are not logged
+    pub async fn report_periodically(self, period: Duration, mut quit_rx: broadcast::Receiver<()>) {
+        let mut interval = tokio::time::interval(period);
+        let mut last_report = (0, 0);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let report = (self.lagged_sessions(), self.missed_events());
+                    if report != last_report {
+                        log::info!(
+                            "{} sessions have fallen behind a room and missed {} events so far",
+                            report.0,
+                            report.1
+                        );
+                        last_report = report;
+                    }
+                }
+                _ = quit_rx.recv() => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use comms::event::{self, Event, RoomDetail};
+use tokio::sync::broadcast;
+
+use crate::{
+    room_manager::{ChatRoomMetadata, RoomManager},
+    session::SessionRegistry,
+};
+
+const CHAT_ROOMS_METADATAS: &str = include_str!("../resources/chat_rooms_metadatas.json");
+
+/// Reads the set of rooms from the rooms file
+/// The embedded rooms are only used until the first room is created or deleted at runtime
+pub fn load(rooms_file: &Path) -> anyhow::Result<Vec<ChatRoomMetadata>> {
+    match std::fs::read_to_string(rooms_file) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("could not parse rooms file {}", rooms_file.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            serde_json::from_str(CHAT_ROOMS_METADATAS)
+                .context("could not parse the default chat rooms")
+        }
+        Err(e) => {
+            Err(e).with_context(|| format!("could not read rooms file {}", rooms_file.display()))
+        }
+    }
+}
+
+/// Reloads the set of rooms from the rooms file every time the process receives SIGHUP, until the server shuts down
+/// A rooms file which can not be loaded is reported and the running rooms are kept as they are
+#[cfg(unix)]
+pub async fn reload_on_hangup(
+    mut hangups: tokio::signal::unix::Signal,
+    rooms_file: std::path::PathBuf,
+    room_manager: Arc<RoomManager>,
+    session_registry: SessionRegistry,
+    mut quit_rx: broadcast::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            Some(_) = hangups.recv() => {
+                match reload(&rooms_file, &room_manager, &session_registry).await {
+                    Ok(()) => log::info!("Reloaded the rooms from {}", rooms_file.display()),
+                    Err(e) => log::error!("Could not reload the rooms: {:#}", e),
+                }
+            }
+            _ = quit_rx.recv() => break,
+        }
+    }
+}
+
+/// Reconciles the room manager with the rooms file, then notifies every session
+/// The sessions are never waited for, the ones which have fallen behind resync their rooms once they catch up
+async fn reload(
+    rooms_file: &Path,
+    room_manager: &RoomManager,
+    session_registry: &SessionRegistry,
+) -> anyhow::Result<()> {
+    let closed_room_names = room_manager.reload(load(rooms_file)?).await?;
+
+    // every session drops its handle of a closed room when it receives the event, same as a deleted room
+    for room in closed_room_names {
+        session_registry.broadcast(&Event::RoomDeleted(event::RoomDeletedBroadcastEvent {
+            room,
+        }));
+    }
+
+    session_registry.broadcast(&Event::RoomList(event::RoomListBroadcastEvent {
+        rooms: room_manager
+            .chat_room_metadatas()
+            .iter()
+            .map(|metadata| RoomDetail {
+                name: metadata.name.clone(),
+                description: metadata.description.clone(),
+            })
+            .collect(),
+    }));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        room_manager::{RoomManagerBuilder, SessionAndUserId},
+        session::direct_event_channel,
+    };
+
+    fn metadata(name: &str) -> ChatRoomMetadata {
+        ChatRoomMetadata {
+            name: name.to_string(),
+            description: name.to_string(),
+            owner: None,
+            operators: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_never_waits_for_a_stalled_session() {
+        let dir = std::env::temp_dir().join(nanoid::nanoid!());
+        let rooms_file = dir.join("rooms.json");
+        let mut builder = RoomManagerBuilder::new(&dir, &rooms_file);
+        for index in 0..200 {
+            builder = builder
+                .create_room(metadata(&format!("room-{}", index)))
+                .unwrap();
+        }
+        let room_manager = builder.build();
+
+        // the session never reads its events, its channel is full long before every room is closed
+        let session_registry = SessionRegistry::new();
+        let (event_tx, event_rx) = direct_event_channel();
+        session_registry.register(
+            &SessionAndUserId {
+                session_id: "session-1".to_string(),
+                user_id: "user-1".to_string(),
+            },
+            event_tx,
+        );
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            &rooms_file,
+            serde_json::to_string(&vec![metadata("room-0")]).unwrap(),
+        )
+        .unwrap();
+
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            reload(&rooms_file, &room_manager, &session_registry),
+        )
+        .await
+        .expect("the reload should not wait for the stalled session")
+        .unwrap();
+
+        assert_eq!(room_manager.chat_room_metadatas().len(), 1);
+        assert!(event_rx.take_missed_events() > 0);
+    }
+}
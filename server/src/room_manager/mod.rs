@@ -1,9 +1,10 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
-use tokio::sync::Mutex;
+use anyhow::Context;
 
 use self::room::ChatRoom;
 pub use self::room::{ChatRoomMetadata, SessionAndUserId, UserSessionHandle};
+use self::room_manager::validate_room_metadata;
 
 pub use self::room_manager::RoomManager;
 
@@ -13,35 +14,43 @@ mod room_manager;
 
 #[derive(Debug)]
 pub struct RoomManagerBuilder {
-    chat_rooms: Vec<(ChatRoomMetadata, Arc<Mutex<room::ChatRoom>>)>,
+    /// The directory that holds the message history and moderation of every room
+    history_dir: PathBuf,
+    /// The file the set of rooms is persisted to when rooms are created or deleted
+    rooms_file_path: PathBuf,
+    chat_rooms: Vec<(ChatRoomMetadata, Arc<room::ChatRoom>)>,
 }
 
 impl RoomManagerBuilder {
-    pub fn new() -> Self {
+    pub fn new(history_dir: impl Into<PathBuf>, rooms_file_path: impl Into<PathBuf>) -> Self {
         RoomManagerBuilder {
+            history_dir: history_dir.into(),
+            rooms_file_path: rooms_file_path.into(),
             chat_rooms: Vec::new(),
         }
     }
 
-    /// Add a room to the room manager
-    /// Will panic if a room with the same name already exists
-    pub fn create_room(mut self, metadata: ChatRoomMetadata) -> Self {
-        let chat_room = Arc::new(Mutex::new(ChatRoom::new(metadata.clone())));
+    /// Add a room to the room manager, loading its message history and moderation from the history directory
+    /// Fails if the room is invalid or a room with the same name already exists
+    pub fn create_room(mut self, metadata: ChatRoomMetadata) -> anyhow::Result<Self> {
+        validate_room_metadata(&metadata)
+            .with_context(|| format!("invalid room '{}'", metadata.name))?;
 
         if self
             .chat_rooms
             .iter()
             .any(|(m, _)| m.name.eq(&metadata.name))
         {
-            panic!("room with the same name already exists");
+            return Err(anyhow::anyhow!("room '{}' is listed twice", metadata.name));
         }
 
+        let chat_room = Arc::new(ChatRoom::open(metadata.clone(), &self.history_dir)?);
         self.chat_rooms.push((metadata, chat_room));
 
-        self
+        Ok(self)
     }
 
     pub fn build(self) -> RoomManager {
-        RoomManager::new(self.chat_rooms)
+        RoomManager::new(self.history_dir, self.rooms_file_path, self.chat_rooms)
     }
 }
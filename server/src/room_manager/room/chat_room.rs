@@ -1,9 +1,20 @@
-use comms::event::{self, Event};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use comms::{
+    event::{self, ErrorCode},
+    transport::server::SharedEvent,
+};
 use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast;
 
+use crate::command_error::CommandError;
+
 use super::{
-    user_registry::UserRegistry, user_session_handle::UserSessionHandle, SessionAndUserId,
+    room_moderation::ModerationSnapshot, user_registry::UserRegistry,
+    user_session_handle::UserSessionHandle, RoomHistory, RoomModeration, SessionAndUserId,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,78 +22,259 @@ use super::{
 pub struct ChatRoomMetadata {
     pub name: String,
     pub description: String,
+    /// The user who created the room, rooms defined by the server have no owner unless the rooms file gives one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Operators given by the rooms file, in addition to the ones appointed by the owner
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub operators: Vec<String>,
 }
 
 const BROADCAST_CHANNEL_CAPACITY: usize = 100;
 
+pub type RoomJoinResult = (
+    broadcast::Receiver<SharedEvent>,
+    UserSessionHandle,
+    Vec<String>,
+    Vec<event::UserMessageBroadcastEvent>,
+);
+
 #[derive(Debug)]
 /// [ChatRoom] handles the participants of a chat room and the primary broadcast channel
 /// A [UserSessionHandle] is handed out to a user when they join the room
+///
+/// A room is shared without an outer lock, users joining, leaving and being moderated at the same time
+/// only wait for each other within the shard of the [UserRegistry] that holds them.
 pub struct ChatRoom {
-    metadata: ChatRoomMetadata,
-    broadcast_tx: broadcast::Sender<event::Event>,
+    name: String,
+    /// Every event is serialized once for all the participants, however many of them there are
+    broadcast_tx: broadcast::Sender<SharedEvent>,
+    history: Arc<Mutex<RoomHistory>>,
+    moderation: Arc<Mutex<RoomModeration>>,
+    /// The version of the last moderation snapshot written, writes are serialized without holding the room locks
+    saved_moderation_version: tokio::sync::Mutex<u64>,
     user_registry: UserRegistry,
 }
 
 impl ChatRoom {
-    pub fn new(metadata: ChatRoomMetadata) -> Self {
+    pub fn new(
+        metadata: ChatRoomMetadata,
+        history: RoomHistory,
+        moderation: RoomModeration,
+    ) -> Self {
         let (broadcast_tx, _) = broadcast::channel(BROADCAST_CHANNEL_CAPACITY);
 
         ChatRoom {
-            metadata,
+            name: metadata.name,
             broadcast_tx,
+            history: Arc::new(Mutex::new(history)),
+            moderation: Arc::new(Mutex::new(moderation)),
+            saved_moderation_version: tokio::sync::Mutex::new(0),
             user_registry: UserRegistry::new(),
         }
     }
 
-    pub fn get_unique_user_ids(&self) -> Vec<String> {
-        self.user_registry.get_unique_user_ids()
+    /// Create a room, loading its message history and moderation from the history directory
+    pub fn open(metadata: ChatRoomMetadata, history_dir: &Path) -> anyhow::Result<Self> {
+        let history = RoomHistory::open(&RoomHistory::path(history_dir, &metadata.name))?;
+        let moderation = RoomModeration::open(
+            &RoomModeration::path(history_dir, &metadata.name),
+            metadata.owner.clone(),
+            metadata.operators.clone(),
+        )?;
+
+        Ok(ChatRoom::new(metadata, history, moderation))
+    }
+
+    /// Replace the owner and the operators given by the rooms file, the name of a room never changes
+    pub fn update_roles(&self, metadata: &ChatRoomMetadata) {
+        self.lock_moderation()
+            .set_configured_roles(metadata.owner.clone(), metadata.operators.clone());
     }
 
     /// Add a participant to the room and broadcast that they joined
+    /// Fails if the user is banned from the room
     ///
     /// # Returns
     ///
     /// - A broadcast receiver for the user to receive messages from the room
     /// - A [UserSessionHandle] for the user to be able to interact with the room
-    pub fn join(
-        &mut self,
-        session_and_user_id: &SessionAndUserId,
-    ) -> (broadcast::Receiver<Event>, UserSessionHandle) {
+    /// - The users of the room, including the joining user
+    /// - The most recent messages of the room, sent before the broadcast receiver was created
+    pub fn join(&self, session_and_user_id: &SessionAndUserId) -> anyhow::Result<RoomJoinResult> {
         let broadcast_tx = self.broadcast_tx.clone();
-        let broadcast_rx = broadcast_tx.subscribe();
+        // subscribe while holding the history lock, so no message falls between the history and the receiver
+        let (broadcast_rx, recent_messages) = {
+            let history = self
+                .history
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            (broadcast_tx.subscribe(), history.recent_messages())
+        };
+
+        {
+            // the ban is checked under the lock of the user, so an operator banning them at the same time
+            // either sees them in the room and removes them, or bans them before they are let in
+            let mut participants = self.user_registry.lock(&session_and_user_id.user_id);
+            if self
+                .lock_moderation()
+                .is_banned(&session_and_user_id.user_id)
+            {
+                return Err(CommandError::new(
+                    ErrorCode::Banned,
+                    format!("you are banned from room '{}'", self.name),
+                )
+                .into());
+            }
+
+            // If the user is new e.g. they do not have another session with same user id,
+            // broadcast that they joined to all users
+            if participants.insert(
+                &session_and_user_id.user_id,
+                &session_and_user_id.session_id,
+            ) {
+                self.broadcast_participation(
+                    &session_and_user_id.user_id,
+                    event::RoomParticipationStatus::Joined,
+                );
+            }
+        }
+
+        // users joining or leaving from now on are either listed or broadcast to the receiver
+        let user_ids = self.user_registry.get_unique_user_ids();
         let user_session_handle = UserSessionHandle::new(
-            self.metadata.name.clone(),
+            self.name.clone(),
             broadcast_tx,
+            Arc::clone(&self.history),
+            Arc::clone(&self.moderation),
             session_and_user_id.clone(),
         );
 
-        // If the user is new e.g. they do not have another session with same user id,
-        // broadcast that they joined to all users
-        if self.user_registry.insert(&user_session_handle) {
-            let _ = self.broadcast_tx.send(event::Event::RoomParticipation(
-                event::RoomParticipationBroacastEvent {
-                    user_id: session_and_user_id.user_id.clone(),
-                    room: self.metadata.name.clone(),
-                    status: event::RoomParticipationStatus::Joined,
-                },
-            ));
-        }
-
-        (broadcast_rx, user_session_handle)
+        Ok((broadcast_rx, user_session_handle, user_ids, recent_messages))
     }
 
     /// Remove a participant from the room and broadcast that they left
     /// Consume the [UserSessionHandle] to drop it
-    pub fn leave(&mut self, user_session_handle: UserSessionHandle) {
-        if self.user_registry.remove(&user_session_handle) {
-            let _ = self.broadcast_tx.send(event::Event::RoomParticipation(
-                event::RoomParticipationBroacastEvent {
-                    user_id: String::from(user_session_handle.user_id()),
-                    room: self.metadata.name.clone(),
-                    status: event::RoomParticipationStatus::Left,
-                },
-            ));
+    pub fn leave(&self, user_session_handle: UserSessionHandle) {
+        let mut participants = self.user_registry.lock(user_session_handle.user_id());
+
+        if participants.remove(
+            user_session_handle.user_id(),
+            user_session_handle.session_id(),
+        ) {
+            self.broadcast_participation(
+                user_session_handle.user_id(),
+                event::RoomParticipationStatus::Left,
+            );
+        }
+    }
+
+    /// Apply a moderation action to a user on behalf of an operator and broadcast it to the room
+    /// Kicked and banned users are removed from the room, their sessions drop their handles when they receive the event
+    ///
+    /// # Returns
+    ///
+    /// - The moderation event
+    /// - Whether the affected user was in the room, hence has received the event through the broadcast channel
+    pub async fn moderate(
+        &self,
+        by_user_id: &str,
+        user_id: &str,
+        action: event::ModerationAction,
+    ) -> anyhow::Result<(event::ModerationBroadcastEvent, bool)> {
+        let (moderation_event, is_in_room, snapshot) =
+            self.apply_moderation(by_user_id, user_id, action)?;
+
+        // the action is already in effect, a moderation file which can not be written only loses it on restart
+        if let Some(snapshot) = snapshot {
+            if let Err(e) = self.save_moderation(snapshot).await {
+                log::error!(
+                    "Could not save the moderation of room '{}': {:#}",
+                    self.name,
+                    e
+                );
+            }
+        }
+
+        Ok((moderation_event, is_in_room))
+    }
+
+    /// Applies a moderation action under the lock of the affected user, the file is written by the caller
+    fn apply_moderation(
+        &self,
+        by_user_id: &str,
+        user_id: &str,
+        action: event::ModerationAction,
+    ) -> anyhow::Result<(
+        event::ModerationBroadcastEvent,
+        bool,
+        Option<ModerationSnapshot>,
+    )> {
+        let mut participants = self.user_registry.lock(user_id);
+        let is_in_room = participants.contains(user_id);
+        if action == event::ModerationAction::Kicked && !is_in_room {
+            return Err(CommandError::new(
+                ErrorCode::UserNotInRoom,
+                format!("'{}' is not in room '{}'", user_id, self.name),
+            )
+            .into());
+        }
+
+        let snapshot = self.lock_moderation().apply(by_user_id, user_id, &action)?;
+
+        let is_removed = matches!(
+            action,
+            event::ModerationAction::Kicked | event::ModerationAction::Banned
+        );
+        let moderation_event = event::ModerationBroadcastEvent {
+            room: self.name.clone(),
+            user_id: String::from(user_id),
+            by_user_id: String::from(by_user_id),
+            action,
+        };
+
+        let _ = self
+            .broadcast_tx
+            .send(event::Event::Moderation(moderation_event.clone()).into());
+
+        if is_removed && participants.remove_user(user_id) {
+            self.broadcast_participation(user_id, event::RoomParticipationStatus::Left);
         }
+
+        Ok((moderation_event, is_in_room, snapshot))
+    }
+
+    /// Writes a moderation snapshot off the async workers, unless a newer one has already been written
+    async fn save_moderation(&self, snapshot: ModerationSnapshot) -> anyhow::Result<()> {
+        let mut saved_version = self.saved_moderation_version.lock().await;
+        if snapshot.version() <= *saved_version {
+            return Ok(());
+        }
+
+        let version = snapshot.version();
+        tokio::task::spawn_blocking(move || snapshot.save()).await??;
+        *saved_version = version;
+
+        Ok(())
+    }
+
+    /// Broadcast that a user joined or left the room
+    /// Called under the lock of the user, so that the events of a user are sent in the order they happened
+    fn broadcast_participation(&self, user_id: &str, status: event::RoomParticipationStatus) {
+        let _ = self.broadcast_tx.send(
+            event::Event::RoomParticipation(event::RoomParticipationBroacastEvent {
+                user_id: String::from(user_id),
+                room: self.name.clone(),
+                status,
+            })
+            .into(),
+        );
+    }
+
+    fn lock_moderation(&self) -> std::sync::MutexGuard<'_, RoomModeration> {
+        self.moderation
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
     }
 }
@@ -1,6 +1,10 @@
 mod chat_room;
+mod room_history;
+mod room_moderation;
 mod user_registry;
 mod user_session_handle;
 
-pub use self::chat_room::{ChatRoom, ChatRoomMetadata};
+pub use self::chat_room::{ChatRoom, ChatRoomMetadata, RoomJoinResult};
+pub use self::room_history::RoomHistory;
+pub use self::room_moderation::RoomModeration;
 pub use self::user_session_handle::{SessionAndUserId, UserSessionHandle};
@@ -0,0 +1,401 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use comms::event::UserMessageBroadcastEvent;
+use serde::Deserialize;
+
+/// How many of the most recent messages are replayed to joining users
+pub const REPLAYED_MESSAGE_COUNT: usize = 50;
+/// The maximum number of messages served in a single history page, the most recent page is kept in memory
+pub const MAX_HISTORY_PAGE_SIZE: usize = 100;
+
+#[derive(Debug)]
+/// [RoomHistory] is the durable message log of a single room
+///
+/// Every message is appended to a JSON lines file, so history survives server restarts.
+/// The id of a message is its position in the log, and the byte offset of every message is
+/// kept in memory to be able to serve older pages without scanning the whole file.
+/// The most recent page of the log is also kept in memory to replay it without reading the file.
+///
+/// The file is read and written with blocking calls, which are kept off the async worker threads.
+pub struct RoomHistory {
+    /// Shared to sync the appended messages to the disk without holding the history
+    file: Arc<File>,
+    /// Byte offsets of the messages in the log file, indexed by message id
+    offsets: Vec<u64>,
+    /// Length of the log file in bytes, where the next message will be written
+    len: u64,
+    recent_messages: VecDeque<UserMessageBroadcastEvent>,
+}
+
+impl RoomHistory {
+    /// The path of the log file of the given room, within the directory that holds all histories
+    pub fn path(history_dir: &Path, room_name: &str) -> PathBuf {
+        history_dir.join(format!("{}.jsonl", room_name))
+    }
+
+    /// Opens the log file at the given path, creating it if it does not exist
+    /// and loading the most recent messages into memory
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("could not create directory {}", parent.display()))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("could not open room history {}", path.display()))?;
+
+        let mut offsets = Vec::new();
+        let mut recent_messages = VecDeque::with_capacity(MAX_HISTORY_PAGE_SIZE);
+        let mut reader = BufReader::new(&file);
+        let mut offset = 0;
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+
+            // a line which can not be parsed is most likely a partial write from a crash, skip it
+            if let Some(mut message) = parse_line(line.as_bytes()) {
+                message.id = offsets.len() as u64;
+                offsets.push(offset);
+
+                if recent_messages.len() == MAX_HISTORY_PAGE_SIZE {
+                    recent_messages.pop_front();
+                }
+                recent_messages.push_back(message);
+            }
+
+            offset += read as u64;
+        }
+
+        let len = terminate_partial_line(&mut file)?;
+
+        Ok(RoomHistory {
+            file: Arc::new(file),
+            offsets,
+            len,
+            recent_messages,
+        })
+    }
+
+    /// The id the next appended message will get
+    pub fn next_message_id(&self) -> u64 {
+        self.offsets.len() as u64
+    }
+
+    /// Appends a message to the log, the message must carry [RoomHistory::next_message_id]
+    ///
+    /// The message survives a crash once [RoomHistory::sync_handle] has been synced.
+    /// A failed append is rolled back, so that the next message starts right after the last complete one.
+    pub fn append(&mut self, message: &UserMessageBroadcastEvent) -> anyhow::Result<()> {
+        debug_assert_eq!(message.id, self.next_message_id());
+
+        let mut serialized_bytes = serde_json::to_vec(message)?;
+        serialized_bytes.push(b'\n');
+
+        if let Err(err) = (&*self.file).write_all(&serialized_bytes) {
+            // a partial line would shift the offset of every later message
+            if let Err(truncate_err) = self.file.set_len(self.len) {
+                log::error!(
+                    "Could not roll back a failed append to the room history: {}",
+                    truncate_err
+                );
+            }
+
+            return Err(err).context("could not append to the room history");
+        }
+
+        self.offsets.push(self.len);
+        self.len += serialized_bytes.len() as u64;
+
+        if self.recent_messages.len() == MAX_HISTORY_PAGE_SIZE {
+            self.recent_messages.pop_front();
+        }
+        self.recent_messages.push_back(message.clone());
+
+        Ok(())
+    }
+
+    /// The file of the log, to sync the appended messages to the disk once the history is released,
+    /// so that the messages appended meanwhile are synced at once
+    pub fn sync_handle(&self) -> Arc<File> {
+        Arc::clone(&self.file)
+    }
+
+    /// The most recent messages of the room to replay to joining users, from oldest to newest
+    pub fn recent_messages(&self) -> Vec<UserMessageBroadcastEvent> {
+        self.recent_messages
+            .iter()
+            .skip(
+                self.recent_messages
+                    .len()
+                    .saturating_sub(REPLAYED_MESSAGE_COUNT),
+            )
+            .cloned()
+            .collect()
+    }
+
+    /// The most recent page of messages, from oldest to newest, read from memory
+    ///
+    /// # Returns
+    ///
+    /// - The messages of the page, at most [MAX_HISTORY_PAGE_SIZE]
+    /// - Whether there are even older messages
+    pub fn recent_page(&self) -> (Vec<UserMessageBroadcastEvent>, bool) {
+        (
+            self.recent_messages.iter().cloned().collect(),
+            self.recent_messages
+                .front()
+                .is_some_and(|message| message.id > 0),
+        )
+    }
+
+    /// Reads a page of messages older than the given message id, from oldest to newest
+    /// The most recent messages are read if no message id is given
+    ///
+    /// # Returns
+    ///
+    /// - The messages of the page, at most [MAX_HISTORY_PAGE_SIZE]
+    /// - Whether there are even older messages
+    pub fn messages_before(
+        &mut self,
+        before_message_id: Option<u64>,
+        limit: usize,
+    ) -> anyhow::Result<(Vec<UserMessageBroadcastEvent>, bool)> {
+        let end = before_message_id
+            .map(|id| (id as usize).min(self.offsets.len()))
+            .unwrap_or(self.offsets.len());
+        let start = end.saturating_sub(limit.min(MAX_HISTORY_PAGE_SIZE));
+
+        let first_recent_id = self.offsets.len() - self.recent_messages.len();
+        if start >= first_recent_id {
+            let messages = self
+                .recent_messages
+                .range(start - first_recent_id..end - first_recent_id)
+                .cloned()
+                .collect();
+
+            return Ok((messages, start > 0));
+        }
+
+        // the messages of a page are next to each other in the log, they are read at once
+        let from = self.offsets[start];
+        let to = self.offsets.get(end).copied().unwrap_or(self.len);
+        let mut bytes = vec![0; (to - from) as usize];
+        (&*self.file).seek(SeekFrom::Start(from))?;
+        (&*self.file).read_exact(&mut bytes)?;
+
+        // the lines skipped when the log was opened are skipped again, they have no id
+        let messages = bytes
+            .split(|byte| *byte == b'\n')
+            .filter_map(parse_line)
+            .zip(start as u64..)
+            .map(|(mut message, id)| {
+                message.id = id;
+                message
+            })
+            .collect::<Vec<_>>();
+        anyhow::ensure!(
+            messages.len() == end - start,
+            "could not parse a message from the room history"
+        );
+
+        Ok((messages, start > 0))
+    }
+}
+
+/// A message as it is logged, the messages logged before they had ids and timestamps are still read
+#[derive(Deserialize)]
+struct LoggedMessage {
+    #[serde(rename = "r")]
+    room: String,
+    /// The messages logged before they had timestamps are dated at the Unix epoch
+    #[serde(rename = "t", default)]
+    timestamp: DateTime<Utc>,
+    #[serde(rename = "u")]
+    user_id: String,
+    #[serde(rename = "c")]
+    content: String,
+}
+
+/// Parses a line of the log, [None] if it is not a message
+/// The id of a message is its position in the log, it is left for the caller to set
+fn parse_line(line: &[u8]) -> Option<UserMessageBroadcastEvent> {
+    let message = serde_json::from_slice::<LoggedMessage>(line).ok()?;
+
+    Some(UserMessageBroadcastEvent {
+        room: message.room,
+        id: 0,
+        timestamp: message.timestamp,
+        user_id: message.user_id,
+        content: message.content,
+    })
+}
+
+/// Makes sure the next appended message starts on its own line,
+/// even if the previous run of the server crashed in the middle of a write
+///
+/// # Returns
+///
+/// - The length of the file after the partial line is terminated
+fn terminate_partial_line(file: &mut File) -> anyhow::Result<u64> {
+    let len = file.metadata()?.len();
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let mut last_byte = [0u8; 1];
+    file.seek(SeekFrom::Start(len - 1))?;
+    file.read_exact(&mut last_byte)?;
+
+    if last_byte[0] != b'\n' {
+        file.write_all(b"\n")?;
+        return Ok(len + 1);
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append_message(history: &mut RoomHistory, content: &str) {
+        let message = UserMessageBroadcastEvent {
+            room: "room-1".to_string(),
+            id: history.next_message_id(),
+            timestamp: chrono::Utc::now(),
+            user_id: "user-1".to_string(),
+            content: content.to_string(),
+        };
+
+        history.append(&message).unwrap();
+    }
+
+    fn contents(messages: &[UserMessageBroadcastEvent]) -> Vec<String> {
+        messages.iter().map(|m| m.content.clone()).collect()
+    }
+
+    #[test]
+    fn test_history_survives_reopen() {
+        let path = std::env::temp_dir()
+            .join(nanoid::nanoid!())
+            .join("room-1.jsonl");
+
+        let mut history = RoomHistory::open(&path).unwrap();
+        for idx in 0..REPLAYED_MESSAGE_COUNT + 1 {
+            append_message(&mut history, &idx.to_string());
+        }
+        drop(history);
+
+        let mut history = RoomHistory::open(&path).unwrap();
+        let recent_messages = history.recent_messages();
+
+        assert_eq!(history.next_message_id(), REPLAYED_MESSAGE_COUNT as u64 + 1);
+        assert_eq!(recent_messages.len(), REPLAYED_MESSAGE_COUNT);
+        assert_eq!(recent_messages.first().unwrap().content, "1");
+        assert_eq!(
+            recent_messages.last().unwrap().content,
+            REPLAYED_MESSAGE_COUNT.to_string()
+        );
+
+        let (messages, has_more) = history.messages_before(Some(3), 2).unwrap();
+        assert_eq!(contents(&messages), vec!["1", "2"]);
+        assert!(has_more);
+
+        let (messages, has_more) = history.messages_before(Some(2), 10).unwrap();
+        assert_eq!(contents(&messages), vec!["0", "1"]);
+        assert!(!has_more);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_messages_logged_without_ids_and_timestamps_are_read() {
+        let path = std::env::temp_dir()
+            .join(nanoid::nanoid!())
+            .join("room-1.jsonl");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(
+            &path,
+            concat!(
+                r#"{"r":"room-1","u":"user-1","c":"0"}"#,
+                "\n",
+                r#"{"r":"room-1","u":"user-1","c":"1"}"#,
+                "\n"
+            ),
+        )
+        .unwrap();
+
+        let mut history = RoomHistory::open(&path).unwrap();
+        append_message(&mut history, "2");
+
+        let recent_messages = history.recent_messages();
+        assert_eq!(contents(&recent_messages), vec!["0", "1", "2"]);
+        assert_eq!(recent_messages[1].id, 1);
+        assert_eq!(recent_messages[1].timestamp, DateTime::<Utc>::UNIX_EPOCH);
+        assert_eq!(history.next_message_id(), 3);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_older_pages_are_read_from_the_log() {
+        let path = std::env::temp_dir()
+            .join(nanoid::nanoid!())
+            .join("room-1.jsonl");
+
+        let mut history = RoomHistory::open(&path).unwrap();
+        append_message(&mut history, "0");
+        drop(history);
+        // a partial write from a crash is skipped, the messages after it keep their ids
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(br#"{"r":"room-1","u":"#)
+            .unwrap();
+
+        let mut history = RoomHistory::open(&path).unwrap();
+        for idx in 1..MAX_HISTORY_PAGE_SIZE + 3 {
+            append_message(&mut history, &idx.to_string());
+        }
+
+        let (messages, has_more) = history.messages_before(Some(3), 3).unwrap();
+        assert_eq!(contents(&messages), vec!["0", "1", "2"]);
+        assert_eq!(messages[2].id, 2);
+        assert!(!has_more);
+
+        let (messages, has_more) = history.messages_before(None, 2).unwrap();
+        assert_eq!(
+            contents(&messages),
+            vec![
+                (MAX_HISTORY_PAGE_SIZE + 1).to_string(),
+                (MAX_HISTORY_PAGE_SIZE + 2).to_string()
+            ]
+        );
+        assert!(has_more);
+
+        let (messages, has_more) = history.recent_page();
+        assert_eq!(messages.len(), MAX_HISTORY_PAGE_SIZE);
+        assert_eq!(messages.first().unwrap().id, 3);
+        assert!(has_more);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
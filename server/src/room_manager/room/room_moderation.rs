@@ -0,0 +1,325 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use comms::event::{ErrorCode, ModerationAction, RoomRole};
+use serde::{Deserialize, Serialize};
+
+use crate::command_error::CommandError;
+
+/// The part of [RoomModeration] that is persisted, the owner comes from the room metadata
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ModerationRecord {
+    operators: BTreeSet<String>,
+    banned_user_ids: BTreeSet<String>,
+    /// Muted users and the time their mute is lifted
+    muted_until: BTreeMap<String, DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+/// [RoomModeration] keeps track of the roles, bans and mutes of a single room
+///
+/// Every change is written to a JSON file next to the room history, so moderation
+/// survives server restarts. The rooms file may give an owner and operators to the rooms
+/// defined by the server, they are applied again whenever the rooms file is reloaded.
+pub struct RoomModeration {
+    path: PathBuf,
+    owner: Option<String>,
+    /// Operators given by the rooms file, only the rooms file can take their role away
+    configured_operators: BTreeSet<String>,
+    record: ModerationRecord,
+    /// Incremented on every change, so that an older snapshot is never written over a newer one
+    version: u64,
+}
+
+#[derive(Debug)]
+/// [ModerationSnapshot] is the content of the moderation file after a change,
+/// it is written once the locks of the room have been released
+pub struct ModerationSnapshot {
+    path: PathBuf,
+    version: u64,
+    contents: Vec<u8>,
+}
+
+impl RoomModeration {
+    /// The path of the moderation file of the given room, within the directory that holds all histories
+    pub fn path(history_dir: &Path, room_name: &str) -> PathBuf {
+        history_dir.join(format!("{}.moderation.json", room_name))
+    }
+
+    /// Opens the moderation file at the given path, starting with no operators, bans or mutes
+    /// if it does not exist
+    pub fn open(
+        path: &Path,
+        owner: Option<String>,
+        configured_operators: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let record = match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("could not parse room moderation {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ModerationRecord::default(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("could not read room moderation {}", path.display()))
+            }
+        };
+
+        Ok(RoomModeration {
+            path: path.to_path_buf(),
+            owner,
+            configured_operators: configured_operators.into_iter().collect(),
+            record,
+            version: 0,
+        })
+    }
+
+    pub fn set_configured_roles(
+        &mut self,
+        owner: Option<String>,
+        configured_operators: Vec<String>,
+    ) {
+        self.owner = owner;
+        self.configured_operators = configured_operators.into_iter().collect();
+    }
+
+    pub fn role_of(&self, user_id: &str) -> RoomRole {
+        if self.owner.as_deref() == Some(user_id) {
+            RoomRole::Owner
+        } else if self.record.operators.contains(user_id)
+            || self.configured_operators.contains(user_id)
+        {
+            RoomRole::Operator
+        } else {
+            RoomRole::Member
+        }
+    }
+
+    pub fn is_banned(&self, user_id: &str) -> bool {
+        self.record.banned_user_ids.contains(user_id)
+    }
+
+    pub fn is_muted(&self, user_id: &str) -> bool {
+        self.record
+            .muted_until
+            .get(user_id)
+            .is_some_and(|until| *until > Utc::now())
+    }
+
+    /// Checks that the given user is allowed to apply the action to the affected user, then applies it
+    ///
+    /// Operators may only moderate members, the owner may moderate everyone else
+    /// and is the only one who can change roles.
+    ///
+    /// # Returns
+    ///
+    /// The snapshot to write to the moderation file, unless the action changes nothing that is persisted
+    pub fn apply(
+        &mut self,
+        by_user_id: &str,
+        user_id: &str,
+        action: &ModerationAction,
+    ) -> anyhow::Result<Option<ModerationSnapshot>> {
+        let by_role = self.role_of(by_user_id);
+        let role = self.role_of(user_id);
+
+        if by_role < RoomRole::Operator {
+            return Err(CommandError::new(
+                ErrorCode::Forbidden,
+                "only operators can moderate the room",
+            )
+            .into());
+        }
+        if role >= by_role {
+            return Err(CommandError::new(
+                ErrorCode::Forbidden,
+                format!(
+                    "'{}' can not be moderated by a user with the same or a lower role",
+                    user_id
+                ),
+            )
+            .into());
+        }
+
+        match action {
+            ModerationAction::Kicked => return Ok(None),
+            ModerationAction::Banned => {
+                self.record.banned_user_ids.insert(String::from(user_id));
+            }
+            ModerationAction::Unbanned => {
+                self.record.banned_user_ids.remove(user_id);
+            }
+            ModerationAction::Muted { until } => {
+                self.record
+                    .muted_until
+                    .insert(String::from(user_id), *until);
+            }
+            ModerationAction::Unmuted => {
+                self.record.muted_until.remove(user_id);
+            }
+            ModerationAction::RoleChanged { role } => {
+                if by_role != RoomRole::Owner {
+                    return Err(CommandError::new(
+                        ErrorCode::Forbidden,
+                        "only the owner can change roles",
+                    )
+                    .into());
+                }
+
+                if self.configured_operators.contains(user_id) {
+                    return Err(CommandError::new(
+                        ErrorCode::Forbidden,
+                        format!("'{}' is an operator by the rooms file", user_id),
+                    )
+                    .into());
+                }
+
+                match role {
+                    RoomRole::Operator => self.record.operators.insert(String::from(user_id)),
+                    RoomRole::Member => self.record.operators.remove(user_id),
+                    RoomRole::Owner => {
+                        return Err(CommandError::new(
+                            ErrorCode::Forbidden,
+                            "the room can not be handed over",
+                        )
+                        .into())
+                    }
+                };
+            }
+        }
+
+        self.snapshot().map(Some)
+    }
+
+    /// Serializes the moderation file, mutes which have already been lifted are dropped on the way
+    fn snapshot(&mut self) -> anyhow::Result<ModerationSnapshot> {
+        let now = Utc::now();
+        self.record.muted_until.retain(|_, until| *until > now);
+        self.version += 1;
+
+        Ok(ModerationSnapshot {
+            path: self.path.clone(),
+            version: self.version,
+            contents: serde_json::to_vec_pretty(&self.record)?,
+        })
+    }
+}
+
+impl ModerationSnapshot {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Writes the moderation file to a temporary file and moves it over the previous one
+    pub fn save(&self) -> anyhow::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, &self.contents)
+            .with_context(|| format!("could not write room moderation {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.path).with_context(|| {
+            format!("could not replace room moderation {}", self.path.display())
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moderation_roles_and_reopen() {
+        let dir = std::env::temp_dir().join(nanoid::nanoid!());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = RoomModeration::path(&dir, "room-1");
+        let owner = Some("owner".to_string());
+
+        let mut moderation = RoomModeration::open(&path, owner.clone(), Vec::new()).unwrap();
+        let operator = ModerationAction::RoleChanged {
+            role: RoomRole::Operator,
+        };
+
+        // members can not moderate, operators can not change roles or moderate each other
+        assert!(moderation
+            .apply("member", "other", &ModerationAction::Banned)
+            .is_err());
+        moderation.apply("owner", "op-1", &operator).unwrap();
+        moderation.apply("owner", "op-2", &operator).unwrap();
+        // kicks are not persisted, nothing needs to be written
+        assert!(moderation
+            .apply("owner", "member", &ModerationAction::Kicked)
+            .unwrap()
+            .is_none());
+        assert!(moderation.apply("op-1", "member", &operator).is_err());
+        assert!(moderation
+            .apply("op-1", "op-2", &ModerationAction::Banned)
+            .is_err());
+        assert!(moderation
+            .apply("op-1", "owner", &ModerationAction::Kicked)
+            .is_err());
+
+        moderation
+            .apply("op-1", "member", &ModerationAction::Banned)
+            .unwrap();
+        moderation
+            .apply(
+                "op-1",
+                "other",
+                &ModerationAction::Muted {
+                    until: Utc::now() + chrono::Duration::minutes(5),
+                },
+            )
+            .unwrap()
+            .unwrap()
+            .save()
+            .unwrap();
+        drop(moderation);
+
+        let moderation = RoomModeration::open(&path, owner, Vec::new()).unwrap();
+
+        assert_eq!(moderation.role_of("owner"), RoomRole::Owner);
+        assert_eq!(moderation.role_of("op-1"), RoomRole::Operator);
+        assert_eq!(moderation.role_of("member"), RoomRole::Member);
+        assert!(moderation.is_banned("member"));
+        assert!(!moderation.is_banned("other"));
+        assert!(moderation.is_muted("other"));
+        assert!(!moderation.is_muted("member"));
+    }
+
+    #[test]
+    fn test_rooms_file_gives_roles() {
+        let dir = std::env::temp_dir().join(nanoid::nanoid!());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = RoomModeration::path(&dir, "room-1");
+
+        // a room defined by the server can be moderated by the operators of the rooms file
+        let mut moderation = RoomModeration::open(&path, None, vec!["op-1".to_string()]).unwrap();
+        assert_eq!(moderation.role_of("op-1"), RoomRole::Operator);
+        moderation
+            .apply("op-1", "member", &ModerationAction::Banned)
+            .unwrap();
+        assert!(moderation
+            .apply("op-2", "member", &ModerationAction::Unbanned)
+            .is_err());
+
+        // reloading the rooms file changes the roles right away
+        moderation.set_configured_roles(Some("owner".to_string()), vec!["op-2".to_string()]);
+        assert_eq!(moderation.role_of("op-1"), RoomRole::Member);
+        assert_eq!(moderation.role_of("owner"), RoomRole::Owner);
+        moderation
+            .apply("op-2", "member", &ModerationAction::Unbanned)
+            .unwrap();
+        // only the rooms file can take the role of its operators away
+        assert!(moderation
+            .apply(
+                "owner",
+                "op-2",
+                &ModerationAction::RoleChanged {
+                    role: RoomRole::Member
+                }
+            )
+            .is_err());
+    }
+}
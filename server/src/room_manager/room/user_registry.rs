@@ -1,70 +1,99 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard},
+};
 
-use super::user_session_handle::UserSessionHandle;
+/// How many independently locked parts the participants of a room are split into
+const SHARD_COUNT: usize = 16;
 
 #[derive(Debug)]
+/// [UserRegistry] is a smart container for keeping track of which unique list of users are in a room
+///
+/// Since a user can have multiple sessions, we need to keep track of which sessions belong to which users.
+/// Users are spread over shards by their id, so that users joining and leaving at the same time
+/// rarely wait for each other.
 pub struct UserRegistry {
-    user_id_to_sessions: HashMap<String, HashSet<String>>,
-    user_ids: HashSet<String>,
+    shards: Box<[Mutex<Participants>]>,
 }
 
-/// [UserRegistry] is a smart container for keeping track of which unique list of users are in a room
-///
-/// Since a user can have multiple sessions, we need to keep track of which sessions belong to which users
 impl UserRegistry {
     pub fn new() -> Self {
         UserRegistry {
-            user_id_to_sessions: HashMap::new(),
-            user_ids: HashSet::new(),
+            shards: (0..SHARD_COUNT)
+                .map(|_| Mutex::new(Participants::default()))
+                .collect(),
+        }
+    }
+
+    /// Lock the shard holding the given user, every change to the user happens under this lock
+    pub fn lock(&self, user_id: &str) -> MutexGuard<'_, Participants> {
+        let mut hasher = DefaultHasher::new();
+        user_id.hash(&mut hasher);
+
+        self.shards[hasher.finish() as usize % self.shards.len()]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Collects the users of every shard, locking one shard at a time
+    pub fn get_unique_user_ids(&self) -> Vec<String> {
+        let mut user_ids = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            user_ids.extend(shard.user_id_to_sessions.keys().cloned());
         }
+
+        user_ids
     }
+}
 
-    /// Add a user to the room, returns true if the user is a new user
-    pub fn insert(&mut self, user_session_handle: &UserSessionHandle) -> bool {
-        let user_id = String::from(user_session_handle.user_id());
-        let session_id = String::from(user_session_handle.session_id());
+#[derive(Debug, Default)]
+/// [Participants] are the users of a single shard of a [UserRegistry] alongside with their sessions
+pub struct Participants {
+    user_id_to_sessions: HashMap<String, HashSet<String>>,
+}
 
+impl Participants {
+    /// Add a session of a user, returns true if the user is a new user
+    pub fn insert(&mut self, user_id: &str, session_id: &str) -> bool {
         let sessions = self
             .user_id_to_sessions
-            .entry(user_id.clone())
+            .entry(String::from(user_id))
             .or_default();
 
-        sessions.insert(session_id);
-
-        let is_new_user = sessions.len() == 1;
+        sessions.insert(String::from(session_id));
 
-        if is_new_user {
-            self.user_ids.insert(user_id);
-        }
-
-        is_new_user
+        sessions.len() == 1
     }
 
     /// Removes a given session from the participant list, returns true if the user is no longer in the room
     /// Does nothing and returns false if the user does not exist
-    pub fn remove(&mut self, user_session_handle: &UserSessionHandle) -> bool {
-        let user_id = String::from(user_session_handle.user_id());
-        let session_id = String::from(user_session_handle.session_id());
-
-        let to_remove = self.user_id_to_sessions.get_mut(&user_id);
+    pub fn remove(&mut self, user_id: &str, session_id: &str) -> bool {
+        let Some(sessions) = self.user_id_to_sessions.get_mut(user_id) else {
+            return false;
+        };
 
-        if let Some(sessions) = to_remove {
-            sessions.remove(&session_id);
+        sessions.remove(session_id);
 
-            if sessions.is_empty() {
-                self.user_id_to_sessions.remove(&user_id);
-                self.user_ids.remove(&user_id);
+        if sessions.is_empty() {
+            self.user_id_to_sessions.remove(user_id);
 
-                true
-            } else {
-                false
-            }
+            true
         } else {
             false
         }
     }
 
-    pub fn get_unique_user_ids(&self) -> Vec<String> {
-        self.user_ids.iter().cloned().collect()
+    /// Removes every session of the given user from the participant list, returns true if the user was in the room
+    pub fn remove_user(&mut self, user_id: &str) -> bool {
+        self.user_id_to_sessions.remove(user_id).is_some()
+    }
+
+    pub fn contains(&self, user_id: &str) -> bool {
+        self.user_id_to_sessions.contains_key(user_id)
     }
 }
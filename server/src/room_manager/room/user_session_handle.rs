@@ -1,7 +1,11 @@
-use anyhow::Context;
-use comms::event;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use comms::{event, transport::server::SharedEvent};
 use tokio::sync::broadcast;
 
+use super::{RoomHistory, RoomModeration};
+
 #[derive(Debug, Clone)]
 pub struct SessionAndUserId {
     pub session_id: String,
@@ -17,7 +21,11 @@ pub struct UserSessionHandle {
     /// The name of the room which is associated with this handle
     room: String,
     /// The channel to use for sending events to the all users of the room
-    broadcast_tx: broadcast::Sender<event::Event>,
+    broadcast_tx: broadcast::Sender<SharedEvent>,
+    /// The durable message log of the room
+    history: Arc<Mutex<RoomHistory>>,
+    /// The roles, bans and mutes of the room
+    moderation: Arc<Mutex<RoomModeration>>,
     /// The session and user id associated with this handle
     session_and_user_id: SessionAndUserId,
 }
@@ -25,12 +33,16 @@ pub struct UserSessionHandle {
 impl UserSessionHandle {
     pub(super) fn new(
         room: String,
-        broadcast_tx: broadcast::Sender<event::Event>,
+        broadcast_tx: broadcast::Sender<SharedEvent>,
+        history: Arc<Mutex<RoomHistory>>,
+        moderation: Arc<Mutex<RoomModeration>>,
         session_and_user_id: SessionAndUserId,
     ) -> Self {
         UserSessionHandle {
             room,
             broadcast_tx,
+            history,
+            moderation,
             session_and_user_id,
         }
     }
@@ -47,18 +59,104 @@ impl UserSessionHandle {
         &self.session_and_user_id.user_id
     }
 
-    /// Send a message to the room
-    pub fn send_message(&self, content: String) -> anyhow::Result<()> {
-        self.broadcast_tx
-            .send(comms::event::Event::UserMessage(
-                event::UserMessageBroadcastEvent {
-                    room: self.room.clone(),
-                    user_id: self.session_and_user_id.user_id.clone(),
+    /// Whether the user of this handle is currently muted in the room
+    pub fn is_muted(&self) -> bool {
+        self.moderation
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .is_muted(&self.session_and_user_id.user_id)
+    }
+
+    /// Stamp a message with the next id and the current time of the room,
+    /// then record it to the room history and send it to the room
+    ///
+    /// The message is on the disk once this returns, the history is written off the async worker threads.
+    /// Once the message is in the history it is never reported as failed, a failing sync is only logged.
+    ///
+    /// # Returns
+    ///
+    /// - The id of the message
+    pub async fn send_message(&self, content: String) -> anyhow::Result<u64> {
+        let room = self.room.clone();
+        let room_name = self.room.clone();
+        let user_id = self.session_and_user_id.user_id.clone();
+        let broadcast_tx = self.broadcast_tx.clone();
+        let history = Arc::clone(&self.history);
+
+        tokio::task::spawn_blocking(move || {
+            let (message_id, file) = {
+                // the history lock is held while broadcasting, so that users joining the room
+                // see every message either in the replayed history or in the broadcast channel, never both
+                let mut history = history
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+                let message_id = history.next_message_id();
+                let message = event::UserMessageBroadcastEvent {
+                    room,
+                    id: message_id,
+                    timestamp: Utc::now(),
+                    user_id,
                     content,
-                },
-            ))
-            .context("could not write to the broadcast channel")?;
+                };
+
+                history.append(&message)?;
+
+                // nobody may be listening, the message is in the history either way
+                let _ = broadcast_tx.send(event::Event::UserMessage(message).into());
+
+                (message_id, history.sync_handle())
+            };
 
-        Ok(())
+            // synced once the history is released, the messages appended meanwhile by others are synced along
+            // the message is already in the history and may have been received, it is not reported as failed
+            if let Err(e) = file.sync_data() {
+                log::error!(
+                    "Could not sync the history of room '{}' to the disk: {}",
+                    room_name,
+                    e
+                );
+            }
+
+            Ok(message_id)
+        })
+        .await?
+    }
+
+    /// Read a page of messages older than the given message id from the room history
+    pub async fn fetch_history(
+        &self,
+        before_message_id: Option<u64>,
+        limit: usize,
+    ) -> anyhow::Result<event::HistoryPageReplyEvent> {
+        let history = Arc::clone(&self.history);
+        let (messages, has_more) = tokio::task::spawn_blocking(move || {
+            history
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .messages_before(before_message_id, limit)
+        })
+        .await??;
+
+        Ok(event::HistoryPageReplyEvent {
+            room: self.room.clone(),
+            messages,
+            has_more,
+        })
+    }
+
+    /// The most recent page of the room history, it is kept in memory so reading it never blocks
+    pub fn recent_history(&self) -> event::HistoryPageReplyEvent {
+        let (messages, has_more) = self
+            .history
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .recent_page();
+
+        event::HistoryPageReplyEvent {
+            room: self.room.clone(),
+            messages,
+            has_more,
+        }
     }
 }
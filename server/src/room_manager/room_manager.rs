@@ -1,69 +1,587 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
 
-use comms::event::Event;
-use tokio::sync::{broadcast, Mutex};
+use anyhow::Context;
+use comms::event::{ErrorCode, ModerationAction, ModerationBroadcastEvent};
+use tokio::sync::Mutex;
 
-use super::room::{ChatRoom, ChatRoomMetadata, SessionAndUserId, UserSessionHandle};
+use crate::command_error::CommandError;
 
-pub type RoomJoinResult = (broadcast::Receiver<Event>, UserSessionHandle, Vec<String>);
+use super::room::{
+    ChatRoom, ChatRoomMetadata, RoomHistory, RoomJoinResult, RoomModeration, SessionAndUserId,
+    UserSessionHandle,
+};
 
-#[derive(Debug, Clone)]
+const MAX_ROOM_NAME_LENGTH: usize = 32;
+const MAX_ROOM_DESCRIPTION_LENGTH: usize = 256;
+
+#[derive(Debug, Default)]
+struct ChatRooms {
+    by_name: HashMap<String, Arc<ChatRoom>>,
+    /// Metadatas of the rooms, in the order they were created
+    metadatas: Vec<ChatRoomMetadata>,
+}
+
+#[derive(Debug)]
+/// [RoomManager] owns the chat rooms of the server
+///
+/// Rooms can be created and deleted while the server is running,
+/// every change to the set of rooms is persisted to the rooms file.
+///
+/// The set of rooms is only locked for as long as it takes to look a room up or swap it out,
+/// joining, leaving and moderating happen on the room itself and never wait on each other across rooms.
 pub struct RoomManager {
-    chat_rooms: HashMap<String, Arc<Mutex<ChatRoom>>>,
-    chat_room_metadatas: Vec<ChatRoomMetadata>,
+    /// The directory that holds the message history and moderation of every room
+    history_dir: PathBuf,
+    /// The file the set of rooms is persisted to
+    rooms_file_path: PathBuf,
+    chat_rooms: RwLock<ChatRooms>,
+    /// Serializes changes to the set of rooms, including writing them to the rooms file
+    catalogue_lock: Mutex<()>,
 }
 
 impl RoomManager {
-    pub(super) fn new(chat_rooms: Vec<(ChatRoomMetadata, Arc<Mutex<ChatRoom>>)>) -> RoomManager {
-        let chat_room_metadatas = chat_rooms
+    pub(super) fn new(
+        history_dir: PathBuf,
+        rooms_file_path: PathBuf,
+        chat_rooms: Vec<(ChatRoomMetadata, Arc<ChatRoom>)>,
+    ) -> RoomManager {
+        let metadatas = chat_rooms
             .iter()
             .map(|(metadata, _)| metadata.clone())
             .collect();
 
         RoomManager {
-            chat_room_metadatas,
-            chat_rooms: chat_rooms
-                .into_iter()
-                .map(|(metadata, chat_room)| (metadata.name.clone(), chat_room))
-                .collect(),
+            history_dir,
+            rooms_file_path,
+            chat_rooms: RwLock::new(ChatRooms {
+                metadatas,
+                by_name: chat_rooms
+                    .into_iter()
+                    .map(|(metadata, chat_room)| (metadata.name.clone(), chat_room))
+                    .collect(),
+            }),
+            catalogue_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn chat_room_metadatas(&self) -> Vec<ChatRoomMetadata> {
+        self.read_chat_rooms().metadatas.clone()
+    }
+
+    /// Creates a new room and persists the new set of rooms
+    pub async fn create_room(&self, metadata: ChatRoomMetadata) -> anyhow::Result<()> {
+        validate_room_metadata(&metadata)?;
+
+        let _catalogue = self.catalogue_lock.lock().await;
+        if self.read_chat_rooms().by_name.contains_key(&metadata.name) {
+            return Err(CommandError::new(
+                ErrorCode::RoomAlreadyExists,
+                format!("room '{}' already exists", metadata.name),
+            )
+            .into());
+        }
+
+        let chat_room = Arc::new(open_chat_room(metadata.clone(), &self.history_dir).await?);
+
+        let mut metadatas = self.chat_room_metadatas();
+        metadatas.push(metadata.clone());
+
+        // the room can only be joined once it is persisted, same as a deleted room is only gone once persisted
+        persist_chat_room_metadatas(&self.rooms_file_path, &metadatas).await?;
+        {
+            let mut chat_rooms = self.write_chat_rooms();
+            chat_rooms.by_name.insert(metadata.name, chat_room);
+            chat_rooms.metadatas = metadatas;
         }
+
+        Ok(())
     }
 
-    pub fn chat_room_metadatas(&self) -> &Vec<ChatRoomMetadata> {
-        &self.chat_room_metadatas
+    /// Deletes a room alongside with its message history and moderation, then persists the new set of rooms
+    /// Only the owner of a room can delete it, rooms defined by the server can not be deleted
+    ///
+    /// The room is deleted once the new set of rooms is persisted, failing to remove the files of the room
+    /// afterwards is only logged, they are reused if a room with the same name is created again.
+    ///
+    /// Users which have joined the room keep their [UserSessionHandle]s until they drop them,
+    /// but the room can no longer be joined.
+    pub async fn delete_room(&self, room_name: &str, user_id: &str) -> anyhow::Result<()> {
+        let _catalogue = self.catalogue_lock.lock().await;
+        let metadatas = {
+            let chat_rooms = self.read_chat_rooms();
+            let metadata = chat_rooms
+                .metadatas
+                .iter()
+                .find(|metadata| metadata.name == room_name)
+                .ok_or_else(|| room_not_found(room_name))?;
+            if metadata.owner.as_deref() != Some(user_id) {
+                return Err(CommandError::new(
+                    ErrorCode::Forbidden,
+                    format!("only the owner can delete room '{}'", room_name),
+                )
+                .into());
+            }
+
+            chat_rooms
+                .metadatas
+                .iter()
+                .filter(|metadata| metadata.name != room_name)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        // changes to the set of rooms are serialized, nothing else has changed it since it was read
+        persist_chat_room_metadatas(&self.rooms_file_path, &metadatas).await?;
+        {
+            let mut chat_rooms = self.write_chat_rooms();
+            chat_rooms.by_name.remove(room_name);
+            chat_rooms.metadatas = metadatas;
+        }
+
+        // the moderation file only exists once the room has been moderated
+        for path in [
+            RoomHistory::path(&self.history_dir, room_name),
+            RoomModeration::path(&self.history_dir, room_name),
+        ] {
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    log::warn!(
+                        "Could not remove {} of deleted room '{}': {}",
+                        path.display(),
+                        room_name,
+                        e
+                    );
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reconciles the running rooms with the given set of rooms, usually read again from the rooms file
+    /// New rooms are opened, the descriptions and owners of the remaining rooms are updated
+    /// and rooms which are no longer listed are closed. Nothing changes if any of the rooms is invalid.
+    ///
+    /// The message history and moderation of closed rooms are kept, listing them again reopens them as they were.
+    /// Users which have joined a closed room keep their [UserSessionHandle]s until they drop them.
+    ///
+    /// # Returns
+    ///
+    /// - The names of the closed rooms, their participants have to be notified
+    pub async fn reload(&self, metadatas: Vec<ChatRoomMetadata>) -> anyhow::Result<Vec<String>> {
+        let mut names = HashSet::new();
+        for metadata in metadatas.iter() {
+            validate_room_metadata(metadata)?;
+
+            if !names.insert(metadata.name.as_str()) {
+                return Err(anyhow::anyhow!("room '{}' is listed twice", metadata.name));
+            }
+        }
+
+        let _catalogue = self.catalogue_lock.lock().await;
+
+        // open every new room before changing anything, so a failure leaves the running rooms untouched
+        let mut opened_rooms = HashMap::new();
+        for metadata in metadatas.iter() {
+            if !self.read_chat_rooms().by_name.contains_key(&metadata.name) {
+                let chat_room = open_chat_room(metadata.clone(), &self.history_dir).await?;
+
+                opened_rooms.insert(metadata.name.clone(), Arc::new(chat_room));
+            }
+        }
+
+        let mut chat_rooms = self.write_chat_rooms();
+        let closed_room_names: Vec<String> = chat_rooms
+            .metadatas
+            .iter()
+            .filter(|metadata| !names.contains(metadata.name.as_str()))
+            .map(|metadata| metadata.name.clone())
+            .collect();
+        for room_name in closed_room_names.iter() {
+            chat_rooms.by_name.remove(room_name);
+        }
+
+        for metadata in metadatas.iter() {
+            match opened_rooms.remove(&metadata.name) {
+                Some(chat_room) => {
+                    chat_rooms.by_name.insert(metadata.name.clone(), chat_room);
+                }
+                None => {
+                    chat_rooms.by_name[&metadata.name].update_roles(metadata);
+                }
+            }
+        }
+        chat_rooms.metadatas = metadatas;
+
+        Ok(closed_room_names)
+    }
+
+    fn read_chat_rooms(&self) -> RwLockReadGuard<'_, ChatRooms> {
+        self.chat_rooms
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write_chat_rooms(&self) -> RwLockWriteGuard<'_, ChatRooms> {
+        self.chat_rooms
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn get_room(&self, room_name: &str) -> anyhow::Result<Arc<ChatRoom>> {
+        self.read_chat_rooms()
+            .by_name
+            .get(room_name)
+            .cloned()
+            .ok_or_else(|| room_not_found(room_name).into())
     }
 
     /// Joins to a room given a user session
-    pub async fn join_room(
+    pub fn join_room(
         &self,
         room_name: &str,
         session_and_user_id: &SessionAndUserId,
     ) -> anyhow::Result<RoomJoinResult> {
-        let room = self
-            .chat_rooms
-            .get(room_name)
-            .ok_or_else(|| anyhow::anyhow!("room '{}' not found", room_name))?;
+        self.get_room(room_name)?.join(session_and_user_id)
+    }
 
-        let mut room = room.lock().await;
-        let (broadcast_rx, user_session_handle) = room.join(session_and_user_id);
+    /// Apply a moderation action to a user of a room on behalf of an operator
+    ///
+    /// # Returns
+    ///
+    /// - The moderation event
+    /// - Whether the affected user was in the room, hence has received the event through the room
+    pub async fn moderate(
+        &self,
+        room_name: &str,
+        by_user_id: &str,
+        user_id: &str,
+        action: ModerationAction,
+    ) -> anyhow::Result<(ModerationBroadcastEvent, bool)> {
+        self.get_room(room_name)?
+            .moderate(by_user_id, user_id, action)
+            .await
+    }
 
-        Ok((
-            broadcast_rx,
-            user_session_handle,
-            room.get_unique_user_ids().clone(),
-        ))
+    pub fn drop_user_session_handle(&self, handle: UserSessionHandle) {
+        self.drop_user_session_handles(vec![handle]);
     }
 
-    pub async fn drop_user_session_handle(&self, handle: UserSessionHandle) -> anyhow::Result<()> {
-        let room = self
-            .chat_rooms
-            .get(handle.room())
-            .ok_or_else(|| anyhow::anyhow!("room '{}' not found", handle.room()))?;
+    /// Leaves every room of the given handles at once, as a session does when it disconnects
+    /// The rooms are looked up together, so the set of rooms is locked once however many rooms are left
+    pub fn drop_user_session_handles(&self, handles: Vec<UserSessionHandle>) {
+        let rooms = {
+            let chat_rooms = self.read_chat_rooms();
 
-        let mut room = room.lock().await;
+            handles
+                .into_iter()
+                .map(|handle| (chat_rooms.by_name.get(handle.room()).cloned(), handle))
+                .collect::<Vec<_>>()
+        };
 
-        room.leave(handle);
+        for (room, handle) in rooms {
+            // the room may have been deleted while the user was still participating, there is no one to notify
+            if let Some(room) = room {
+                room.leave(handle);
+            }
+        }
+    }
+}
 
-        Ok(())
+fn room_not_found(room_name: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::RoomNotFound,
+        format!("room '{}' not found", room_name),
+    )
+}
+
+/// Room names are used as file names and shown with a '#' prefix, keep them simple slugs
+pub(super) fn validate_room_metadata(metadata: &ChatRoomMetadata) -> anyhow::Result<()> {
+    if metadata.name.is_empty() || metadata.name.len() > MAX_ROOM_NAME_LENGTH {
+        return Err(CommandError::new(
+            ErrorCode::InvalidRoom,
+            format!(
+                "room name must be between 1 and {} characters",
+                MAX_ROOM_NAME_LENGTH
+            ),
+        )
+        .into());
+    }
+
+    if !metadata
+        .name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(CommandError::new(
+            ErrorCode::InvalidRoom,
+            "room name may only contain lowercase letters, digits and '-'",
+        )
+        .into());
+    }
+
+    if metadata.description.len() > MAX_ROOM_DESCRIPTION_LENGTH {
+        return Err(CommandError::new(
+            ErrorCode::InvalidRoom,
+            format!(
+                "room description must be at most {} characters",
+                MAX_ROOM_DESCRIPTION_LENGTH
+            ),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Opens a room off the async workers, its history is scanned and its moderation read from the history directory
+async fn open_chat_room(
+    metadata: ChatRoomMetadata,
+    history_dir: &Path,
+) -> anyhow::Result<ChatRoom> {
+    let history_dir = history_dir.to_path_buf();
+
+    tokio::task::spawn_blocking(move || ChatRoom::open(metadata, &history_dir)).await?
+}
+
+/// Writes the metadatas to a temporary file and moves it over the rooms file,
+/// so a crash while writing never leaves a truncated rooms file behind
+async fn persist_chat_room_metadatas(
+    path: &Path,
+    metadatas: &[ChatRoomMetadata],
+) -> anyhow::Result<()> {
+    let serialized = serde_json::to_vec_pretty(metadatas)?;
+    let tmp_path = path.with_extension("tmp");
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(&tmp_path, serialized)
+        .await
+        .with_context(|| format!("could not write rooms file {}", tmp_path.display()))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("could not replace rooms file {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_manager::RoomManagerBuilder;
+
+    fn metadata(name: &str, description: &str) -> ChatRoomMetadata {
+        ChatRoomMetadata {
+            name: name.to_string(),
+            description: description.to_string(),
+            owner: None,
+            operators: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_builder_refuses_invalid_rooms() {
+        let dir = std::env::temp_dir().join(nanoid::nanoid!());
+
+        // room names end up in file paths, they must not escape the history directory
+        assert!(RoomManagerBuilder::new(&dir, dir.join("rooms.json"))
+            .create_room(metadata("../escape", ""))
+            .is_err());
+        assert!(!dir.parent().unwrap().join("escape.jsonl").exists());
+
+        assert!(RoomManagerBuilder::new(&dir, dir.join("rooms.json"))
+            .create_room(metadata("twice", ""))
+            .unwrap()
+            .create_room(metadata("twice", ""))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_reconciles_rooms() {
+        let dir = std::env::temp_dir().join(nanoid::nanoid!());
+        let room_manager = RoomManagerBuilder::new(&dir, dir.join("rooms.json"))
+            .create_room(metadata("kept", "old"))
+            .unwrap()
+            .create_room(metadata("closed", "closed"))
+            .unwrap()
+            .build();
+
+        // an invalid set of rooms changes nothing
+        assert!(room_manager
+            .reload(vec![metadata("kept", "new"), metadata("kept", "new")])
+            .await
+            .is_err());
+        assert_eq!(room_manager.chat_room_metadatas().len(), 2);
+
+        let closed_room_names = room_manager
+            .reload(vec![metadata("added", "added"), metadata("kept", "new")])
+            .await
+            .unwrap();
+
+        assert_eq!(closed_room_names, vec!["closed".to_string()]);
+        let metadatas = room_manager.chat_room_metadatas();
+        assert_eq!(
+            metadatas
+                .iter()
+                .map(|metadata| (metadata.name.as_str(), metadata.description.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("added", "added"), ("kept", "new")]
+        );
+        assert!(room_manager.get_room("closed").is_err());
+        assert!(room_manager.get_room("added").is_ok());
+    }
+
+    fn room_manager_with_rooms(room_count: usize) -> Arc<RoomManager> {
+        let dir = std::env::temp_dir().join(nanoid::nanoid!());
+        let mut builder = RoomManagerBuilder::new(&dir, dir.join("rooms.json"));
+        for i in 0..room_count {
+            builder = builder
+                .create_room(metadata(&format!("room-{}", i), ""))
+                .unwrap();
+        }
+
+        Arc::new(builder.build())
+    }
+
+    /// Connects the given number of sessions at once, every session joining a few rooms then leaving all of them
+    ///
+    /// # Returns
+    ///
+    /// - How long it took for every session to join its rooms
+    /// - How long it took for every session to leave its rooms
+    async fn connect_storm(
+        room_manager: &Arc<RoomManager>,
+        session_count: usize,
+        rooms_per_session: usize,
+    ) -> (std::time::Duration, std::time::Duration) {
+        let room_names = room_manager
+            .chat_room_metadatas()
+            .into_iter()
+            .map(|metadata| metadata.name)
+            .collect::<Vec<_>>();
+
+        let started_at = std::time::Instant::now();
+        let mut join_set = tokio::task::JoinSet::new();
+        for session in 0..session_count {
+            let room_manager = Arc::clone(room_manager);
+            let rooms = (0..rooms_per_session)
+                .map(|i| room_names[(session + i) % room_names.len()].clone())
+                .collect::<Vec<_>>();
+
+            join_set.spawn(async move {
+                let session_and_user_id = SessionAndUserId {
+                    session_id: format!("session-{}", session),
+                    user_id: format!("user-{}", session),
+                };
+
+                // the users and messages are sent to the client then dropped, the receiver and handle are kept
+                rooms
+                    .iter()
+                    .map(|room| {
+                        let (broadcast_rx, user_session_handle, _, _) =
+                            room_manager.join_room(room, &session_and_user_id).unwrap();
+
+                        (broadcast_rx, user_session_handle)
+                    })
+                    .collect::<Vec<_>>()
+            });
+        }
+        let joined_sessions = join_set.join_all().await;
+        let joined_in = started_at.elapsed();
+
+        let started_at = std::time::Instant::now();
+        let mut join_set = tokio::task::JoinSet::new();
+        for joined_rooms in joined_sessions {
+            let room_manager = Arc::clone(room_manager);
+
+            join_set.spawn(async move {
+                room_manager.drop_user_session_handles(
+                    joined_rooms
+                        .into_iter()
+                        .map(|(_, user_session_handle)| user_session_handle)
+                        .collect(),
+                );
+            });
+        }
+        join_set.join_all().await;
+
+        (joined_in, started_at.elapsed())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_joins_and_leaves() {
+        let room_manager = room_manager_with_rooms(4);
+        let observer = SessionAndUserId {
+            session_id: "observer".to_string(),
+            user_id: "observer".to_string(),
+        };
+        let (mut broadcast_rx, _handle, _, _) =
+            room_manager.join_room("room-0", &observer).unwrap();
+
+        // few enough sessions for every event of the room to fit in the broadcast channel of the observer
+        connect_storm(&room_manager, 40, 2).await;
+
+        // every session has joined then left, the participation events of a user are never reordered
+        let mut joined = HashSet::new();
+        while let Ok(event) = broadcast_rx.try_recv() {
+            let comms::event::Event::RoomParticipation(participation) = event.event() else {
+                continue;
+            };
+            match participation.status {
+                comms::event::RoomParticipationStatus::Joined => {
+                    assert!(joined.insert(participation.user_id.clone()));
+                }
+                comms::event::RoomParticipationStatus::Left => {
+                    assert!(joined.remove(&participation.user_id));
+                }
+            }
+        }
+        assert_eq!(joined, HashSet::from(["observer".to_string()]));
+
+        let (_, _, user_ids, _) = room_manager
+            .join_room(
+                "room-0",
+                &SessionAndUserId {
+                    session_id: "late".to_string(),
+                    user_id: "late".to_string(),
+                },
+            )
+            .unwrap();
+        let mut user_ids = user_ids;
+        user_ids.sort();
+        assert_eq!(user_ids, vec!["late".to_string(), "observer".to_string()]);
+    }
+
+    /// Micro-benchmark of thousands of sessions connecting at once, run it in release mode:
+    /// `cargo test --release -p server bench_connect_storm -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_connect_storm() {
+        const ROOM_COUNT: usize = 24;
+        const ROOMS_PER_SESSION: usize = 5;
+
+        for session_count in [1_000, 2_500, 10_000] {
+            let room_manager = room_manager_with_rooms(ROOM_COUNT);
+            let (joined_in, left_in) =
+                connect_storm(&room_manager, session_count, ROOMS_PER_SESSION).await;
+            let joins = (session_count * ROOMS_PER_SESSION) as f64;
+
+            println!(
+                "{} sessions joining {} of {} rooms: joined in {:.2?} ({:.0} joins/s), left in {:.2?} ({:.0} leaves/s)",
+                session_count,
+                ROOMS_PER_SESSION,
+                ROOM_COUNT,
+                joined_in,
+                joins / joined_in.as_secs_f64(),
+                left_in,
+                joins / left_in.as_secs_f64(),
+            );
+        }
     }
 }
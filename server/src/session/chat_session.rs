@@ -1,132 +1,560 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
 
 use anyhow::Context;
+use chrono::{Duration, Utc};
 use comms::{
     command::UserCommand,
-    event::{self, Event},
+    event::{self, ErrorCode, Event, ModerationAction},
+    transport::server::SharedEvent,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt, StreamMap,
 };
-use tokio::{
-    sync::mpsc,
-    task::{AbortHandle, JoinSet},
+
+use crate::{
+    command_error::CommandError,
+    metrics::Metrics,
+    room_manager::{ChatRoomMetadata, RoomManager, SessionAndUserId, UserSessionHandle},
 };
 
-use crate::room_manager::{RoomManager, SessionAndUserId, UserSessionHandle};
+use super::{
+    rate_limiter::{RateLimited, SessionRateLimiter},
+    replay_buffer::ReplayBuffer,
+    session_registry::{direct_event_channel, DirectEventReceiver},
+    SessionLimits, SessionRegistry,
+};
+
+/// Longer mutes are capped, banning is the tool for keeping a user out for good
+const MAX_MUTE_DURATION_SECS: u64 = 7 * 24 * 60 * 60;
 
 pub(super) struct ChatSession {
     session_and_user_id: SessionAndUserId,
     room_manager: Arc<RoomManager>,
-    joined_rooms: HashMap<String, (UserSessionHandle, AbortHandle)>,
-    join_set: JoinSet<()>,
-    mpsc_tx: mpsc::Sender<Event>,
-    mpsc_rx: mpsc::Receiver<Event>,
+    session_registry: SessionRegistry,
+    joined_rooms: HashMap<String, UserSessionHandle>,
+    /// The broadcast channels of the joined rooms, polled by the session itself instead of a task per room
+    room_events: StreamMap<String, BroadcastStream<SharedEvent>>,
+    /// The id of the last message delivered from each joined room, [None] until the room has any message
+    last_message_ids: HashMap<String, Option<u64>>,
+    /// Replies to the commands of the session, they are received before anything else
+    replies: VecDeque<SharedEvent>,
+    /// Direct messages and notices sent by other sessions, which do not come from a room
+    direct_events: DirectEventReceiver,
+    /// The longest content of a room or direct message in bytes
+    max_message_length: usize,
+    rate_limiter: SessionRateLimiter,
+    metrics: Metrics,
+    /// Whether the session has ever fallen behind a room, it is counted only once
+    has_lagged: bool,
+    /// The most recent events sent to the client, to send them again if the session is resumed
+    sent_events: ReplayBuffer,
 }
 
 impl ChatSession {
-    pub fn new(session_id: &str, user_id: &str, room_manager: Arc<RoomManager>) -> Self {
-        let (mpsc_tx, mpsc_rx) = mpsc::channel(100);
+    pub fn new(
+        session_id: &str,
+        user_id: &str,
+        room_manager: Arc<RoomManager>,
+        session_registry: SessionRegistry,
+        limits: &SessionLimits,
+        metrics: Metrics,
+        sent_events: ReplayBuffer,
+    ) -> Self {
+        let (direct_event_tx, direct_events) = direct_event_channel();
         let session_and_user_id = SessionAndUserId {
             session_id: String::from(session_id),
             user_id: String::from(user_id),
         };
 
+        // direct messages to the user are received alongside with the room events
+        session_registry.register(&session_and_user_id, direct_event_tx);
+
         ChatSession {
             session_and_user_id,
             room_manager,
+            session_registry,
             joined_rooms: HashMap::new(),
-            join_set: JoinSet::new(),
-            mpsc_tx,
-            mpsc_rx,
+            room_events: StreamMap::new(),
+            last_message_ids: HashMap::new(),
+            replies: VecDeque::new(),
+            direct_events,
+            max_message_length: limits.max_message_length,
+            rate_limiter: limits.rate_limiter.session(user_id),
+            metrics,
+            has_lagged: false,
+            sent_events,
+        }
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_and_user_id.session_id
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.session_and_user_id.user_id
+    }
+
+    /// The rooms the session is in, ordered by their name
+    pub fn joined_rooms(&self) -> Vec<String> {
+        let mut rooms = self.joined_rooms.keys().cloned().collect::<Vec<_>>();
+        rooms.sort();
+
+        rooms
+    }
+
+    /// Keeps an event which is being sent to the client, see [ReplayBuffer]
+    pub fn record_sent(&mut self, event: SharedEvent) {
+        self.sent_events.push(event);
+    }
+
+    /// The events sent after the last one the client has seen, see [ReplayBuffer::replay_after]
+    pub fn replay_after(&self, last_seen_seq: u64) -> (u64, Vec<SharedEvent>) {
+        self.sent_events.replay_after(last_seen_seq)
+    }
+
+    /// Checks whether the command may be handled right away, only messages are rate limited
+    pub fn check_rate_limit(&mut self, cmd: &UserCommand) -> Result<(), RateLimited> {
+        match cmd {
+            UserCommand::SendMessage(_) | UserCommand::SendDirectMessage(_) => {
+                self.rate_limiter.check()
+            }
+            _ => Ok(()),
         }
     }
 
-    /// Handle a user command related to room management such as; join, leave, send message
-    pub async fn handle_user_command(&mut self, cmd: UserCommand) -> anyhow::Result<()> {
+    /// Handle a user command related to room management such as; join, leave, send message, fetch history,
+    /// create and delete rooms, moderation and direct messages to other users
+    ///
+    /// Failures the user can recover from are returned as [CommandError]s
+    ///
+    /// # Returns
+    ///
+    /// - The id of the sent message, for commands sending a message to a room
+    pub async fn handle_user_command(&mut self, cmd: UserCommand) -> anyhow::Result<Option<u64>> {
         match cmd {
             UserCommand::JoinRoom(cmd) => {
                 if self.joined_rooms.contains_key(&cmd.room) {
-                    return Err(anyhow::anyhow!("already joined room '{}'", &cmd.room));
+                    return Err(CommandError::new(
+                        ErrorCode::AlreadyJoined,
+                        format!("already joined room '{}'", &cmd.room),
+                    )
+                    .into());
                 }
 
-                let (mut broadcast_rx, user_session_handle, user_ids) = self
+                let (broadcast_rx, user_session_handle, user_ids, messages) = self
                     .room_manager
-                    .join_room(&cmd.room, &self.session_and_user_id)
-                    .await?;
+                    .join_room(&cmd.room, &self.session_and_user_id)?;
 
-                // spawn a task to forward broadcasted messages to the users' mpsc channel
-                // hence the user can receive messages from different rooms via single channel
-                let abort_handle = self.join_set.spawn({
-                    let mpsc_tx = self.mpsc_tx.clone();
-
-                    // start with sending the user joined room event as a reply to the user
-                    mpsc_tx
-                        .send(Event::UserJoinedRoom(event::UserJoinedRoomReplyEvent {
-                            room: cmd.room.clone(),
-                            users: user_ids,
-                        }))
-                        .await?;
-
-                    async move {
-                        while let Ok(event) = broadcast_rx.recv().await {
-                            let _ = mpsc_tx.send(event).await;
-                        }
-                    }
-                });
+                self.last_message_ids
+                    .insert(cmd.room.clone(), messages.last().map(|message| message.id));
+
+                // the reply is queued before the room is polled, so it is received before any event of the room
+                self.replies.push_back(
+                    Event::UserJoinedRoom(event::UserJoinedRoomReplyEvent {
+                        room: cmd.room.clone(),
+                        users: user_ids,
+                        messages,
+                    })
+                    .into(),
+                );
 
-                // store references to the user session handle and abort handle
-                // this is used to send messages to the room and to cancel the task when user leaves the room
+                // store the user session handle to send messages to the room, and the receiver to receive from it
+                // alongside with the events of the other rooms
+                self.room_events
+                    .insert(cmd.room.clone(), BroadcastStream::new(broadcast_rx));
                 self.joined_rooms
-                    .insert(cmd.room.clone(), (user_session_handle, abort_handle));
+                    .insert(cmd.room.clone(), user_session_handle);
             }
             UserCommand::SendMessage(cmd) => {
-                if let Some((user_session_handle, _)) = self.joined_rooms.get(&cmd.room) {
-                    let _ = user_session_handle.send_message(cmd.content);
+                self.check_message_length(&cmd.content)?;
+                let user_session_handle = self.get_joined_room(&cmd.room)?;
+                if user_session_handle.is_muted() {
+                    return Err(CommandError::new(
+                        ErrorCode::Muted,
+                        format!("you are muted in room '{}'", &cmd.room),
+                    )
+                    .into());
                 }
+
+                let message_id = user_session_handle
+                    .send_message(cmd.content)
+                    .await
+                    .map_err(|e| {
+                        CommandError::new(
+                            ErrorCode::Internal,
+                            format!("could not send message: {:#}", e),
+                        )
+                    })?;
+
+                return Ok(Some(message_id));
+            }
+            UserCommand::SendDirectMessage(cmd) => {
+                self.check_message_length(&cmd.content)?;
+                let event = Event::DirectMessage(event::DirectMessageEvent {
+                    from_user_id: self.session_and_user_id.user_id.clone(),
+                    to_user_id: cmd.to_user_id.clone(),
+                    timestamp: Utc::now(),
+                    content: cmd.content,
+                });
+
+                if !self.session_registry.send_to_user(&cmd.to_user_id, &event) {
+                    return Err(CommandError::new(
+                        ErrorCode::UserOffline,
+                        format!("'{}' is not online", &cmd.to_user_id),
+                    )
+                    .into());
+                }
+                // echo the message to the sender's sessions, so every open conversation stays in sync
+                if cmd.to_user_id != self.session_and_user_id.user_id {
+                    self.session_registry
+                        .send_to_user(&self.session_and_user_id.user_id, &event);
+                }
+            }
+            UserCommand::FetchHistory(cmd) => {
+                let user_session_handle = self.get_joined_room(&cmd.room)?;
+                let history_page = user_session_handle
+                    .fetch_history(cmd.before_message_id, cmd.limit)
+                    .await?;
+
+                self.replies
+                    .push_back(Event::HistoryPage(history_page).into());
+            }
+            UserCommand::CreateRoom(cmd) => {
+                self.room_manager
+                    .create_room(ChatRoomMetadata {
+                        name: cmd.room.clone(),
+                        description: cmd.description.clone(),
+                        owner: Some(self.session_and_user_id.user_id.clone()),
+                        operators: Vec::new(),
+                    })
+                    .await?;
+
+                self.session_registry.broadcast(&Event::RoomCreated(
+                    event::RoomCreatedBroadcastEvent {
+                        room: cmd.room,
+                        description: cmd.description,
+                    },
+                ));
+            }
+            UserCommand::DeleteRoom(cmd) => {
+                self.room_manager
+                    .delete_room(&cmd.room, &self.session_and_user_id.user_id)
+                    .await?;
+
+                // every session, including this one, drops its handle of the room when it receives the event
+                self.session_registry.broadcast(&Event::RoomDeleted(
+                    event::RoomDeletedBroadcastEvent { room: cmd.room },
+                ));
+            }
+            UserCommand::Kick(cmd) => {
+                self.moderate(&cmd.room, &cmd.user_id, ModerationAction::Kicked)
+                    .await?;
+            }
+            UserCommand::Ban(cmd) => {
+                self.moderate(&cmd.room, &cmd.user_id, ModerationAction::Banned)
+                    .await?;
+            }
+            UserCommand::Unban(cmd) => {
+                self.moderate(&cmd.room, &cmd.user_id, ModerationAction::Unbanned)
+                    .await?;
+            }
+            UserCommand::Mute(cmd) => {
+                let action = match cmd.duration_secs.min(MAX_MUTE_DURATION_SECS) {
+                    0 => ModerationAction::Unmuted,
+                    duration_secs => ModerationAction::Muted {
+                        until: Utc::now() + Duration::seconds(duration_secs as i64),
+                    },
+                };
+
+                self.moderate(&cmd.room, &cmd.user_id, action).await?;
+            }
+            UserCommand::SetRole(cmd) => {
+                self.moderate(
+                    &cmd.room,
+                    &cmd.user_id,
+                    ModerationAction::RoleChanged { role: cmd.role },
+                )
+                .await?;
             }
             UserCommand::LeaveRoom(cmd) => {
                 // remove the room from joined rooms and drop user session handle for the room
-                if let Some(urp) = self.joined_rooms.remove(&cmd.room) {
-                    self.cleanup_room(urp).await?;
+                self.get_joined_room(&cmd.room)?;
+                if let Some(user_session_handle) = self.joined_rooms.remove(&cmd.room) {
+                    self.cleanup_room(user_session_handle);
                 }
             }
             _ => {}
         }
 
-        Ok(())
+        Ok(None)
     }
 
-    // TODO: optimize the performance of this function. leaving one by one may not be a good idea.
-    /// Leave all the rooms the user is currently participating in
-    pub async fn leave_all_rooms(&mut self) -> anyhow::Result<()> {
-        // drain the joined rooms to a variable, necessary to avoid borrowing self
-        let drained = self.joined_rooms.drain().collect::<Vec<_>>();
+    fn get_joined_room(&self, room: &str) -> anyhow::Result<&UserSessionHandle> {
+        self.joined_rooms.get(room).ok_or_else(|| {
+            CommandError::new(
+                ErrorCode::NotJoined,
+                format!("you have not joined room '{}'", room),
+            )
+            .into()
+        })
+    }
 
-        for (_, urp) in drained {
-            self.cleanup_room(urp).await?;
+    fn check_message_length(&self, content: &str) -> anyhow::Result<()> {
+        if content.len() > self.max_message_length {
+            return Err(CommandError::new(
+                ErrorCode::MessageTooLong,
+                format!(
+                    "messages can not be longer than {} bytes",
+                    self.max_message_length
+                ),
+            )
+            .into());
         }
 
         Ok(())
     }
 
-    /// Cleanup the room by removing the user from the room and
-    /// aborting the task that forwards broadcasted messages to the user
-    async fn cleanup_room(
-        &mut self,
-        (user_session_handle, abort_handle): (UserSessionHandle, AbortHandle),
+    /// Moderate a user of a room on behalf of the user of this session,
+    /// the affected user is notified directly if they are not in the room
+    async fn moderate(
+        &self,
+        room: &str,
+        user_id: &str,
+        action: ModerationAction,
     ) -> anyhow::Result<()> {
-        self.room_manager
-            .drop_user_session_handle(user_session_handle)
+        let (moderation_event, is_in_room) = self
+            .room_manager
+            .moderate(room, &self.session_and_user_id.user_id, user_id, action)
             .await?;
 
-        abort_handle.abort();
+        if !is_in_room {
+            self.session_registry
+                .send_to_user(user_id, &Event::Moderation(moderation_event));
+        }
 
         Ok(())
     }
 
+    /// Leave all the rooms the user is currently participating in, in a single call to the room manager
+    pub fn leave_all_rooms(&mut self) {
+        self.room_events.clear();
+        self.last_message_ids.clear();
+        self.room_manager.drop_user_session_handles(
+            self.joined_rooms
+                .drain()
+                .map(|(_, user_session_handle)| user_session_handle)
+                .collect(),
+        );
+    }
+
+    /// Cleanup the room by removing the user from the room and
+    /// dropping the receiver of the room's broadcast channel
+    fn cleanup_room(&mut self, user_session_handle: UserSessionHandle) {
+        self.room_events.remove(user_session_handle.room());
+        self.last_message_ids.remove(user_session_handle.room());
+        self.room_manager
+            .drop_user_session_handle(user_session_handle);
+    }
+
     /// Recieve an event that may have originated from any of the rooms the user is actively participating in
-    pub async fn recv(&mut self) -> anyhow::Result<Event> {
-        self.mpsc_rx
-            .recv()
-            .await
-            .context("could not recv from the broadcast channel")
+    ///
+    /// # Cancel Safety
+    ///
+    /// This method is cancel-safe, nothing is awaited once an event has been received.
+    pub async fn recv(&mut self) -> anyhow::Result<SharedEvent> {
+        loop {
+            // replies come first, a room is only polled once the reply to joining it has been received
+            if let Some(reply) = self.replies.pop_front() {
+                return Ok(reply);
+            }
+
+            let missed_events = self.direct_events.take_missed_events();
+            if missed_events > 0 {
+                // the dropped events may have been about rooms being created or deleted
+                self.resync_rooms();
+                return Ok(self.missed_direct_events(missed_events).into());
+            }
+
+            let event = tokio::select! {
+                biased;
+                event = self.direct_events.recv() => event.context("could not recv from the session channel")?,
+                Some((room, event)) = self.room_events.next() => match event {
+                    Ok(event) => event,
+                    // the session has not kept up with the room and the oldest events are gone,
+                    // the missed messages are filled in from the history
+                    Err(BroadcastStreamRecvError::Lagged(missed_events)) => {
+                        Event::RoomLagged(event::RoomLaggedReplyEvent {
+                            room,
+                            missed_events,
+                            messages: Vec::new(),
+                            has_more: false,
+                        })
+                        .into()
+                    }
+                },
+            };
+
+            if let Some(event) = self.track_delivery(event) {
+                self.handle_removal(event.event());
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Brings the rooms of the session in line with the rooms of the server, once events about them may have been
+    /// dropped, the rooms which no longer exist are left and the current list of rooms is sent to the user
+    fn resync_rooms(&mut self) {
+        let metadatas = self.room_manager.chat_room_metadatas();
+        let closed_rooms = self
+            .joined_rooms
+            .keys()
+            .filter(|room| !metadatas.iter().any(|metadata| metadata.name == **room))
+            .cloned()
+            .collect::<Vec<_>>();
+        // same as receiving the deletion of the room, it is no longer in the room manager
+        for room in closed_rooms {
+            self.joined_rooms.remove(&room);
+            self.room_events.remove(&room);
+            self.last_message_ids.remove(&room);
+        }
+
+        self.replies.push_back(
+            Event::RoomList(event::RoomListBroadcastEvent {
+                rooms: metadatas
+                    .into_iter()
+                    .map(|metadata| event::RoomDetail {
+                        name: metadata.name,
+                        description: metadata.description,
+                    })
+                    .collect(),
+            })
+            .into(),
+        );
+    }
+
+    /// Lets the user know that events sent directly to the session have been dropped while it was falling behind
+    fn missed_direct_events(&self, missed_events: u64) -> Event {
+        log::warn!(
+            "Session {} of user {} fell behind and missed {} direct events",
+            self.session_and_user_id.session_id,
+            self.session_and_user_id.user_id,
+            missed_events
+        );
+        self.metrics.record_missed_events(missed_events);
+
+        Event::Error(event::ErrorReplyEvent {
+            code: ErrorCode::MissedEvents,
+            message: format!(
+                "fell behind and missed {} events sent directly to you, such as direct messages",
+                missed_events
+            ),
+            command: None,
+        })
+    }
+
+    /// Keeps track of the messages delivered from every room, so that the messages missed by falling behind a room
+    /// are replayed and the ones replayed are not delivered twice
+    ///
+    /// # Returns
+    ///
+    /// - The event to deliver, [None] if it should be dropped
+    fn track_delivery(&mut self, event: SharedEvent) -> Option<SharedEvent> {
+        match event.event() {
+            Event::UserMessage(message) => {
+                if let Some(last_message_id) = self.last_message_ids.get_mut(&message.room) {
+                    if last_message_id.is_some_and(|id| message.id <= id) {
+                        return None;
+                    }
+                    *last_message_id = Some(message.id);
+                }
+
+                Some(event)
+            }
+            Event::RoomLagged(lagged) => self
+                .replay_missed_messages(lagged.clone())
+                .map(|lagged| Event::RoomLagged(lagged).into()),
+            _ => Some(event),
+        }
+    }
+
+    /// Fills in the messages the session has missed from the room history, the most recent page of it at most
+    ///
+    /// # Returns
+    ///
+    /// - The event to let the user know about the missed events, [None] if the room has been left meanwhile
+    fn replay_missed_messages(
+        &mut self,
+        mut lagged: event::RoomLaggedReplyEvent,
+    ) -> Option<event::RoomLaggedReplyEvent> {
+        let user_session_handle = self.joined_rooms.get(&lagged.room)?;
+        let last_message_id = self.last_message_ids.get_mut(&lagged.room)?;
+
+        log::warn!(
+            "Session {} of user {} fell behind room '{}' and missed {} events",
+            self.session_and_user_id.session_id,
+            self.session_and_user_id.user_id,
+            lagged.room,
+            lagged.missed_events
+        );
+        if !std::mem::replace(&mut self.has_lagged, true) {
+            self.metrics.record_lagged_session();
+        }
+        self.metrics.record_missed_events(lagged.missed_events);
+
+        // the most recent page is kept in memory, so the events of the other rooms are not held up by the disk
+        let page = user_session_handle.recent_history();
+        // the page falls short if it does not reach back to the last delivered message
+        lagged.has_more = match *last_message_id {
+            Some(last_id) => page
+                .messages
+                .first()
+                .is_some_and(|oldest| oldest.id > last_id + 1),
+            None => page.has_more,
+        };
+        lagged.messages = page
+            .messages
+            .into_iter()
+            .filter(|message| last_message_id.is_none_or(|last_id| message.id > last_id))
+            .collect();
+
+        if let Some(newest) = lagged.messages.last() {
+            *last_message_id = Some(newest.id);
+        }
+
+        Some(lagged)
+    }
+
+    /// Stops receiving the events of a room the user has been removed from
+    fn handle_removal(&mut self, event: &Event) {
+        // the room no longer exists in the room manager, or the user has already been removed from it
+        // by an operator, only the receiver of the room needs to be dropped
+        let removed_from_room = match event {
+            Event::RoomDeleted(event) => Some(&event.room),
+            Event::Moderation(event)
+                if event.user_id == self.session_and_user_id.user_id
+                    && matches!(
+                        event.action,
+                        ModerationAction::Kicked | ModerationAction::Banned
+                    ) =>
+            {
+                Some(&event.room)
+            }
+            _ => None,
+        };
+        if let Some(room) = removed_from_room {
+            self.joined_rooms.remove(room);
+            self.room_events.remove(room);
+            self.last_message_ids.remove(room);
+        }
+    }
+}
+
+impl Drop for ChatSession {
+    fn drop(&mut self) {
+        self.session_registry.unregister(&self.session_and_user_id);
     }
 }
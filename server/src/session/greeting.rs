@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+
+use comms::{
+    command::{Capability, HelloCommand, UserCommand, PROTOCOL_VERSION},
+    event::{ErrorCode, Event, HelloReplyEvent},
+};
+
+use crate::command_error::CommandError;
+
+/// The protocol versions this server speaks
+const SUPPORTED_PROTOCOL_VERSIONS: [u32; 1] = [PROTOCOL_VERSION];
+
+#[derive(Debug, Clone)]
+/// [Capabilities] are the optional features switched on for a single session
+pub(super) struct Capabilities(BTreeSet<Capability>);
+
+impl Capabilities {
+    /// Every capability the server supports, which is what clients that never say hello get
+    pub fn all() -> Self {
+        Capabilities(BTreeSet::from(Capability::ALL))
+    }
+
+    /// Fails with a [CommandError] if the command belongs to a capability which is not switched on
+    pub fn check_command(&self, cmd: &UserCommand) -> anyhow::Result<()> {
+        match cmd.required_capability() {
+            Some(capability) if !self.0.contains(&capability) => Err(CommandError::new(
+                ErrorCode::CapabilityNotEnabled,
+                format!(
+                    "the {:?} capability is not enabled for this connection",
+                    capability
+                ),
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the event can be sent to the client, events of capabilities which are not switched on are dropped
+    pub fn allows_event(&self, event: &Event) -> bool {
+        event
+            .required_capability()
+            .is_none_or(|capability| self.0.contains(&capability))
+    }
+}
+
+/// Answers the hello of a client
+///
+/// # Returns
+///
+/// - The reply to send to the client alongside with the capabilities of the session,
+///   [None] if the server does not speak the protocol version of the client
+pub(super) fn greet(hello: &HelloCommand) -> (HelloReplyEvent, Option<Capabilities>) {
+    let supported_versions = SUPPORTED_PROTOCOL_VERSIONS.to_vec();
+    if !supported_versions.contains(&hello.protocol_version) {
+        return (
+            HelloReplyEvent {
+                supported_versions,
+                capabilities: Vec::new(),
+            },
+            None,
+        );
+    }
+
+    let Capabilities(supported) = Capabilities::all();
+    let capabilities: BTreeSet<Capability> = hello
+        .capabilities
+        .iter()
+        .filter(|capability| supported.contains(capability))
+        .copied()
+        .collect();
+
+    (
+        HelloReplyEvent {
+            supported_versions,
+            capabilities: capabilities.iter().copied().collect(),
+        },
+        Some(Capabilities(capabilities)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use comms::command::FetchHistoryCommand;
+
+    use super::*;
+
+    #[test]
+    fn test_greet() {
+        let (reply, capabilities) = greet(&HelloCommand {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: "tui".to_string(),
+            capabilities: vec![
+                Capability::RoomList,
+                Capability::Unknown,
+                Capability::History,
+            ],
+        });
+
+        assert_eq!(reply.supported_versions, vec![PROTOCOL_VERSION]);
+        assert_eq!(
+            reply.capabilities,
+            vec![Capability::History, Capability::RoomList]
+        );
+
+        let capabilities = capabilities.unwrap();
+        assert!(capabilities
+            .check_command(&UserCommand::FetchHistory(FetchHistoryCommand {
+                room: "room-1".to_string(),
+                before_message_id: None,
+                limit: 10,
+            }))
+            .is_ok());
+        assert!(!capabilities.allows_event(&Event::Moderation(
+            comms::event::ModerationBroadcastEvent {
+                room: "room-1".to_string(),
+                user_id: "user-1".to_string(),
+                by_user_id: "user-2".to_string(),
+                action: comms::event::ModerationAction::Kicked,
+            }
+        )));
+    }
+
+    #[test]
+    fn test_greet_unsupported_version() {
+        let (reply, capabilities) = greet(&HelloCommand {
+            protocol_version: PROTOCOL_VERSION + 1,
+            client_name: "tui".to_string(),
+            capabilities: vec![Capability::History],
+        });
+
+        assert_eq!(reply.supported_versions, vec![PROTOCOL_VERSION]);
+        assert!(reply.capabilities.is_empty());
+        assert!(capabilities.is_none());
+    }
+}
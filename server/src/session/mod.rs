@@ -1,88 +1,1363 @@
 use std::sync::Arc;
 
+use anyhow::Context;
 use comms::{
-    command::UserCommand,
-    event::{self, RoomDetail},
-    transport,
+    command::{UserCommand, UserCommandEnvelope},
+    event::{self, ErrorCode, RoomDetail},
+    transport::{
+        server::{CommandStream, EventWriter, SharedEvent},
+        FrameTooLongError,
+    },
 };
 use nanoid::nanoid;
-use tokio::{net::TcpStream, sync::broadcast};
+use tokio::sync::{broadcast, oneshot};
 use tokio_stream::StreamExt;
 
-use crate::room_manager::RoomManager;
+use crate::{
+    account_store::{AccountStore, AuthOutcome},
+    command_error::CommandError,
+    metrics::Metrics,
+    room_manager::RoomManager,
+};
 
-use self::chat_session::ChatSession;
+#[cfg(test)]
+pub(crate) use self::session_registry::direct_event_channel;
+use self::{
+    chat_session::ChatSession,
+    greeting::{greet, Capabilities},
+    rate_limiter::Penalty,
+    replay_buffer::ReplayBuffer,
+    resumable_sessions::{hand_over, DetachedSession, Takeover},
+};
+pub use self::{
+    rate_limiter::RateLimiter, resumable_sessions::ResumableSessions,
+    session_registry::SessionRegistry,
+};
 
 mod chat_session;
+mod greeting;
+mod rate_limiter;
+mod replay_buffer;
+mod resumable_sessions;
+mod session_registry;
 
-/// Given a tcp stream and a room manager, handles the user session
-/// until the user quits the session, or the tcp stream is closed for some reason, or the server shuts down
+/// [SessionLimits] bound what a single user session may send
+#[derive(Debug, Clone)]
+pub struct SessionLimits {
+    /// The longest content of a room or direct message in bytes
+    pub max_message_length: usize,
+    pub rate_limiter: RateLimiter,
+}
+
+/// Given the split user connection and a room manager, handles the user session
+/// until the user quits the session, or the connection is closed for some reason, or the server shuts down
+///
+/// The session does not depend on the transport, any stream split by [comms::transport::server] can be served.
+/// A session whose connection is lost can be resumed over another connection, see [ResumableSessions].
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_user_session(
     room_manager: Arc<RoomManager>,
+    account_store: AccountStore,
+    session_registry: SessionRegistry,
+    resumable_sessions: ResumableSessions,
+    limits: SessionLimits,
+    metrics: Metrics,
     mut quit_rx: broadcast::Receiver<()>,
-    stream: TcpStream,
+    (mut commands, mut event_writer): (CommandStream, EventWriter),
 ) -> anyhow::Result<()> {
-    let session_id = nanoid!();
-    // Generate a random id for the user, since we don't have a login system
-    let user_id = String::from(&nanoid!()[0..5]);
-    // Split the tcp stream into a command stream and an event writer with better ergonomics
-    let (mut commands, mut event_writer) = transport::server::split_tcp_stream(stream);
+    let mut capabilities = Capabilities::all();
 
-    // Welcoming the user with a login successful event and necessary information about the server
-    event_writer
-        .write(&event::Event::LoginSuccessful(
-            event::LoginSuccessfulReplyEvent {
-                session_id: session_id.clone(),
-                user_id: user_id.clone(),
-                rooms: room_manager
-                    .chat_room_metadatas()
-                    .iter()
-                    .map(|metadata| RoomDetail {
-                        name: metadata.name.clone(),
-                        description: metadata.description.clone(),
-                    })
-                    .collect(),
-            },
-        ))
-        .await?;
+    // The user has to login, register or resume a session before they can interact with the rooms
+    let authenticated = tokio::select! {
+        result = authenticate(&account_store, &resumable_sessions, &mut capabilities, &mut commands, &mut event_writer) => match result? {
+            Some(authenticated) => authenticated,
+            // The user left before authenticating, there is nothing to cleanup
+            None => return Ok(()),
+        },
+        Ok(_) = quit_rx.recv() => {
+            drop(event_writer);
+            log::debug!("Gracefully shutting down user tcp stream.");
+            return Ok(());
+        }
+    };
+    let rooms = room_manager
+        .chat_room_metadatas()
+        .iter()
+        .map(|metadata| RoomDetail {
+            name: metadata.name.clone(),
+            description: metadata.description.clone(),
+        })
+        .collect();
+
+    let mut chat_session = match authenticated {
+        Authenticated::User(user_id) => {
+            let session_id = nanoid!();
 
-    // Create a chat session with the given room manager
-    // Chat Session will abstract the user session handling logic for multiple rooms
-    let mut chat_session = ChatSession::new(&session_id, &user_id, room_manager);
+            // Welcoming the user with a login successful event and necessary information about the server
+            event_writer
+                .write(&event::Event::LoginSuccessful(
+                    event::LoginSuccessfulReplyEvent {
+                        session_id: session_id.clone(),
+                        user_id: user_id.clone(),
+                        rooms,
+                    },
+                ))
+                .await?;
 
+            // Create a chat session with the given room manager
+            // Chat Session will abstract the user session handling logic for multiple rooms
+            ChatSession::new(
+                &session_id,
+                &user_id,
+                room_manager,
+                session_registry,
+                &limits,
+                metrics,
+                ReplayBuffer::new(resumable_sessions.replay_capacity()),
+            )
+        }
+        Authenticated::Resumed {
+            session,
+            last_seen_seq,
+        } => {
+            let DetachedSession { chat_session, .. } = *session;
+            log::debug!(
+                "Session {} of user {} resumed after event {}",
+                chat_session.session_id(),
+                chat_session.user_id(),
+                last_seen_seq
+            );
+
+            // the session is already in its rooms, only the events the client has missed are sent
+            if let Err(e) = resume(&mut event_writer, &chat_session, rooms, last_seen_seq).await {
+                resumable_sessions.detach(DetachedSession {
+                    chat_session,
+                    capabilities,
+                });
+                return Err(e);
+            }
+
+            chat_session
+        }
+    };
+    let mut takeover = resumable_sessions.register(chat_session.session_id());
+
+    let disconnect = serve(
+        &mut chat_session,
+        &capabilities,
+        &mut takeover,
+        &mut quit_rx,
+        (&mut commands, &mut event_writer),
+    )
+    .await;
+
+    // the session outlives a lost connection, in case the user comes back in time
+    let disconnect = match disconnect {
+        Err(e) if e.is::<ConnectionLost>() => {
+            log::debug!(
+                "Could not write to user {}: {:#}",
+                chat_session.user_id(),
+                e
+            );
+            Ok(Disconnect::Lost)
+        }
+        disconnect => disconnect,
+    };
+    match disconnect {
+        Ok(Disconnect::Closed) => chat_session.leave_all_rooms(),
+        // Since the server is shutting down, we don't need to notify other users about the user's departure
+        Ok(Disconnect::Shutdown) => {}
+        Ok(Disconnect::Lost) => {
+            drop(takeover);
+            resumable_sessions.detach(DetachedSession {
+                chat_session,
+                capabilities,
+            });
+        }
+        Ok(Disconnect::TakenOver(handover_tx)) => {
+            log::debug!(
+                "Session {} of user {} has been resumed over another connection",
+                chat_session.session_id(),
+                chat_session.user_id()
+            );
+            hand_over(
+                DetachedSession {
+                    chat_session,
+                    capabilities,
+                },
+                handover_tx,
+            );
+        }
+        Err(e) => {
+            chat_session.leave_all_rooms();
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// How the connection of a logged in session has come to an end
+enum Disconnect {
+    /// The user has quit or has been disconnected by the server, the session ends with its connection
+    Closed,
+    /// The connection has been lost, the session can be resumed for a while
+    Lost,
+    /// The server is shutting down
+    Shutdown,
+    /// Another connection is resuming the session, which is handed over through the channel
+    TakenOver(oneshot::Sender<DetachedSession>),
+}
+
+/// An event could not be written to the client, the connection is considered lost
+#[derive(Debug, Clone, Copy)]
+struct ConnectionLost;
+
+impl std::fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the connection to the client has been lost")
+    }
+}
+
+/// Serves the commands of a logged in session and sends it its events, until its connection comes to an end
+///
+/// Fails with [ConnectionLost] if an event could not be written to the client
+async fn serve(
+    chat_session: &mut ChatSession,
+    capabilities: &Capabilities,
+    takeover: &mut Takeover,
+    quit_rx: &mut broadcast::Receiver<()>,
+    (commands, event_writer): (&mut CommandStream, &mut EventWriter),
+) -> anyhow::Result<Disconnect> {
     loop {
         tokio::select! {
             cmd = commands.next() => match cmd {
-                // If the user closes the tcp stream, or sends a quit cmd
-                // We need to cleanup resources in a way that the other users are notified about the user's departure
-                None | Some(Ok(UserCommand::Quit(_))) => {
-                    chat_session.leave_all_rooms().await?;
-                    break;
-                }
+                // If the user closes the tcp stream, the session waits to be resumed
+                // before the other users are notified about the user's departure
+                None => return Ok(Disconnect::Lost),
+                // If the user sends a quit cmd, the other users are notified right away
+                Some(Ok(UserCommandEnvelope {
+                    command: UserCommand::Quit(_),
+                    ..
+                })) => return Ok(Disconnect::Closed),
                 // Handle a valid user command
-                Some(Ok(cmd)) => match cmd {
+                Some(Ok(UserCommandEnvelope { command: cmd, request_id })) => match cmd {
                     // For user session related commands, we need to handle them in the chat session
-                    UserCommand::JoinRoom(_) | UserCommand::SendMessage(_) | UserCommand::LeaveRoom(_) => {
-                        chat_session.handle_user_command(cmd).await?;
+                    UserCommand::JoinRoom(_)
+                    | UserCommand::SendMessage(_)
+                    | UserCommand::SendDirectMessage(_)
+                    | UserCommand::LeaveRoom(_)
+                    | UserCommand::FetchHistory(_)
+                    | UserCommand::CreateRoom(_)
+                    | UserCommand::DeleteRoom(_)
+                    | UserCommand::Kick(_)
+                    | UserCommand::Ban(_)
+                    | UserCommand::Unban(_)
+                    | UserCommand::Mute(_)
+                    | UserCommand::SetRole(_) => {
+                        // flooding is answered with its own event, repeat offenders are disconnected
+                        if let Err(rate_limited) = chat_session.check_rate_limit(&cmd) {
+                            let sent = send(
+                                event_writer,
+                                chat_session,
+                                event::Event::RateLimited(rate_limited.into_reply(request_id)).into(),
+                            )
+                            .await;
+
+                            if rate_limited.penalty == Penalty::Disconnected {
+                                log::debug!("Disconnecting user {} for flooding", chat_session.user_id());
+                                return Ok(Disconnect::Closed);
+                            }
+                            sent?;
+                            continue;
+                        }
+
+                        let result = match capabilities.check_command(&cmd) {
+                            Ok(()) => chat_session.handle_user_command(cmd.clone()).await,
+                            Err(e) => Err(e),
+                        };
+
+                        // failures are reported back, only a lost connection ends the session
+                        let reply = match (result, request_id) {
+                            (Ok(message_id), Some(request_id)) => Some(event::Event::Ack(event::AckReplyEvent {
+                                request_id,
+                                message_id,
+                            })),
+                            (Ok(_), None) => None,
+                            (Err(e), _) if e.is::<ConnectionLost>() => return Err(e),
+                            (Err(e), request_id) => {
+                                let e = match e.downcast::<CommandError>() {
+                                    Ok(e) => e,
+                                    Err(e) => {
+                                        log::error!(
+                                            "Could not handle a command of user {}: {:#}",
+                                            chat_session.user_id(),
+                                            e
+                                        );
+                                        CommandError::new(
+                                            ErrorCode::Internal,
+                                            "the server could not process the command",
+                                        )
+                                    }
+                                };
+
+                                Some(match request_id {
+                                    Some(request_id) => event::Event::Nack(event::NackReplyEvent {
+                                        request_id,
+                                        code: e.code,
+                                        message: e.message,
+                                    }),
+                                    None => event::Event::Error(event::ErrorReplyEvent {
+                                        code: e.code,
+                                        message: e.message,
+                                        command: Some(cmd),
+                                    }),
+                                })
+                            }
+                        };
+
+                        if let Some(reply) = reply {
+                            send(event_writer, chat_session, reply.into()).await?;
+                        }
+                    }
+                    // the codec and the capabilities are negotiated before the user logs in
+                    UserCommand::SelectCodec(_) | UserCommand::Hello(_) => {
+                        send(event_writer, chat_session, misplaced_handshake(cmd).into()).await?;
                     }
                     _ => {}
                 }
-                _ => {}
+                // the client has sent more than the server is willing to buffer, it does not get another chance
+                Some(Err(e)) if e.is::<FrameTooLongError>() => {
+                    let _ = send(event_writer, chat_session, frame_too_long(&e).into()).await;
+                    return Ok(Disconnect::Closed);
+                }
+                // the tcp stream can not be read anymore, the same as if it was closed
+                Some(Err(e)) if e.downcast_ref::<std::io::Error>().is_some() => {
+                    return Ok(Disconnect::Lost);
+                }
+                // the line could not be parsed as a command, the next one may be fine
+                Some(Err(e)) => {
+                    send(
+                        event_writer,
+                        chat_session,
+                        event::Event::Error(event::ErrorReplyEvent {
+                            code: ErrorCode::InvalidCommand,
+                            message: format!("{:#}", e),
+                            command: None,
+                        })
+                        .into(),
+                    )
+                    .await?;
+                }
             },
             // Aggregated events from the chat session are sent to the user
             Ok(event) = chat_session.recv() => {
-                event_writer.write(&event).await?;
+                if capabilities.allows_event(event.event()) {
+                    send(event_writer, chat_session, event).await?;
+                }
+            }
+            // The user has come back over another connection, e.g. while this one is half open
+            handover_tx = takeover.requested() => {
+                return Ok(Disconnect::TakenOver(handover_tx));
             }
             // If the server is shutting down, we can just close the tcp streams
-            // and exit the session handler. Since the server is shutting down,
-            // we don't need to notify other users about the user's departure or cleanup resources
+            // and exit the session handler without cleaning up resources
             Ok(_) = quit_rx.recv() => {
-                drop(event_writer);
-                println!("Gracefully shutting down user tcp stream.");
-                break;
+                log::debug!("Gracefully shutting down user tcp stream.");
+                return Ok(Disconnect::Shutdown);
             }
         }
     }
+}
+
+/// Writes an event to the client, keeping it to replay it if the session is resumed
+async fn send(
+    event_writer: &mut EventWriter,
+    chat_session: &mut ChatSession,
+    event: SharedEvent,
+) -> anyhow::Result<()> {
+    chat_session.record_sent(event.clone());
+
+    event_writer
+        .write_shared(&event)
+        .await
+        .context(ConnectionLost)
+}
+
+/// Welcomes the user back to their session, then sends the events sent after the last one the client has seen again
+async fn resume(
+    event_writer: &mut EventWriter,
+    chat_session: &ChatSession,
+    rooms: Vec<RoomDetail>,
+    last_seen_seq: u64,
+) -> anyhow::Result<()> {
+    let (seq, events) = chat_session.replay_after(last_seen_seq);
+
+    event_writer
+        .write(&event::Event::Resumed(event::ResumedReplyEvent {
+            session_id: String::from(chat_session.session_id()),
+            user_id: String::from(chat_session.user_id()),
+            rooms,
+            joined_rooms: chat_session.joined_rooms(),
+            seq,
+        }))
+        .await?;
+    for event in events.iter() {
+        event_writer.write_shared(event).await?;
+    }
 
     Ok(())
 }
+
+/// How the user of a connection has been authenticated
+enum Authenticated {
+    /// The user has logged in or registered, a new session starts
+    User(String),
+    /// The user has resumed an existing session, which has to catch up with the events after the last seen one
+    Resumed {
+        session: Box<DetachedSession>,
+        last_seen_seq: u64,
+    },
+}
+
+/// Reads commands until the user successfully logs in, registers or resumes a session.
+/// Any other command is ignored, failed attempts and malformed commands are reported back to the user.
+/// The first command may be a hello, which switches on the capabilities asked for by the client.
+///
+/// # Returns
+///
+/// - How the user has been authenticated, or [None] if the user quit or closed the tcp stream before authenticating,
+///   or the server does not speak the protocol version of the client
+async fn authenticate(
+    account_store: &AccountStore,
+    resumable_sessions: &ResumableSessions,
+    capabilities: &mut Capabilities,
+    commands: &mut CommandStream,
+    event_writer: &mut EventWriter,
+) -> anyhow::Result<Option<Authenticated>> {
+    let mut is_first_command = true;
+
+    while let Some(cmd) = commands.next().await {
+        let is_first = std::mem::take(&mut is_first_command);
+        let cmd = match cmd {
+            Err(e) if e.is::<FrameTooLongError>() => {
+                event_writer.write(&frame_too_long(&e)).await?;
+                return Ok(None);
+            }
+            // the tcp stream can not be read anymore, the same as if it was closed
+            Err(e) if e.downcast_ref::<std::io::Error>().is_some() => return Ok(None),
+            // the line could not be parsed as a command, the next one may be fine
+            Err(e) => {
+                event_writer
+                    .write(&event::Event::Error(event::ErrorReplyEvent {
+                        code: ErrorCode::InvalidCommand,
+                        message: format!("{:#}", e),
+                        command: None,
+                    }))
+                    .await?;
+                continue;
+            }
+            Ok(cmd) => cmd,
+        };
+        let outcome = match cmd.command {
+            UserCommand::Hello(hello) if is_first => {
+                let (reply, accepted) = greet(&hello);
+                let supported_versions = reply.supported_versions.clone();
+                event_writer.write(&event::Event::Hello(reply)).await?;
+
+                let Some(accepted) = accepted else {
+                    log::debug!(
+                        "Refusing client '{}' speaking protocol version {}",
+                        hello.client_name,
+                        hello.protocol_version
+                    );
+                    let outdated = match supported_versions.iter().min() {
+                        Some(version) if *version > hello.protocol_version => "client",
+                        _ => "server",
+                    };
+                    event_writer
+                        .write(&event::Event::Error(event::ErrorReplyEvent {
+                            code: ErrorCode::UnsupportedProtocolVersion,
+                            message: format!(
+                                "protocol version {} is not supported, the server speaks {:?}, please upgrade the {}",
+                                hello.protocol_version, supported_versions, outdated
+                            ),
+                            command: None,
+                        }))
+                        .await?;
+                    return Ok(None);
+                };
+                log::debug!(
+                    "Client '{}' speaks protocol version {}",
+                    hello.client_name,
+                    hello.protocol_version
+                );
+                *capabilities = accepted;
+                continue;
+            }
+            UserCommand::Login(cmd) => account_store.login(&cmd.username, &cmd.password).await,
+            UserCommand::Register(cmd) => {
+                account_store.register(&cmd.username, &cmd.password).await
+            }
+            UserCommand::Resume(cmd) => match resumable_sessions.take(&cmd.session_id).await {
+                Some(session) => {
+                    return Ok(Some(Authenticated::Resumed {
+                        session: Box::new(session),
+                        last_seen_seq: cmd.last_seen_seq,
+                    }))
+                }
+                None => Ok(AuthOutcome::Rejected(String::from(
+                    "the session has ended and can not be resumed, please log in again",
+                ))),
+            },
+            UserCommand::Quit(_) => return Ok(None),
+            cmd @ (UserCommand::SelectCodec(_) | UserCommand::Hello(_)) => {
+                event_writer.write(&misplaced_handshake(cmd)).await?;
+                continue;
+            }
+            _ => continue,
+        };
+
+        match outcome {
+            Ok(AuthOutcome::Authenticated(user_id)) => {
+                return Ok(Some(Authenticated::User(user_id)))
+            }
+            Ok(AuthOutcome::Rejected(reason)) => {
+                event_writer
+                    .write(&event::Event::LoginFailed(event::LoginFailedReplyEvent {
+                        reason,
+                    }))
+                    .await?;
+            }
+            // the account store may work again for the next attempt
+            Err(e) => {
+                log::error!("Could not authenticate a user: {:#}", e);
+                event_writer
+                    .write(&event::Event::Error(event::ErrorReplyEvent {
+                        code: ErrorCode::Internal,
+                        message: String::from("the server could not process the command"),
+                        command: None,
+                    }))
+                    .await?;
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// The reply to a handshake command which comes too late, a codec selection which is not the first command
+/// of a byte stream or is sent over a WebSocket, or a hello which is not the first command of the session
+fn misplaced_handshake(cmd: UserCommand) -> event::Event {
+    let message = match cmd {
+        UserCommand::SelectCodec(_) => {
+            "the codec can only be selected with the first command of a connection"
+        }
+        _ => "hello is only valid as the first command of a session",
+    };
+
+    event::Event::Error(event::ErrorReplyEvent {
+        code: ErrorCode::InvalidCommand,
+        message: message.to_string(),
+        command: Some(cmd),
+    })
+}
+
+/// The last event sent to a client which has sent an oversized frame, right before the connection is closed
+fn frame_too_long(e: &anyhow::Error) -> event::Event {
+    event::Event::Error(event::ErrorReplyEvent {
+        code: ErrorCode::FrameTooLong,
+        message: format!("{:#}", e),
+        command: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use comms::{
+        command::{self, UserCommandEnvelope},
+        transport,
+    };
+
+    use crate::{
+        config::RateLimitConfig,
+        room_manager::{ChatRoomMetadata, RoomManagerBuilder, SessionAndUserId},
+    };
+
+    use super::*;
+
+    /// Reads events until one matches the given predicate, failing if the session ends before
+    async fn next_matching_event(
+        events: &mut transport::client::EventStream,
+        predicate: impl Fn(&event::Event) -> bool,
+    ) -> event::Event {
+        while let Some(event) = events.next().await {
+            let event = event.unwrap();
+            if predicate(&event) {
+                return event;
+            }
+        }
+
+        panic!("the session ended before the expected event");
+    }
+
+    /// Creates a room manager with a single room in a fresh directory
+    fn room_manager() -> Arc<RoomManager> {
+        let dir = std::env::temp_dir().join(nanoid!());
+
+        Arc::new(
+            RoomManagerBuilder::new(dir.join("history"), dir.join("rooms.json"))
+                .create_room(ChatRoomMetadata {
+                    name: "room-1".to_string(),
+                    description: "some description".to_string(),
+                    owner: None,
+                    operators: Vec::new(),
+                })
+                .unwrap()
+                .build(),
+        )
+    }
+
+    /// Spawns a session with a single room over an in-memory pipe, returning the client side of it
+    async fn spawn_session() -> (
+        transport::client::EventStream,
+        transport::client::CommandWriter,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        spawn_session_in(room_manager(), Metrics::new()).await
+    }
+
+    /// Spawns a session over an in-memory pipe sharing the given room manager, returning the client side of it
+    async fn spawn_session_in(
+        room_manager: Arc<RoomManager>,
+        metrics: Metrics,
+    ) -> (
+        transport::client::EventStream,
+        transport::client::CommandWriter,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        spawn_resumable_session_in(
+            room_manager,
+            SessionRegistry::new(),
+            metrics,
+            ResumableSessions::new(std::time::Duration::from_secs(60), 16),
+        )
+        .await
+    }
+
+    /// Spawns a session over an in-memory pipe which can resume the given sessions and reach the sessions
+    /// of the given registry, returning the client side of it
+    async fn spawn_resumable_session_in(
+        room_manager: Arc<RoomManager>,
+        session_registry: SessionRegistry,
+        metrics: Metrics,
+        resumable_sessions: ResumableSessions,
+    ) -> (
+        transport::client::EventStream,
+        transport::client::CommandWriter,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        let (client_io, session) =
+            spawn_raw_session_in(room_manager, session_registry, metrics, resumable_sessions).await;
+        let (events, commands) = transport::client::split_stream(client_io);
+
+        (events, commands, session)
+    }
+
+    /// Spawns a session over an in-memory pipe, returning the raw client side of it
+    async fn spawn_raw_session_in(
+        room_manager: Arc<RoomManager>,
+        session_registry: SessionRegistry,
+        metrics: Metrics,
+        resumable_sessions: ResumableSessions,
+    ) -> (
+        tokio::io::DuplexStream,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        let dir = std::env::temp_dir().join(nanoid!());
+        let account_store = AccountStore::open(dir.join("accounts.json")).await.unwrap();
+        let (_quit_tx, quit_rx) = broadcast::channel(1);
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let session = tokio::spawn(handle_user_session(
+            room_manager,
+            account_store,
+            session_registry,
+            resumable_sessions,
+            SessionLimits {
+                max_message_length: 64,
+                rate_limiter: RateLimiter::new(RateLimitConfig::default()),
+            },
+            metrics,
+            quit_rx,
+            transport::server::split_stream(server_io),
+        ));
+
+        (client_io, session)
+    }
+
+    #[tokio::test]
+    async fn test_session_over_in_memory_transport() {
+        let (mut events, mut commands, session) = spawn_session().await;
+
+        commands
+            .write(
+                &UserCommand::Register(command::RegisterCommand {
+                    username: "user-1".to_string(),
+                    password: "password-1".to_string(),
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        let event::Event::LoginSuccessful(login) = events.next().await.unwrap().unwrap() else {
+            panic!("expected the login successful event");
+        };
+        assert_eq!(login.user_id, "user-1");
+        assert_eq!(login.rooms.len(), 1);
+
+        commands
+            .write(
+                &UserCommand::JoinRoom(command::JoinRoomCommand {
+                    room: "room-1".to_string(),
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        next_matching_event(&mut events, |event| {
+            matches!(event, event::Event::UserJoinedRoom(_))
+        })
+        .await;
+
+        commands
+            .write(&UserCommandEnvelope {
+                command: UserCommand::SendMessage(command::SendMessageCommand {
+                    room: "room-1".to_string(),
+                    content: "content-1".to_string(),
+                }),
+                request_id: Some(1),
+            })
+            .await
+            .unwrap();
+        let event::Event::Ack(ack) =
+            next_matching_event(&mut events, |event| matches!(event, event::Event::Ack(_))).await
+        else {
+            unreachable!();
+        };
+        assert_eq!(ack.request_id, 1);
+        assert!(ack.message_id.is_some());
+
+        commands
+            .write(&UserCommand::Quit(command::QuitCommand).into())
+            .await
+            .unwrap();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_refuses_oversized_messages_and_frames() {
+        let (mut events, mut commands, session) = spawn_session().await;
+
+        commands
+            .write(
+                &UserCommand::Register(command::RegisterCommand {
+                    username: "user-1".to_string(),
+                    password: "password-1".to_string(),
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        commands
+            .write(
+                &UserCommand::JoinRoom(command::JoinRoomCommand {
+                    room: "room-1".to_string(),
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+
+        // a message longer than the limit of the session is refused, the session stays alive
+        commands
+            .write(&UserCommandEnvelope {
+                command: UserCommand::SendMessage(command::SendMessageCommand {
+                    room: "room-1".to_string(),
+                    content: "a".repeat(65),
+                }),
+                request_id: Some(1),
+            })
+            .await
+            .unwrap();
+        let event::Event::Nack(nack) =
+            next_matching_event(&mut events, |event| matches!(event, event::Event::Nack(_))).await
+        else {
+            unreachable!();
+        };
+        assert_eq!(nack.code, ErrorCode::MessageTooLong);
+
+        // a frame longer than the transport accepts ends the session
+        let oversized = UserCommand::SendMessage(command::SendMessageCommand {
+            room: "room-1".to_string(),
+            content: "a".repeat(transport::server::DEFAULT_MAX_FRAME_LENGTH),
+        })
+        .into();
+        let (_, error) = tokio::join!(
+            commands.write(&oversized),
+            next_matching_event(&mut events, |event| matches!(event, event::Event::Error(_)))
+        );
+        let event::Event::Error(error) = error else {
+            unreachable!();
+        };
+        assert_eq!(error.code, ErrorCode::FrameTooLong);
+
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_malformed_commands_before_login_are_reported() {
+        use tokio::io::AsyncWriteExt;
+
+        let (client_io, session) = spawn_raw_session_in(
+            room_manager(),
+            SessionRegistry::new(),
+            Metrics::new(),
+            ResumableSessions::new(std::time::Duration::from_secs(60), 16),
+        )
+        .await;
+        let (client_reader, mut client_writer) = tokio::io::split(client_io);
+        // the events are read through the client transport, the commands are written by hand
+        let (mut events, _) =
+            transport::client::split_stream(tokio::io::join(client_reader, tokio::io::sink()));
+
+        client_writer.write_all(b"not a command\n").await.unwrap();
+        let event::Event::Error(error) = events.next().await.unwrap().unwrap() else {
+            panic!("expected the malformed command to be reported");
+        };
+        assert_eq!(error.code, ErrorCode::InvalidCommand);
+
+        // the session keeps reading, the user can still log in
+        let register = serde_json::to_string(&UserCommandEnvelope::from(UserCommand::Register(
+            command::RegisterCommand {
+                username: "user-1".to_string(),
+                password: "password-1".to_string(),
+            },
+        )))
+        .unwrap();
+        client_writer
+            .write_all(format!("{}\n", register).as_bytes())
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.next().await.unwrap().unwrap(),
+            event::Event::LoginSuccessful(_)
+        ));
+
+        let quit = serde_json::to_string(&UserCommandEnvelope::from(UserCommand::Quit(
+            command::QuitCommand,
+        )))
+        .unwrap();
+        client_writer
+            .write_all(format!("{}\n", quit).as_bytes())
+            .await
+            .unwrap();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_survives_internal_errors() {
+        // the rooms file can not be written, its directory is a file
+        let dir = std::env::temp_dir().join(nanoid!());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("not-a-dir"), "").unwrap();
+        let room_manager = Arc::new(
+            RoomManagerBuilder::new(
+                dir.join("history"),
+                dir.join("not-a-dir").join("rooms.json"),
+            )
+            .build(),
+        );
+        let (mut events, mut commands, session) =
+            spawn_session_in(room_manager, Metrics::new()).await;
+
+        commands
+            .write(
+                &UserCommand::Register(command::RegisterCommand {
+                    username: "user-1".to_string(),
+                    password: "password-1".to_string(),
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.next().await.unwrap().unwrap(),
+            event::Event::LoginSuccessful(_)
+        ));
+
+        for request_id in [1, 2] {
+            commands
+                .write(&UserCommandEnvelope {
+                    command: UserCommand::CreateRoom(command::CreateRoomCommand {
+                        room: "new-room".to_string(),
+                        description: "new room".to_string(),
+                    }),
+                    request_id: Some(request_id),
+                })
+                .await
+                .unwrap();
+            let event::Event::Nack(nack) = events.next().await.unwrap().unwrap() else {
+                panic!("expected the command to be refused");
+            };
+            assert_eq!(nack.request_id, request_id);
+            assert_eq!(nack.code, ErrorCode::Internal);
+        }
+
+        commands
+            .write(&UserCommand::Quit(command::QuitCommand).into())
+            .await
+            .unwrap();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_refuses_unsupported_protocol_version() {
+        let (mut events, mut commands, session) = spawn_session().await;
+
+        commands
+            .write(
+                &UserCommand::Hello(command::HelloCommand {
+                    protocol_version: command::PROTOCOL_VERSION + 1,
+                    client_name: "client-1".to_string(),
+                    capabilities: vec![command::Capability::History],
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        let event::Event::Hello(hello) = events.next().await.unwrap().unwrap() else {
+            panic!("expected the hello event");
+        };
+        assert_eq!(hello.supported_versions, vec![command::PROTOCOL_VERSION]);
+        let event::Event::Error(error) = events.next().await.unwrap().unwrap() else {
+            panic!("expected the error event");
+        };
+        assert_eq!(error.code, ErrorCode::UnsupportedProtocolVersion);
+
+        session.await.unwrap().unwrap();
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_catches_up_after_falling_behind_a_room() {
+        let room_manager = room_manager();
+        let metrics = Metrics::new();
+        let (mut events, mut commands, session) =
+            spawn_session_in(Arc::clone(&room_manager), metrics.clone()).await;
+
+        commands
+            .write(
+                &UserCommand::Register(command::RegisterCommand {
+                    username: "user-1".to_string(),
+                    password: "password-1".to_string(),
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        commands
+            .write(
+                &UserCommand::JoinRoom(command::JoinRoomCommand {
+                    room: "room-1".to_string(),
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        next_matching_event(&mut events, |event| {
+            matches!(event, event::Event::UserJoinedRoom(_))
+        })
+        .await;
+
+        // the session can not keep up with a burst much larger than the broadcast channel
+        let (_broadcast_rx, handle, _, _) = room_manager
+            .join_room(
+                "room-1",
+                &SessionAndUserId {
+                    session_id: "session-2".to_string(),
+                    user_id: "user-2".to_string(),
+                },
+            )
+            .unwrap();
+        for i in 0..300 {
+            handle.send_message(format!("message-{}", i)).await.unwrap();
+        }
+
+        let event::Event::RoomLagged(lagged) = next_matching_event(&mut events, |event| {
+            matches!(event, event::Event::RoomLagged(_))
+        })
+        .await
+        else {
+            unreachable!();
+        };
+        assert_eq!(lagged.room, "room-1");
+        assert!(lagged.missed_events > 0);
+        assert!(lagged.has_more);
+        assert_eq!(
+            lagged.messages.iter().map(|m| m.id).collect::<Vec<_>>(),
+            (200..300).collect::<Vec<_>>()
+        );
+
+        // the replayed messages still waiting in the broadcast channel are not delivered twice
+        handle
+            .send_message("message-300".to_string())
+            .await
+            .unwrap();
+        let event::Event::UserMessage(message) = next_matching_event(&mut events, |event| {
+            matches!(event, event::Event::UserMessage(_))
+        })
+        .await
+        else {
+            unreachable!();
+        };
+        assert_eq!(message.id, 300);
+        assert_eq!(metrics.lagged_sessions(), 1);
+        assert_eq!(metrics.missed_events(), lagged.missed_events);
+
+        commands
+            .write(&UserCommand::Quit(command::QuitCommand).into())
+            .await
+            .unwrap();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_direct_messages_never_wait_for_a_stalled_session() {
+        let room_manager = room_manager();
+        let session_registry = SessionRegistry::new();
+        let mut clients = Vec::new();
+        for user_id in ["user-1", "user-2"] {
+            let (mut events, mut commands, session) = spawn_resumable_session_in(
+                Arc::clone(&room_manager),
+                session_registry.clone(),
+                Metrics::new(),
+                ResumableSessions::new(std::time::Duration::ZERO, 16),
+            )
+            .await;
+            commands
+                .write(
+                    &UserCommand::Register(command::RegisterCommand {
+                        username: user_id.to_string(),
+                        password: "password".to_string(),
+                    })
+                    .into(),
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                events.next().await.unwrap().unwrap(),
+                event::Event::LoginSuccessful(_)
+            ));
+            clients.push((events, commands, session));
+        }
+        let (mut stalled_events, _stalled_commands, _stalled_session) = clients.pop().unwrap();
+        let (mut events, mut commands, session) = clients.pop().unwrap();
+
+        // the client of user-2 stops reading, its session stops draining its events once the pipe is full
+        let notice = event::Event::Moderation(event::ModerationBroadcastEvent {
+            room: "room-1".to_string(),
+            user_id: "user-2".to_string(),
+            by_user_id: "user-1".to_string(),
+            action: event::ModerationAction::Unmuted,
+        });
+        for _ in 0..1000 {
+            assert!(session_registry.send_to_user("user-2", &notice));
+            tokio::task::yield_now().await;
+        }
+
+        for (request_id, to_user_id) in [(1, "user-2"), (2, "user-1")] {
+            commands
+                .write(&UserCommandEnvelope {
+                    command: UserCommand::SendDirectMessage(command::SendDirectMessageCommand {
+                        to_user_id: to_user_id.to_string(),
+                        content: "hello".to_string(),
+                    }),
+                    request_id: Some(request_id),
+                })
+                .await
+                .unwrap();
+            let ack = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                next_matching_event(&mut events, |event| matches!(event, event::Event::Ack(_))),
+            )
+            .await
+            .expect("the sender should not wait for the stalled session");
+            let event::Event::Ack(ack) = ack else {
+                unreachable!();
+            };
+            assert_eq!(ack.request_id, request_id);
+        }
+
+        // the stalled session learns about the dropped events once it catches up
+        let event::Event::Error(error) = next_matching_event(&mut stalled_events, |event| {
+            matches!(event, event::Event::Error(_))
+        })
+        .await
+        else {
+            unreachable!();
+        };
+        assert_eq!(error.code, ErrorCode::MissedEvents);
+
+        commands
+            .write(&UserCommand::Quit(command::QuitCommand).into())
+            .await
+            .unwrap();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_room_broadcasts_never_wait_for_a_stalled_session() {
+        let room_manager = room_manager();
+        let session_registry = SessionRegistry::new();
+        let mut clients = Vec::new();
+        for user_id in ["user-1", "user-2"] {
+            let (mut events, mut commands, session) = spawn_resumable_session_in(
+                Arc::clone(&room_manager),
+                session_registry.clone(),
+                Metrics::new(),
+                ResumableSessions::new(std::time::Duration::ZERO, 16),
+            )
+            .await;
+            commands
+                .write(
+                    &UserCommand::Register(command::RegisterCommand {
+                        username: user_id.to_string(),
+                        password: "password".to_string(),
+                    })
+                    .into(),
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                events.next().await.unwrap().unwrap(),
+                event::Event::LoginSuccessful(_)
+            ));
+            clients.push((events, commands, session));
+        }
+        let (mut stalled_events, _stalled_commands, _stalled_session) = clients.pop().unwrap();
+        let (mut events, mut commands, session) = clients.pop().unwrap();
+
+        // every room created and deleted is broadcast, user-2 never reads them
+        for request_id in 0..200 {
+            let room = format!("new-room-{}", request_id / 2);
+            let command = if request_id % 2 == 0 {
+                UserCommand::CreateRoom(command::CreateRoomCommand {
+                    room,
+                    description: "new room".to_string(),
+                })
+            } else {
+                UserCommand::DeleteRoom(command::DeleteRoomCommand { room })
+            };
+            commands
+                .write(&UserCommandEnvelope {
+                    command,
+                    request_id: Some(request_id),
+                })
+                .await
+                .unwrap();
+            let ack = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                next_matching_event(&mut events, |event| matches!(event, event::Event::Ack(_))),
+            )
+            .await
+            .expect("the broadcast should not wait for the stalled session");
+            let event::Event::Ack(ack) = ack else {
+                unreachable!();
+            };
+            assert_eq!(ack.request_id, request_id);
+        }
+
+        // the stalled session is told about the dropped events and gets the current rooms again
+        let event::Event::Error(error) = next_matching_event(&mut stalled_events, |event| {
+            matches!(event, event::Event::Error(_))
+        })
+        .await
+        else {
+            unreachable!();
+        };
+        assert_eq!(error.code, ErrorCode::MissedEvents);
+        let event::Event::RoomList(room_list) = stalled_events.next().await.unwrap().unwrap()
+        else {
+            panic!("expected the rooms to be sent again");
+        };
+        assert!(room_list
+            .rooms
+            .iter()
+            .all(|room| !room.name.starts_with("new-room")));
+
+        commands
+            .write(&UserCommand::Quit(command::QuitCommand).into())
+            .await
+            .unwrap();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_resumes_after_losing_its_connection() {
+        let room_manager = room_manager();
+        let resumable_sessions = ResumableSessions::new(std::time::Duration::from_secs(60), 16);
+        let (mut events, mut commands, session) = spawn_resumable_session_in(
+            Arc::clone(&room_manager),
+            SessionRegistry::new(),
+            Metrics::new(),
+            resumable_sessions.clone(),
+        )
+        .await;
+
+        commands
+            .write(
+                &UserCommand::Register(command::RegisterCommand {
+                    username: "user-1".to_string(),
+                    password: "password-1".to_string(),
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        let event::Event::LoginSuccessful(login) = events.next().await.unwrap().unwrap() else {
+            panic!("expected the login successful event");
+        };
+        commands
+            .write(
+                &UserCommand::JoinRoom(command::JoinRoomCommand {
+                    room: "room-1".to_string(),
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+
+        // the client counts the events it receives after logging in
+        let mut last_seen_seq = 0;
+        while let Some(event) = events.next().await {
+            last_seen_seq += 1;
+            if matches!(event.unwrap(), event::Event::RoomParticipation(participation) if participation.user_id == "user-1")
+            {
+                break;
+            }
+        }
+
+        // the connection drops without quitting, the session keeps collecting the events of its room
+        drop((events, commands));
+        session.await.unwrap().unwrap();
+        let (mut broadcast_rx, handle, _, _) = room_manager
+            .join_room(
+                "room-1",
+                &SessionAndUserId {
+                    session_id: "session-2".to_string(),
+                    user_id: "user-2".to_string(),
+                },
+            )
+            .unwrap();
+        handle.send_message("while away".to_string()).await.unwrap();
+
+        let (mut events, mut commands, session) = spawn_resumable_session_in(
+            Arc::clone(&room_manager),
+            SessionRegistry::new(),
+            Metrics::new(),
+            resumable_sessions.clone(),
+        )
+        .await;
+        commands
+            .write(
+                &UserCommand::Resume(command::ResumeCommand {
+                    session_id: login.session_id.clone(),
+                    last_seen_seq,
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        let event::Event::Resumed(resumed) = events.next().await.unwrap().unwrap() else {
+            panic!("expected the resumed event");
+        };
+        assert_eq!(resumed.user_id, "user-1");
+        assert_eq!(resumed.joined_rooms, vec!["room-1".to_string()]);
+        assert_eq!(resumed.seq, last_seen_seq);
+        let event::Event::RoomParticipation(participation) = events.next().await.unwrap().unwrap()
+        else {
+            panic!("expected the participation of the other user");
+        };
+        assert_eq!(participation.user_id, "user-2");
+        let event::Event::UserMessage(message) = events.next().await.unwrap().unwrap() else {
+            panic!("expected the message sent while away");
+        };
+        assert_eq!(message.content, "while away");
+
+        // the other users have never seen the user leave
+        while let Ok(event) = broadcast_rx.try_recv() {
+            assert!(!matches!(
+                event.event(),
+                event::Event::RoomParticipation(participation)
+                    if participation.status == event::RoomParticipationStatus::Left
+            ));
+        }
+
+        // resuming again takes the session over from the connection which is still open
+        let (mut new_events, mut new_commands, new_session) = spawn_resumable_session_in(
+            Arc::clone(&room_manager),
+            SessionRegistry::new(),
+            Metrics::new(),
+            resumable_sessions.clone(),
+        )
+        .await;
+        new_commands
+            .write(
+                &UserCommand::Resume(command::ResumeCommand {
+                    session_id: login.session_id.clone(),
+                    last_seen_seq: resumed.seq + 2,
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        let event::Event::Resumed(resumed) = new_events.next().await.unwrap().unwrap() else {
+            panic!("expected the resumed event");
+        };
+        assert_eq!(resumed.seq, last_seen_seq + 2);
+        assert!(events.next().await.is_none());
+        session.await.unwrap().unwrap();
+
+        new_commands
+            .write(&UserCommand::Quit(command::QuitCommand).into())
+            .await
+            .unwrap();
+        new_session.await.unwrap().unwrap();
+
+        // a session which has ended can not be resumed
+        let (mut events, mut commands, session) = spawn_resumable_session_in(
+            room_manager,
+            SessionRegistry::new(),
+            Metrics::new(),
+            resumable_sessions,
+        )
+        .await;
+        commands
+            .write(
+                &UserCommand::Resume(command::ResumeCommand {
+                    session_id: login.session_id,
+                    last_seen_seq: 0,
+                })
+                .into(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.next().await.unwrap().unwrap(),
+            event::Event::LoginFailed(_)
+        ));
+        drop((events, commands));
+        session.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use comms::event::RateLimitedReplyEvent;
+
+use crate::config::RateLimitConfig;
+
+/// Strikes are forgotten once a user has not been rate limited for this long
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+/// [TokenBucket] allows bursts of up to `capacity` messages, refilled at a constant rate
+struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, refill_per_sec: f64, now: Instant) -> Self {
+        TokenBucket {
+            capacity: f64::from(capacity),
+            refill_per_sec,
+            tokens: f64::from(capacity),
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// How long until a token is available, zero if one is available right away
+    fn wait_time(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_sec)
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+/// What has happened to a user who has sent a message too fast
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Penalty {
+    /// The message has been dropped
+    Dropped,
+    /// The user is muted until the given time across all of their sessions
+    Muted(DateTime<Utc>),
+    /// The session has to be closed
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// [RateLimited] is the outcome of a message which has been sent faster than the limits allow
+pub struct RateLimited {
+    pub retry_after: Duration,
+    pub penalty: Penalty,
+}
+
+impl RateLimited {
+    pub fn into_reply(self, request_id: Option<u64>) -> RateLimitedReplyEvent {
+        RateLimitedReplyEvent {
+            request_id,
+            retry_after_millis: u64::try_from(self.retry_after.as_millis()).unwrap_or(u64::MAX),
+            muted_until: match self.penalty {
+                Penalty::Muted(until) => Some(until),
+                _ => None,
+            },
+            disconnected: self.penalty == Penalty::Disconnected,
+        }
+    }
+}
+
+/// The part of the rate limits shared by every session of a user
+#[derive(Debug)]
+struct UserLimit {
+    bucket: TokenBucket,
+    strikes: u32,
+    struck_at: Option<Instant>,
+    muted_until: Option<DateTime<Utc>>,
+}
+
+impl UserLimit {
+    /// Whether the user has nothing to remember, so that forgetting it does not lift any limit
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.bucket.refill(now);
+
+        self.bucket.is_full()
+            && self.muted_until.is_none_or(|until| until <= Utc::now())
+            && self
+                .struck_at
+                .is_none_or(|struck_at| now.duration_since(struck_at) > STRIKE_WINDOW)
+    }
+}
+
+/// [RateLimiter] keeps track of how fast every user sends messages across the whole server
+///
+/// Each session takes its own [SessionRateLimiter], users are forgotten once their last session is gone
+/// and they have nothing left to serve.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    users: Arc<Mutex<HashMap<String, UserLimit>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            users: Arc::default(),
+        }
+    }
+
+    /// Creates the limiter of a new session of the given user
+    pub fn session(&self, user_id: &str) -> SessionRateLimiter {
+        SessionRateLimiter {
+            rate_limiter: self.clone(),
+            user_id: String::from(user_id),
+            bucket: TokenBucket::new(
+                self.config.session_burst,
+                self.config.session_messages_per_second,
+                Instant::now(),
+            ),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, UserLimit>> {
+        self.users.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug)]
+/// [SessionRateLimiter] limits the messages of a single session, alongside with the limits of its user
+pub struct SessionRateLimiter {
+    rate_limiter: RateLimiter,
+    user_id: String,
+    bucket: TokenBucket,
+}
+
+impl SessionRateLimiter {
+    /// Takes a token for a message from both the session and the user, or neither of them if either is out of tokens
+    pub fn check(&mut self) -> Result<(), RateLimited> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&mut self, now: Instant) -> Result<(), RateLimited> {
+        let config = self.rate_limiter.config;
+        let mut users = self.rate_limiter.lock();
+        let user = users
+            .entry(self.user_id.clone())
+            .or_insert_with(|| UserLimit {
+                bucket: TokenBucket::new(config.user_burst, config.user_messages_per_second, now),
+                strikes: 0,
+                struck_at: None,
+                muted_until: None,
+            });
+
+        self.bucket.refill(now);
+        user.bucket.refill(now);
+
+        let muted_for = user
+            .muted_until
+            .and_then(|until| (until - Utc::now()).to_std().ok());
+        let retry_after = self
+            .bucket
+            .wait_time()
+            .max(user.bucket.wait_time())
+            .max(muted_for.unwrap_or_default());
+
+        if retry_after.is_zero() {
+            self.bucket.tokens -= 1.0;
+            user.bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        if user
+            .struck_at
+            .is_some_and(|struck_at| now.duration_since(struck_at) > STRIKE_WINDOW)
+        {
+            user.strikes = 0;
+        }
+        user.strikes += 1;
+        user.struck_at = Some(now);
+
+        let penalty = if user.strikes >= config.disconnect_after_strikes {
+            Penalty::Disconnected
+        } else if let (Some(until), Some(_)) = (user.muted_until, muted_for) {
+            Penalty::Muted(until)
+        } else if user.strikes >= config.mute_after_strikes {
+            let mute = Duration::from_secs(config.mute_secs);
+            let until = Utc::now() + chrono::Duration::from_std(mute).unwrap_or_default();
+            user.muted_until = Some(until);
+
+            return Err(RateLimited {
+                retry_after: retry_after.max(mute),
+                penalty: Penalty::Muted(until),
+            });
+        } else {
+            Penalty::Dropped
+        };
+
+        Err(RateLimited {
+            retry_after,
+            penalty,
+        })
+    }
+}
+
+impl Drop for SessionRateLimiter {
+    fn drop(&mut self) {
+        let now = Instant::now();
+        let mut users = self.rate_limiter.lock();
+
+        if users
+            .get_mut(&self.user_id)
+            .is_some_and(|user| user.is_idle(now))
+        {
+            users.remove(&self.user_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            session_messages_per_second: 1.0,
+            session_burst: 2,
+            user_messages_per_second: 1.0,
+            user_burst: 3,
+            mute_after_strikes: 2,
+            mute_secs: 60,
+            disconnect_after_strikes: 4,
+        }
+    }
+
+    #[test]
+    fn test_session_and_user_buckets() {
+        let rate_limiter = RateLimiter::new(RateLimitConfig {
+            mute_after_strikes: 5,
+            disconnect_after_strikes: 6,
+            ..config()
+        });
+        let mut session_1 = rate_limiter.session("user-1");
+        let mut session_2 = rate_limiter.session("user-1");
+        let now = Instant::now();
+
+        // the burst of the session runs out first, then the burst of the user shared by both sessions
+        assert!(session_1.check_at(now).is_ok());
+        assert!(session_1.check_at(now).is_ok());
+        assert_eq!(
+            session_1.check_at(now).unwrap_err().penalty,
+            Penalty::Dropped
+        );
+        assert!(session_2.check_at(now).is_ok());
+        let rate_limited = session_2.check_at(now).unwrap_err();
+        assert_eq!(rate_limited.retry_after, Duration::from_secs(1));
+
+        // the buckets refill over time
+        assert!(session_2.check_at(now + Duration::from_secs(1)).is_ok());
+
+        // other users are not affected
+        assert!(rate_limiter.session("user-2").check_at(now).is_ok());
+    }
+
+    #[test]
+    fn test_repeat_offenders_are_muted_then_disconnected() {
+        let rate_limiter = RateLimiter::new(config());
+        let mut session = rate_limiter.session("user-1");
+        let now = Instant::now();
+
+        assert!(session.check_at(now).is_ok());
+        assert!(session.check_at(now).is_ok());
+        assert_eq!(session.check_at(now).unwrap_err().penalty, Penalty::Dropped);
+
+        let rate_limited = session.check_at(now).unwrap_err();
+        assert!(matches!(rate_limited.penalty, Penalty::Muted(_)));
+        assert_eq!(rate_limited.retry_after, Duration::from_secs(60));
+
+        // the mute holds even once the buckets have been refilled, and across sessions
+        let later = now + Duration::from_secs(10);
+        assert!(matches!(
+            rate_limiter
+                .session("user-1")
+                .check_at(later)
+                .unwrap_err()
+                .penalty,
+            Penalty::Muted(_)
+        ));
+        assert_eq!(
+            session.check_at(later).unwrap_err().penalty,
+            Penalty::Disconnected
+        );
+    }
+
+    #[test]
+    fn test_idle_users_are_forgotten() {
+        let rate_limiter = RateLimiter::new(config());
+
+        drop(rate_limiter.session("user-1"));
+        assert!(rate_limiter.lock().is_empty());
+
+        let mut session = rate_limiter.session("user-1");
+        session.check().unwrap();
+        drop(session);
+        // the user bucket has not been refilled yet
+        assert_eq!(rate_limiter.lock().len(), 1);
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::VecDeque;
+
+use comms::transport::server::SharedEvent;
+
+#[derive(Debug)]
+/// [ReplayBuffer] keeps the most recent events sent to a session, so they can be sent again once it is resumed
+///
+/// Events are numbered by the order they are sent in, starting at 1, the same way the client counts them.
+/// Once the buffer is full the oldest event is dropped, its number is still counted.
+pub(super) struct ReplayBuffer {
+    events: VecDeque<SharedEvent>,
+    capacity: usize,
+    /// The number of the last event sent, zero until the first one
+    last_seq: u64,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        ReplayBuffer {
+            events: VecDeque::new(),
+            capacity,
+            last_seq: 0,
+        }
+    }
+
+    /// Keeps an event which is being sent to the client, dropping the oldest one if the buffer is full
+    pub fn push(&mut self, event: SharedEvent) {
+        self.last_seq += 1;
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// The events sent after the given one which are still kept, events the client claims to have seen
+    /// beyond the last one sent are ignored
+    ///
+    /// # Returns
+    ///
+    /// - The number of the event right before the first returned one
+    /// - The events, from oldest to newest
+    pub fn replay_after(&self, last_seen_seq: u64) -> (u64, Vec<SharedEvent>) {
+        let first_kept_seq = self.last_seq + 1 - self.events.len() as u64;
+        let replayed_after_seq = last_seen_seq.clamp(first_kept_seq - 1, self.last_seq);
+
+        (
+            replayed_after_seq,
+            self.events
+                .iter()
+                .skip((replayed_after_seq + 1 - first_kept_seq) as usize)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use comms::event::{Event, RoomDeletedBroadcastEvent};
+
+    use super::*;
+
+    fn event(room: &str) -> SharedEvent {
+        Event::RoomDeleted(RoomDeletedBroadcastEvent {
+            room: room.to_string(),
+        })
+        .into()
+    }
+
+    fn rooms(events: Vec<SharedEvent>) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| match event.event() {
+                Event::RoomDeleted(event) => event.room.clone(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replays_events_after_the_last_seen_one() {
+        let mut buffer = ReplayBuffer::new(5);
+        let (seq, events) = buffer.replay_after(0);
+        assert_eq!(seq, 0);
+        assert!(events.is_empty());
+
+        for room in ["1", "2", "3"] {
+            buffer.push(event(room));
+        }
+
+        let (seq, events) = buffer.replay_after(1);
+        assert_eq!(seq, 1);
+        assert_eq!(rooms(events), vec!["2", "3"]);
+
+        let (seq, events) = buffer.replay_after(3);
+        assert_eq!(seq, 3);
+        assert!(events.is_empty());
+
+        // the client can not have seen more than has been sent
+        let (seq, events) = buffer.replay_after(7);
+        assert_eq!(seq, 3);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_skips_events_no_longer_kept() {
+        let mut buffer = ReplayBuffer::new(2);
+        for room in ["1", "2", "3", "4"] {
+            buffer.push(event(room));
+        }
+
+        let (seq, events) = buffer.replay_after(1);
+        assert_eq!(seq, 2);
+        assert_eq!(rooms(events), vec!["3", "4"]);
+
+        let mut buffer = ReplayBuffer::new(0);
+        buffer.push(event("1"));
+
+        let (seq, events) = buffer.replay_after(0);
+        assert_eq!(seq, 1);
+        assert!(events.is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+
+use super::{chat_session::ChatSession, greeting::Capabilities};
+
+/// A session which has been taken off its connection, alongside with the capabilities it has been served with
+pub(super) struct DetachedSession {
+    pub chat_session: ChatSession,
+    pub capabilities: Capabilities,
+}
+
+/// The channel a session is handed over through, to the connection resuming it
+type HandoverTx = oneshot::Sender<DetachedSession>;
+
+#[derive(Default)]
+struct Holders {
+    /// Session id to the registration of whoever holds the session, and the channel to ask them to hand it over
+    by_session_id: HashMap<String, (u64, oneshot::Sender<HandoverTx>)>,
+    last_registration: u64,
+}
+
+/// [ResumableSessions] keeps track of every session which can be resumed by its session id
+///
+/// While the connection of a session is alive, resuming the session takes it over from the connection,
+/// which may be half open after a network drop. Once the connection is lost, the session stays in its rooms
+/// for the grace period and keeps the events sent to it, then leaves its rooms unless it has been resumed.
+#[derive(Clone)]
+pub struct ResumableSessions {
+    holders: Arc<Mutex<Holders>>,
+    grace_period: Duration,
+    /// How many of the most recent events of a session are kept to replay them
+    replay_capacity: usize,
+}
+
+impl ResumableSessions {
+    /// Sessions end with their connection when the grace period is zero
+    pub fn new(grace_period: Duration, replay_capacity: usize) -> Self {
+        ResumableSessions {
+            holders: Arc::default(),
+            grace_period,
+            replay_capacity,
+        }
+    }
+
+    pub fn replay_capacity(&self) -> usize {
+        self.replay_capacity
+    }
+
+    /// Makes a session resumable while its holder keeps the returned [Takeover]
+    pub(super) fn register(&self, session_id: &str) -> Takeover {
+        if self.grace_period.is_zero() {
+            return Takeover {
+                request_rx: None,
+                session_id: String::from(session_id),
+                registration: 0,
+                resumable_sessions: self.clone(),
+            };
+        }
+
+        let (request_tx, request_rx) = oneshot::channel();
+        let mut holders = self.lock();
+        holders.last_registration += 1;
+        let registration = holders.last_registration;
+        holders
+            .by_session_id
+            .insert(String::from(session_id), (registration, request_tx));
+
+        Takeover {
+            request_rx: Some(request_rx),
+            session_id: String::from(session_id),
+            registration,
+            resumable_sessions: self.clone(),
+        }
+    }
+
+    /// Takes a session over from whoever holds it, nobody else can resume it afterwards
+    ///
+    /// # Returns
+    ///
+    /// - The session, [None] if there is no such session or it has ended meanwhile
+    pub(super) async fn take(&self, session_id: &str) -> Option<DetachedSession> {
+        let (_, request_tx) = self.lock().by_session_id.remove(session_id)?;
+        let (handover_tx, handover_rx) = oneshot::channel();
+        request_tx.send(handover_tx).ok()?;
+
+        handover_rx.await.ok()
+    }
+
+    /// Keeps a session whose connection has been lost until it is resumed or the grace period is over,
+    /// collecting the events sent to it meanwhile
+    pub(super) fn detach(&self, mut session: DetachedSession) {
+        let mut takeover = self.register(session.chat_session.session_id());
+        if takeover.request_rx.is_none() {
+            session.chat_session.leave_all_rooms();
+            return;
+        }
+
+        let grace_period = self.grace_period;
+        tokio::spawn(async move {
+            let expired = tokio::time::sleep(grace_period);
+            tokio::pin!(expired);
+
+            loop {
+                tokio::select! {
+                    biased;
+                    handover_tx = takeover.requested() => {
+                        hand_over(session, handover_tx);
+                        break;
+                    }
+                    _ = &mut expired => {
+                        log::debug!(
+                            "Session {} of user {} has not been resumed in time",
+                            session.chat_session.session_id(),
+                            session.chat_session.user_id()
+                        );
+                        session.chat_session.leave_all_rooms();
+                        break;
+                    }
+                    Ok(event) = session.chat_session.recv() => {
+                        if session.capabilities.allows_event(event.event()) {
+                            session.chat_session.record_sent(event);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    fn unregister(&self, session_id: &str, registration: u64) {
+        let mut holders = self.lock();
+        // the session may have been resumed and registered again by another connection
+        if holders
+            .by_session_id
+            .get(session_id)
+            .is_some_and(|(holder, _)| *holder == registration)
+        {
+            holders.by_session_id.remove(session_id);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Holders> {
+        self.holders
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Hands a session over to the connection resuming it, the session leaves its rooms if that connection is gone
+pub(super) fn hand_over(session: DetachedSession, handover_tx: HandoverTx) {
+    if let Err(mut session) = handover_tx.send(session) {
+        session.chat_session.leave_all_rooms();
+    }
+}
+
+/// [Takeover] is held alongside with a resumable session, to be asked to hand the session over
+/// The session is no longer resumable once it is dropped, unless it has been resumed meanwhile
+pub(super) struct Takeover {
+    request_rx: Option<oneshot::Receiver<HandoverTx>>,
+    session_id: String,
+    registration: u64,
+    resumable_sessions: ResumableSessions,
+}
+
+impl Takeover {
+    /// Waits until another connection resumes the session, never completes if the session is not resumable
+    ///
+    /// # Cancel Safety
+    ///
+    /// This method is cancel-safe, no request is lost if another branch of a [tokio::select!] completes first.
+    pub async fn requested(&mut self) -> HandoverTx {
+        if let Some(request_rx) = self.request_rx.as_mut() {
+            let request = request_rx.await;
+            // a completed receiver can not be polled again
+            self.request_rx = None;
+
+            if let Ok(handover_tx) = request {
+                return handover_tx;
+            }
+        }
+
+        std::future::pending().await
+    }
+}
+
+impl Drop for Takeover {
+    fn drop(&mut self) {
+        if self.request_rx.is_some() {
+            self.resumable_sessions
+                .unregister(&self.session_id, self.registration);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use comms::{event::Event, transport::server::SharedEvent};
+use tokio::sync::mpsc;
+
+use crate::room_manager::SessionAndUserId;
+
+/// How many events sent directly to a session are queued before it is considered to have fallen behind
+const DIRECT_EVENT_CHANNEL_CAPACITY: usize = 100;
+
+/// User id to the event channels of each of the user's sessions, keyed by session id
+type SessionsByUser = HashMap<String, HashMap<String, DirectEventSender>>;
+
+/// Creates the channel of the events sent directly to a session, rather than through a room
+pub fn direct_event_channel() -> (DirectEventSender, DirectEventReceiver) {
+    let (event_tx, event_rx) = mpsc::channel(DIRECT_EVENT_CHANNEL_CAPACITY);
+    let missed_events = Arc::new(AtomicU64::new(0));
+
+    (
+        DirectEventSender {
+            event_tx,
+            missed_events: Arc::clone(&missed_events),
+        },
+        DirectEventReceiver {
+            event_rx,
+            missed_events,
+        },
+    )
+}
+
+/// [DirectEventSender] queues events for a session without ever waiting for it,
+/// so a session which has stopped reading never holds up the one sending to it
+#[derive(Debug, Clone)]
+pub struct DirectEventSender {
+    event_tx: mpsc::Sender<SharedEvent>,
+    missed_events: Arc<AtomicU64>,
+}
+
+impl DirectEventSender {
+    /// Queues an event, it is dropped and counted as missed if the session has fallen behind
+    fn send(&self, event: SharedEvent) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.event_tx.try_send(event) {
+            self.missed_events.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// [DirectEventReceiver] receives the events sent directly to a session, and learns about the dropped ones
+#[derive(Debug)]
+pub struct DirectEventReceiver {
+    event_rx: mpsc::Receiver<SharedEvent>,
+    missed_events: Arc<AtomicU64>,
+}
+
+impl DirectEventReceiver {
+    /// Receives the next event sent to the session
+    ///
+    /// # Cancel Safety
+    ///
+    /// This method is cancel-safe, see [mpsc::Receiver::recv].
+    pub async fn recv(&mut self) -> Option<SharedEvent> {
+        self.event_rx.recv().await
+    }
+
+    /// How many events have been dropped since the last call, because the session had fallen behind
+    pub fn take_missed_events(&self) -> u64 {
+        self.missed_events.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// [SessionRegistry] keeps track of the live sessions of every user across the whole server
+///
+/// Rooms only know about their own participants, the registry is used to reach a user
+/// directly regardless of which rooms they have joined.
+#[derive(Debug, Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<SessionsByUser>>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a session so it can receive events sent to its user
+    pub fn register(&self, session_and_user_id: &SessionAndUserId, event_tx: DirectEventSender) {
+        self.lock()
+            .entry(session_and_user_id.user_id.clone())
+            .or_default()
+            .insert(session_and_user_id.session_id.clone(), event_tx);
+    }
+
+    /// Remove a session from the registry, does nothing if the session is not registered
+    pub fn unregister(&self, session_and_user_id: &SessionAndUserId) {
+        let mut sessions = self.lock();
+
+        if let Some(user_sessions) = sessions.get_mut(&session_and_user_id.user_id) {
+            user_sessions.remove(&session_and_user_id.session_id);
+
+            if user_sessions.is_empty() {
+                sessions.remove(&session_and_user_id.user_id);
+            }
+        }
+    }
+
+    /// Send an event to every live session of the given user, it is serialized once for all of them
+    /// Never waits for the sessions, the ones which have fallen behind miss the event
+    ///
+    /// # Returns
+    ///
+    /// - Whether the user has at least one live session
+    pub fn send_to_user(&self, user_id: &str, event: &Event) -> bool {
+        let event = SharedEvent::from(event.clone());
+        let sessions = self.lock();
+        let Some(user_sessions) = sessions.get(user_id) else {
+            return false;
+        };
+
+        for event_tx in user_sessions.values() {
+            event_tx.send(event.clone());
+        }
+
+        true
+    }
+
+    /// Send an event to every live session on the server, it is serialized once for all of them
+    /// Never waits for the sessions, the ones which have fallen behind miss the event
+    pub fn broadcast(&self, event: &Event) {
+        let event = SharedEvent::from(event.clone());
+
+        for event_tx in self
+            .lock()
+            .values()
+            .flat_map(|user_sessions| user_sessions.values())
+        {
+            event_tx.send(event.clone());
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SessionsByUser> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,37 @@
+use std::path::PathBuf;
+
+use clap::{builder::PossibleValuesParser, builder::TypedValueParser, Parser};
+use comms::{command::Codec, transport::tls::ServerTrust};
+
+/// Command line flags of the terminal client
+#[derive(Debug, Parser)]
+#[command(version, about = "Terminal client of the rust-chat-server project")]
+pub struct Cli {
+    /// Connect with TLS, trusting server certificates issued by the CA certificates in the given PEM file
+    #[arg(long, value_name = "FILE", conflicts_with = "pinned_cert")]
+    pub ca_file: Option<PathBuf>,
+    /// Connect with TLS, trusting only the certificate in the given PEM file e.g. a self-signed one
+    #[arg(long, value_name = "FILE")]
+    pub pinned_cert: Option<PathBuf>,
+    /// The encoding of commands and events, msgpack is more compact but not readable while debugging
+    #[arg(
+        long,
+        default_value = "json",
+        value_parser = PossibleValuesParser::new(["json", "msgpack"]).map(|codec| match codec.as_str() {
+            "msgpack" => Codec::MessagePack,
+            _ => Codec::Json,
+        })
+    )]
+    pub codec: Codec,
+}
+
+impl Cli {
+    /// How the server certificate is trusted, connections are not encrypted without one
+    pub fn server_trust(&self) -> Option<ServerTrust> {
+        match (&self.ca_file, &self.pinned_cert) {
+            (Some(ca_file), _) => Some(ServerTrust::CustomCa(ca_file.clone())),
+            (None, Some(pinned_cert)) => Some(ServerTrust::PinnedCertificate(pinned_cert.clone())),
+            (None, None) => None,
+        }
+    }
+}
@@ -1,7 +1,10 @@
+use clap::Parser;
+use cli::Cli;
 use state_store::StateStore;
 use termination::create_termination;
 use ui_management::UiManager;
 
+mod cli;
 mod state_store;
 mod termination;
 mod ui_management;
@@ -10,8 +13,9 @@ use termination::{Interrupted, Terminator};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
     let (terminator, mut interrupt_rx) = create_termination();
-    let (state_store, state_rx) = StateStore::new();
+    let (state_store, state_rx) = StateStore::new(cli.server_trust(), cli.codec);
     let (ui_manager, action_rx) = UiManager::new();
 
     tokio::try_join!(
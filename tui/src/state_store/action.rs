@@ -1,3 +1,29 @@
+use comms::event::RoomRole;
+
+/// How the user wants to authenticate once connected to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthMode {
+    /// Log in with an existing account
+    Login,
+    /// Create a new account and log in with it
+    Register,
+}
+
+/// What an operator wants to do to a user of a room
+#[derive(Debug, Clone, PartialEq)]
+pub enum Moderation {
+    Kick,
+    Ban,
+    Unban,
+    /// Mute the user for the given duration, zero lifts the mute
+    Mute {
+        duration_secs: u64,
+    },
+    SetRole {
+        role: RoomRole,
+    },
+}
+
 /// The set of actions that can be performed in the application's state store.
 ///
 /// This enum represents the different types of actions that can be dispatched to the
@@ -6,16 +32,49 @@
 #[derive(Debug, Clone, PartialEq)]
 pub enum Action {
     /// No action.
-    /// 
+    ///
     /// This is used when no action is needed. While we could model this as an
     /// `Option<Action>`, this would preclude marking the type as #[must_use],
     /// and thus invite code that accidentally ignores actions returned from functions.
-    /// 
+    ///
     /// See https://github.com/rust-lang/rust/issues/71368 for a more detailed
     /// discussion of this issue.
     None,
-    ConnectToServerRequest { addr: String },
-    SendMessage { content: String },
-    SelectRoom { room: String },
+    ConnectToServerRequest {
+        addr: String,
+        username: String,
+        password: String,
+        auth_mode: AuthMode,
+    },
+    SendMessage {
+        content: String,
+    },
+    SendDirectMessage {
+        to_user_id: String,
+        content: String,
+    },
+    SelectRoom {
+        room: String,
+    },
+    /// Fetch a page of messages older than the ones we hold for the given room
+    FetchHistory {
+        room: String,
+    },
+    /// Create a new room on the server
+    CreateRoom {
+        name: String,
+        description: String,
+    },
+    /// Delete a room from the server
+    DeleteRoom {
+        room: String,
+    },
+    /// Moderate a user of the active room
+    Moderate {
+        user_id: String,
+        moderation: Moderation,
+    },
+    /// Stop trying to connect again after the connection to the server was lost, back to the connect page
+    GiveUpReconnecting,
     Exit,
 }
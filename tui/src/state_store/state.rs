@@ -1,27 +1,94 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
 
+use chrono::{DateTime, Local, Utc};
 use circular_queue::CircularQueue;
-use comms::event;
+use comms::{command, event};
+
+/// Where a message stands on its way to the server and back
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeliveryStatus {
+    /// Sent by us, waiting for the server to acknowledge the request with the given id
+    Pending { request_id: u64 },
+    /// Sent by us and acknowledged, waiting for the server to echo it back
+    Sent,
+    /// Sent by us and rejected by the server
+    Failed,
+    /// Received from the server
+    Delivered,
+}
 
 #[derive(Debug, Clone)]
 pub enum MessageBoxItem {
-    Message { user_id: String, content: String },
+    Message {
+        /// The id of the message within its room, direct messages and pending messages have none
+        id: Option<u64>,
+        timestamp: DateTime<Utc>,
+        user_id: String,
+        content: String,
+        delivery: DeliveryStatus,
+    },
     Notification(String),
 }
 
+impl From<&event::UserMessageBroadcastEvent> for MessageBoxItem {
+    fn from(event: &event::UserMessageBroadcastEvent) -> Self {
+        MessageBoxItem::Message {
+            id: Some(event.id),
+            timestamp: event.timestamp,
+            user_id: event.user_id.clone(),
+            content: event.content.clone(),
+            delivery: DeliveryStatus::Delivered,
+        }
+    }
+}
+
 const MAX_MESSAGES_TO_STORE_PER_ROOM: usize = 100;
+/// How long an error reported by the server stays in the status line
+const STATUS_ERROR_DURATION_SECS: usize = 5;
+
+impl From<&event::DirectMessageEvent> for MessageBoxItem {
+    fn from(event: &event::DirectMessageEvent) -> Self {
+        MessageBoxItem::Message {
+            id: None,
+            timestamp: event.timestamp,
+            user_id: event.from_user_id.clone(),
+            content: event.content.clone(),
+            delivery: DeliveryStatus::Delivered,
+        }
+    }
+}
+
+/// The key of the direct message conversation with the given user in the room data map
+/// Room names can not contain '@', so it never collides with a room
+pub fn direct_conversation_key(user_id: &str) -> String {
+    format!("@{}", user_id)
+}
 
-/// RoomData holds the data for a room
+/// RoomData holds the data for a room, or for a direct message conversation with a single user
 #[derive(Debug, Clone)]
 pub struct RoomData {
     /// The name of the room
     pub name: String,
+    /// The user on the other side, if this is a direct message conversation instead of a room
+    pub direct_user_id: Option<String>,
     /// The description of the Room
     pub description: String,
     /// List of users in the room
     pub users: HashSet<String>,
     /// History of recorded messages
     pub messages: CircularQueue<MessageBoxItem>,
+    /// Older messages fetched from the server on scrollback, they come right before `messages`
+    pub scrollback: VecDeque<MessageBoxItem>,
+    /// How many older messages have been put in front of the scrollback so far,
+    /// the scrollback also grows at its end when recent messages fall out of `messages`
+    pub prepended_count: usize,
+    /// Whether the server has messages older than the ones we hold
+    pub has_more_history: bool,
+    /// Whether a page of older messages was requested and not received yet
+    pub is_fetching_history: bool,
     /// Has joined the room
     pub has_joined: bool,
     /// Has unread messages
@@ -32,9 +99,14 @@ impl Default for RoomData {
     fn default() -> Self {
         RoomData {
             name: String::new(),
+            direct_user_id: None,
             description: String::new(),
             users: HashSet::new(),
             messages: CircularQueue::with_capacity(MAX_MESSAGES_TO_STORE_PER_ROOM),
+            scrollback: VecDeque::new(),
+            prepended_count: 0,
+            has_more_history: false,
+            is_fetching_history: false,
             has_joined: false,
             has_unread: false,
         }
@@ -49,14 +121,102 @@ impl RoomData {
             ..Default::default()
         }
     }
+
+    /// Creates the conversation for direct messages between the user and the given other user
+    pub fn new_direct(user_id: &str, other_user_id: &str) -> Self {
+        RoomData {
+            name: direct_conversation_key(other_user_id),
+            direct_user_id: Some(String::from(other_user_id)),
+            description: format!("Direct messages with @{}", other_user_id),
+            users: HashSet::from([String::from(user_id), String::from(other_user_id)]),
+            // there is no room to join, the conversation is reachable as long as the user is online
+            has_joined: true,
+            ..Default::default()
+        }
+    }
+
+    /// Records a new item after all the other items
+    pub fn push_message(&mut self, item: MessageBoxItem) {
+        // once the user scrolled back, items falling out of the recent messages are kept in the
+        // scrollback instead of being dropped, otherwise there would be a gap in the history
+        if let Some(popped) = self.messages.push(item) {
+            if !self.scrollback.is_empty() {
+                self.scrollback.push_back(popped);
+            }
+        }
+    }
+
+    /// Marks the first of our acknowledged messages matching the given predicate as delivered
+    ///
+    /// # Returns
+    ///
+    /// - Whether a message was found, in which case the echo of the server should not be recorded again
+    fn confirm_sent_message(&mut self, predicate: impl Fn(Option<u64>, &str) -> bool) -> bool {
+        self.messages
+            .asc_iter_mut()
+            .find_map(|item| match item {
+                MessageBoxItem::Message {
+                    id,
+                    content,
+                    delivery,
+                    ..
+                } if *delivery == DeliveryStatus::Sent && predicate(*id, content) => Some(delivery),
+                _ => None,
+            })
+            .map(|delivery| *delivery = DeliveryStatus::Delivered)
+            .is_some()
+    }
+
+    /// Iterates over all the recorded items, from oldest to newest
+    pub fn iter_messages(&self) -> impl Iterator<Item = &MessageBoxItem> {
+        self.scrollback.iter().chain(self.messages.asc_iter())
+    }
+
+    /// The id of the newest message we hold, if any
+    pub fn newest_message_id(&self) -> Option<u64> {
+        self.messages.iter().find_map(|item| match item {
+            MessageBoxItem::Message { id, .. } => *id,
+            MessageBoxItem::Notification(_) => None,
+        })
+    }
+
+    /// The id of the oldest message we hold, if any
+    pub fn oldest_message_id(&self) -> Option<u64> {
+        self.iter_messages().find_map(|item| match item {
+            MessageBoxItem::Message { id, .. } => *id,
+            MessageBoxItem::Notification(_) => None,
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum ServerConnectionStatus {
     Uninitalized,
     Connecting,
-    Connected { addr: String },
-    Errored { err: String },
+    Connected {
+        addr: String,
+    },
+    /// The connection was lost, the session is picked up again on a new connection until the user gives up
+    Reconnecting {
+        addr: String,
+        /// How many attempts to connect again have failed so far
+        failed_attempts: u32,
+        /// The timer value at which the next attempt is made
+        retry_at: usize,
+        /// Why the connection was lost, or why the last attempt failed
+        err: String,
+    },
+    Errored {
+        err: String,
+    },
+}
+
+/// An error reported by the server for one of our commands
+#[derive(Debug, Clone)]
+pub struct StatusError {
+    pub message: String,
+    /// The timer value after which the error is no longer shown
+    pub expires_at: usize,
 }
 
 /// State holds the state of the application
@@ -71,6 +231,8 @@ pub struct State {
     pub room_data_map: HashMap<String, RoomData>,
     /// Timer since app was opened
     pub timer: usize,
+    /// The most recent error reported by the server, if it has not expired yet
+    pub status_error: Option<StatusError>,
 }
 
 impl Default for State {
@@ -81,6 +243,7 @@ impl Default for State {
             user_id: String::new(),
             room_data_map: HashMap::new(),
             timer: 0,
+            status_error: None,
         }
     }
 }
@@ -90,13 +253,36 @@ impl State {
         match event {
             event::Event::LoginSuccessful(event) => {
                 self.user_id.clone_from(&event.user_id);
-                self.room_data_map = event
-                    .rooms
-                    .clone()
-                    .into_iter()
-                    .map(|r| (r.name.clone(), RoomData::new(r.name, r.description)))
-                    .collect();
+                // logging in again after a lost connection keeps the messages, but the new session is in no room
+                // and will never acknowledge what was sent over the previous one
+                self.update_rooms(&event.rooms);
+                for room_data in self.room_data_map.values_mut() {
+                    if room_data.direct_user_id.is_none() {
+                        room_data.has_joined = false;
+                    }
+
+                    for item in room_data.messages.iter_mut() {
+                        if let MessageBoxItem::Message { delivery, .. } = item {
+                            if matches!(delivery, DeliveryStatus::Pending { .. }) {
+                                *delivery = DeliveryStatus::Failed;
+                            }
+                        }
+                    }
+                }
+            }
+            event::Event::Resumed(event) => {
+                self.user_id.clone_from(&event.user_id);
+                self.update_rooms(&event.rooms);
+                for room_data in self.room_data_map.values_mut() {
+                    if room_data.direct_user_id.is_none() {
+                        room_data.has_joined = event.joined_rooms.contains(&room_data.name);
+                    }
+                }
             }
+            // The handshakes and login failures are handled while connecting, before any state is built
+            event::Event::CodecSelected(_)
+            | event::Event::Hello(_)
+            | event::Event::LoginFailed(_) => {}
             event::Event::RoomParticipation(event) => {
                 if let Some(room_data) = self.room_data_map.get_mut(&event.room) {
                     match event.status {
@@ -114,29 +300,59 @@ impl State {
                         }
                     }
 
-                    room_data
-                        .messages
-                        .push(MessageBoxItem::Notification(format!(
-                            "{} has {} the room",
-                            event.user_id,
-                            match event.status {
-                                event::RoomParticipationStatus::Joined => "joined",
-                                event::RoomParticipationStatus::Left => "left",
-                            }
-                        )));
+                    room_data.push_message(MessageBoxItem::Notification(format!(
+                        "{} has {} the room",
+                        event.user_id,
+                        match event.status {
+                            event::RoomParticipationStatus::Joined => "joined",
+                            event::RoomParticipationStatus::Left => "left",
+                        }
+                    )));
                 }
             }
             event::Event::UserJoinedRoom(event) => {
-                self.room_data_map.get_mut(&event.room).unwrap().users =
-                    event.users.clone().into_iter().collect();
+                // the room may have been deleted right after we joined
+                let Some(room_data) = self.room_data_map.get_mut(&event.room) else {
+                    return;
+                };
+
+                room_data.users = event.users.clone().into_iter().collect();
+                // replay the history only once, messages are kept when leaving and re-joining the room
+                if room_data.messages.is_empty() {
+                    for message in event.messages.iter() {
+                        room_data.push_message(MessageBoxItem::from(message));
+                    }
+
+                    room_data.has_more_history = event
+                        .messages
+                        .first()
+                        .map(|message| message.id > 0)
+                        .unwrap_or(false);
+                    return;
+                }
+
+                // only record the messages sent while we were away from the room, they are recorded like any new message
+                let newest_message_id = room_data.newest_message_id();
+                for message in event
+                    .messages
+                    .iter()
+                    .filter(|message| newest_message_id.is_none_or(|id| message.id > id))
+                {
+                    self.handle_server_event(&event::Event::UserMessage(message.clone()));
+                }
             }
             event::Event::UserMessage(event) => {
-                let room_data = self.room_data_map.get_mut(&event.room).unwrap();
+                let Some(room_data) = self.room_data_map.get_mut(&event.room) else {
+                    return;
+                };
+                // the message we have sent is already recorded, it was given its id when acknowledged
+                if event.user_id == self.user_id
+                    && room_data.confirm_sent_message(|id, _| id == Some(event.id))
+                {
+                    return;
+                }
 
-                room_data.messages.push(MessageBoxItem::Message {
-                    user_id: event.user_id.clone(),
-                    content: event.content.clone(),
-                });
+                room_data.push_message(MessageBoxItem::from(event));
 
                 if let Some(active_room) = self.active_room.as_ref() {
                     if !active_room.eq(&event.room) {
@@ -144,7 +360,253 @@ impl State {
                     }
                 }
             }
+            event::Event::DirectMessage(event) => {
+                let other_user_id = if event.from_user_id == self.user_id {
+                    &event.to_user_id
+                } else {
+                    &event.from_user_id
+                };
+                let key = direct_conversation_key(other_user_id);
+                let room_data = self
+                    .room_data_map
+                    .entry(key.clone())
+                    .or_insert_with(|| RoomData::new_direct(&self.user_id, other_user_id));
+                // direct messages have no ids, the echoes of the server come in the order we have sent them
+                if event.from_user_id == self.user_id
+                    && room_data.confirm_sent_message(|_, content| content == event.content)
+                {
+                    return;
+                }
+
+                room_data.push_message(MessageBoxItem::from(event));
+
+                if self.active_room.as_ref() != Some(&key) {
+                    room_data.has_unread = true;
+                }
+            }
+            event::Event::HistoryPage(event) => {
+                let Some(room_data) = self.room_data_map.get_mut(&event.room) else {
+                    return;
+                };
+                let oldest_message_id = room_data.oldest_message_id().unwrap_or(u64::MAX);
+
+                // only keep the messages older than what we hold, the page may overlap with them
+                for message in event
+                    .messages
+                    .iter()
+                    .rev()
+                    .filter(|message| message.id < oldest_message_id)
+                {
+                    room_data
+                        .scrollback
+                        .push_front(MessageBoxItem::from(message));
+                    room_data.prepended_count += 1;
+                }
+
+                room_data.has_more_history = event.has_more;
+                room_data.is_fetching_history = false;
+            }
+            event::Event::RoomCreated(event) => {
+                self.room_data_map
+                    .entry(event.room.clone())
+                    .or_insert_with(|| {
+                        RoomData::new(event.room.clone(), event.description.clone())
+                    });
+            }
+            event::Event::Moderation(event) => {
+                let Some(room_data) = self.room_data_map.get_mut(&event.room) else {
+                    return;
+                };
+
+                // our sessions have already been removed from the room by the server
+                if event.user_id == self.user_id
+                    && matches!(
+                        event.action,
+                        event::ModerationAction::Kicked | event::ModerationAction::Banned
+                    )
+                {
+                    room_data.has_joined = false;
+                    room_data.users.remove(&event.user_id);
+                }
+
+                room_data.push_message(MessageBoxItem::Notification(format!(
+                    "{} has been {} by {}",
+                    event.user_id,
+                    match &event.action {
+                        event::ModerationAction::Kicked => String::from("kicked"),
+                        event::ModerationAction::Banned => String::from("banned"),
+                        event::ModerationAction::Unbanned => String::from("unbanned"),
+                        event::ModerationAction::Muted { until } => format!(
+                            "muted until {}",
+                            until.with_timezone(&Local).format("%H:%M")
+                        ),
+                        event::ModerationAction::Unmuted => String::from("unmuted"),
+                        event::ModerationAction::RoleChanged { role } => match role {
+                            event::RoomRole::Owner => String::from("made the owner"),
+                            event::RoomRole::Operator => String::from("made an operator"),
+                            event::RoomRole::Member => String::from("made a member"),
+                        },
+                    },
+                    event.by_user_id
+                )));
+            }
+            event::Event::Error(event) => {
+                // a failed history fetch would otherwise block any further fetches of the room
+                if let Some(command::UserCommand::FetchHistory(cmd)) = event.command.as_ref() {
+                    if let Some(room_data) = self.room_data_map.get_mut(&cmd.room) {
+                        room_data.is_fetching_history = false;
+                    }
+                }
+
+                self.status_error = Some(StatusError {
+                    message: event.message.clone(),
+                    expires_at: self.timer + STATUS_ERROR_DURATION_SECS,
+                });
+            }
+            event::Event::Ack(event) => {
+                if let Some((message_id, delivery)) = self.find_pending_message(event.request_id) {
+                    *message_id = event.message_id;
+                    *delivery = DeliveryStatus::Sent;
+                }
+            }
+            event::Event::Nack(event) => {
+                if let Some((_, delivery)) = self.find_pending_message(event.request_id) {
+                    *delivery = DeliveryStatus::Failed;
+                }
+
+                self.status_error = Some(StatusError {
+                    message: event.message.clone(),
+                    expires_at: self.timer + STATUS_ERROR_DURATION_SECS,
+                });
+            }
+            event::Event::RateLimited(event) => {
+                if let Some((_, delivery)) = event
+                    .request_id
+                    .and_then(|request_id| self.find_pending_message(request_id))
+                {
+                    *delivery = DeliveryStatus::Failed;
+                }
+
+                let message = match event.muted_until {
+                    _ if event.disconnected => {
+                        "Disconnected by the server for flooding".to_string()
+                    }
+                    Some(until) => format!(
+                        "Muted for flooding until {}",
+                        until.with_timezone(&Local).format("%H:%M:%S")
+                    ),
+                    None => format!(
+                        "Sending too fast, retry in {:.1}s",
+                        event.retry_after_millis as f64 / 1000.0
+                    ),
+                };
+                self.status_error = Some(StatusError {
+                    message,
+                    expires_at: self.timer + STATUS_ERROR_DURATION_SECS,
+                });
+            }
+            event::Event::RoomLagged(event) => {
+                let Some(room_data) = self.room_data_map.get_mut(&event.room) else {
+                    return;
+                };
+
+                room_data.push_message(MessageBoxItem::Notification(if event.has_more {
+                    format!(
+                        "Fell behind the room and missed {} events, only the latest messages are replayed",
+                        event.missed_events
+                    )
+                } else {
+                    format!(
+                        "Fell behind the room and missed {} events, replaying the missed messages",
+                        event.missed_events
+                    )
+                }));
+
+                // the server only replays what we have not received, they are recorded like any new message
+                for message in &event.messages {
+                    self.handle_server_event(&event::Event::UserMessage(message.clone()));
+                }
+            }
+            event::Event::RoomDeleted(event) => {
+                self.room_data_map.remove(&event.room);
+
+                if self.active_room.as_ref() == Some(&event.room) {
+                    self.active_room = None;
+                }
+            }
+            event::Event::RoomList(event) => {
+                self.update_rooms(&event.rooms);
+            }
+        }
+    }
+
+    /// Brings the rooms in line with the given list of the server, keeping the data of the rooms we already know
+    fn update_rooms(&mut self, rooms: &[event::RoomDetail]) {
+        // direct conversations are not rooms, they are never part of the list
+        self.room_data_map.retain(|name, room_data| {
+            room_data.direct_user_id.is_some() || rooms.iter().any(|room| room.name == *name)
+        });
+
+        for room in rooms.iter() {
+            self.room_data_map
+                .entry(room.name.clone())
+                .and_modify(|room_data| room_data.description.clone_from(&room.description))
+                .or_insert_with(|| RoomData::new(room.name.clone(), room.description.clone()));
+        }
+
+        if self
+            .active_room
+            .as_ref()
+            .is_some_and(|active_room| !self.room_data_map.contains_key(active_room))
+        {
+            self.active_room = None;
+        }
+    }
+
+    /// Records a message we are sending to the given room or direct conversation,
+    /// until the server acknowledges the request with the given id
+    pub fn push_pending_message(&mut self, room: &str, request_id: u64, content: String) {
+        if let Some(room_data) = self.room_data_map.get_mut(room) {
+            room_data.push_message(MessageBoxItem::Message {
+                id: None,
+                timestamp: Utc::now(),
+                user_id: self.user_id.clone(),
+                content,
+                delivery: DeliveryStatus::Pending { request_id },
+            });
+        }
+    }
+
+    /// Finds the message sent with the given request id, returns its id and delivery status
+    fn find_pending_message(
+        &mut self,
+        request_id: u64,
+    ) -> Option<(&mut Option<u64>, &mut DeliveryStatus)> {
+        self.room_data_map
+            .values_mut()
+            .flat_map(|room_data| room_data.messages.iter_mut())
+            .find_map(|item| match item {
+                MessageBoxItem::Message { id, delivery, .. }
+                    if *delivery == (DeliveryStatus::Pending { request_id }) =>
+                {
+                    Some((id, delivery))
+                }
+                _ => None,
+            })
+    }
+
+    /// Marks a history fetch for the given room as in-flight.
+    /// Returns the id of the oldest message we hold, if older messages should be fetched.
+    pub fn start_history_fetch(&mut self, room: &str) -> Option<u64> {
+        let room_data = self.room_data_map.get_mut(room)?;
+        if !room_data.has_more_history || room_data.is_fetching_history {
+            return None;
         }
+
+        let oldest_message_id = room_data.oldest_message_id()?;
+        room_data.is_fetching_history = true;
+
+        Some(oldest_message_id)
     }
 
     pub fn mark_connection_request_start(&mut self) {
@@ -161,7 +623,49 @@ impl State {
         }
     }
 
+    /// Keeps the state of a session whose connection was lost, until the next attempt to connect again
+    pub fn mark_reconnecting(
+        &mut self,
+        addr: String,
+        failed_attempts: u32,
+        retry_in: Duration,
+        err: anyhow::Error,
+    ) {
+        self.server_connection_status = ServerConnectionStatus::Reconnecting {
+            addr,
+            failed_attempts,
+            retry_at: self.timer + retry_in.as_secs() as usize,
+            err: err.to_string(),
+        };
+    }
+
+    /// Shows the attempt to connect again as being made right now
+    pub fn mark_reconnection_attempt_start(&mut self) {
+        if let ServerConnectionStatus::Reconnecting { retry_at, .. } =
+            &mut self.server_connection_status
+        {
+            *retry_at = self.timer;
+        }
+    }
+
+    /// Shows an error in the status line for a few seconds
+    pub fn show_status_error(&mut self, message: String) {
+        self.status_error = Some(StatusError {
+            message,
+            expires_at: self.timer + STATUS_ERROR_DURATION_SECS,
+        });
+    }
+
     /// Tries to set the active room as the given room. Returns the [RoomData] associated to the room.
+    /// The active room, unless it is a direct message conversation
+    pub fn active_chat_room(&self) -> Option<&String> {
+        self.active_room.as_ref().filter(|room| {
+            self.room_data_map
+                .get(*room)
+                .is_some_and(|room_data| room_data.direct_user_id.is_none())
+        })
+    }
+
     pub fn try_set_active_room(&mut self, room: &str) -> Option<&RoomData> {
         let room_data = self.room_data_map.get_mut(room)?;
         room_data.has_unread = false;
@@ -173,5 +677,13 @@ impl State {
 
     pub fn tick_timer(&mut self) {
         self.timer += 1;
+
+        if self
+            .status_error
+            .as_ref()
+            .is_some_and(|status_error| status_error.expires_at <= self.timer)
+        {
+            self.status_error = None;
+        }
     }
 }
@@ -1,11 +1,13 @@
-use std::time::Duration;
+use std::{future::Future, pin::Pin, time::Duration};
 
 use anyhow::Context;
 use comms::{
-    command,
+    command::{self, Codec},
+    event,
     transport::{
         self,
         client::{CommandWriter, EventStream},
+        tls::{self, ServerTrust},
     },
 };
 use tokio::{
@@ -14,32 +16,476 @@ use tokio::{
         broadcast,
         mpsc::{self, UnboundedReceiver, UnboundedSender},
     },
+    time::Instant,
 };
 use tokio_stream::StreamExt;
 
 use crate::{Interrupted, Terminator};
 
-use super::{action::Action, State};
+/// How many older messages to request at once while scrolling back
+const HISTORY_PAGE_SIZE: usize = 50;
+/// Server addresses starting with this prefix are paths of Unix domain sockets
+const UNIX_SOCKET_ADDR_PREFIX: &str = "unix:";
+/// How long to wait before connecting again once the connection is lost, doubled after every failed attempt
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The longest wait between two attempts to connect again
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How long an attempt to connect again may take before it is given up
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+use super::{
+    action::{Action, AuthMode, Moderation},
+    direct_conversation_key, State,
+};
 
 pub struct StateStore {
     state_tx: UnboundedSender<State>,
+    /// Connections are encrypted with TLS when the server certificate can be trusted one way or another
+    server_trust: Option<ServerTrust>,
+    /// The codec selected for every connection to the server
+    codec: Codec,
 }
 
 impl StateStore {
-    pub fn new() -> (Self, UnboundedReceiver<State>) {
+    pub fn new(
+        server_trust: Option<ServerTrust>,
+        codec: Codec,
+    ) -> (Self, UnboundedReceiver<State>) {
         let (state_tx, state_rx) = mpsc::unbounded_channel::<State>();
 
-        (StateStore { state_tx }, state_rx)
+        (
+            StateStore {
+                state_tx,
+                server_trust,
+                codec,
+            },
+            state_rx,
+        )
     }
 }
 
 type ServerHandle = (EventStream, CommandWriter);
 
-async fn create_server_handle(addr: &str) -> anyhow::Result<ServerHandle> {
+/// An attempt to connect again which is in progress, see [reconnect]
+type Reconnection<'a> =
+    Pin<Box<dyn Future<Output = anyhow::Result<(ServerHandle, event::Event)>> + Send + 'a>>;
+
+/// What it takes to pick the session up again on a new connection, once the connection to the server is lost
+#[derive(Clone)]
+struct Session {
+    addr: String,
+    username: String,
+    password: String,
+    /// The id of the session on the server, it is resumed instead of logging in again while the server keeps it
+    session_id: String,
+    /// How many events have been received since logging in or resuming, the server numbers them the same way
+    last_seen_seq: u64,
+    /// How many attempts to connect again have failed since the connection was lost
+    failed_attempts: u32,
+    /// When to make the next attempt to connect again
+    retry_at: Instant,
+}
+
+impl Session {
+    fn new(addr: String, username: String, password: String) -> Self {
+        Session {
+            addr,
+            username,
+            password,
+            session_id: String::new(),
+            last_seen_seq: 0,
+            failed_attempts: 0,
+            retry_at: Instant::now(),
+        }
+    }
+
+    /// Starts counting the events of the session the given event has logged in or resumed
+    fn start(&mut self, event: &event::Event) {
+        match event {
+            event::Event::LoginSuccessful(event) => {
+                self.session_id.clone_from(&event.session_id);
+                self.last_seen_seq = 0;
+            }
+            event::Event::Resumed(event) => {
+                self.last_seen_seq = event.seq;
+            }
+            _ => (),
+        }
+
+        self.failed_attempts = 0;
+    }
+
+    /// Schedules the next attempt to connect again, backing off exponentially with the number of failed attempts
+    fn schedule_reconnection(&mut self, state: &mut State, err: anyhow::Error) {
+        let retry_in = INITIAL_RECONNECT_DELAY
+            .saturating_mul(2u32.saturating_pow(self.failed_attempts))
+            .min(MAX_RECONNECT_DELAY);
+        self.retry_at = Instant::now() + retry_in;
+
+        state.mark_reconnecting(self.addr.clone(), self.failed_attempts, retry_in, err);
+    }
+}
+
+/// Connects to the server over TCP, or over a Unix domain socket for `unix:/path` addresses
+/// TCP connections are encrypted with TLS when the server certificate can be trusted, local ones never are
+async fn connect(
+    addr: &str,
+    server_trust: Option<&ServerTrust>,
+    codec: Codec,
+) -> anyhow::Result<ServerHandle> {
+    if let Some(path) = addr.strip_prefix(UNIX_SOCKET_ADDR_PREFIX) {
+        return connect_unix(path, codec).await;
+    }
+
     let stream = TcpStream::connect(addr).await?;
-    let (event_stream, command_writer) = transport::client::split_tcp_stream(stream);
 
-    Ok((event_stream, command_writer))
+    match server_trust {
+        Some(server_trust) => {
+            let stream = tls::connector(server_trust)?
+                .connect(tls::server_name(addr)?, stream)
+                .await
+                .context("TLS handshake failed")?;
+
+            transport::client::connect_stream(stream, codec).await
+        }
+        None => transport::client::connect_stream(stream, codec).await,
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str, codec: Codec) -> anyhow::Result<ServerHandle> {
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .with_context(|| format!("could not connect to unix socket {}", path))?;
+
+    transport::client::connect_stream(stream, codec).await
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &str, _codec: Codec) -> anyhow::Result<ServerHandle> {
+    Err(anyhow::anyhow!(
+        "unix sockets are not supported on this platform"
+    ))
+}
+
+/// Introduces the client to the server, switching on every capability the client knows about
+///
+/// Fails with an explanation of which side has to be upgraded when the server does not speak the protocol version of the client
+async fn say_hello(
+    event_stream: &mut EventStream,
+    command_writer: &mut CommandWriter,
+) -> anyhow::Result<()> {
+    command_writer
+        .write(
+            &command::UserCommand::Hello(command::HelloCommand {
+                protocol_version: command::PROTOCOL_VERSION,
+                client_name: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+                capabilities: command::Capability::ALL.to_vec(),
+            })
+            .into(),
+        )
+        .await
+        .context("could not say hello")?;
+
+    match event_stream.next().await {
+        Some(Ok(event::Event::Hello(event)))
+            if event
+                .supported_versions
+                .contains(&command::PROTOCOL_VERSION) =>
+        {
+            Ok(())
+        }
+        Some(Ok(event::Event::Hello(event))) => {
+            let upgrade = match event.supported_versions.iter().max() {
+                Some(version) if *version > command::PROTOCOL_VERSION => "client",
+                _ => "server",
+            };
+
+            Err(anyhow::anyhow!(
+                "the server speaks protocol versions {:?} but the client speaks {}, please upgrade the {}",
+                event.supported_versions,
+                command::PROTOCOL_VERSION,
+                upgrade
+            ))
+        }
+        Some(Ok(event::Event::Error(_))) => Err(anyhow::anyhow!(
+            "the server does not know the protocol version handshake, please upgrade the server"
+        )),
+        Some(Ok(_)) => Err(anyhow::anyhow!("unexpected event from the server")),
+        Some(Err(err)) => Err(err),
+        None => Err(anyhow::anyhow!("server closed the connection")),
+    }
+}
+
+/// Sends the given command to authenticate and waits for the server to accept it
+///
+/// # Returns
+///
+/// - The event which accepted the command, either the login successful or the resumed one
+async fn authenticate(
+    event_stream: &mut EventStream,
+    command_writer: &mut CommandWriter,
+    command: command::UserCommand,
+) -> anyhow::Result<event::Event> {
+    command_writer
+        .write(&command.into())
+        .await
+        .context("could not send credentials")?;
+
+    match event_stream.next().await {
+        Some(Ok(event @ (event::Event::LoginSuccessful(_) | event::Event::Resumed(_)))) => {
+            Ok(event)
+        }
+        Some(Ok(event::Event::LoginFailed(event))) => Err(anyhow::anyhow!(event.reason)),
+        Some(Ok(_)) => Err(anyhow::anyhow!("unexpected event from the server")),
+        Some(Err(err)) => Err(err),
+        None => Err(anyhow::anyhow!("server closed the connection")),
+    }
+}
+
+/// Connects to the server and authenticates with the given credentials
+///
+/// # Returns
+///
+/// - The server handle alongside with the login successful event which welcomed the user
+async fn create_server_handle(
+    addr: &str,
+    server_trust: Option<&ServerTrust>,
+    codec: Codec,
+    username: String,
+    password: String,
+    auth_mode: AuthMode,
+) -> anyhow::Result<(ServerHandle, event::Event)> {
+    let (mut event_stream, mut command_writer) = connect(addr, server_trust, codec).await?;
+    say_hello(&mut event_stream, &mut command_writer).await?;
+
+    let event = authenticate(
+        &mut event_stream,
+        &mut command_writer,
+        match auth_mode {
+            AuthMode::Login => {
+                command::UserCommand::Login(command::LoginCommand { username, password })
+            }
+            AuthMode::Register => {
+                command::UserCommand::Register(command::RegisterCommand { username, password })
+            }
+        },
+    )
+    .await?;
+
+    Ok(((event_stream, command_writer), event))
+}
+
+/// Connects to the server again after the connection was lost and resumes the session,
+/// or logs in again and joins the given rooms again once the server no longer keeps the session
+///
+/// # Returns
+///
+/// - The server handle alongside with the resumed or the login successful event which picked the session up
+async fn reconnect(
+    server_trust: Option<&ServerTrust>,
+    codec: Codec,
+    session: Session,
+    joined_rooms: Vec<String>,
+) -> anyhow::Result<(ServerHandle, event::Event)> {
+    let (mut event_stream, mut command_writer) =
+        connect(&session.addr, server_trust, codec).await?;
+    say_hello(&mut event_stream, &mut command_writer).await?;
+
+    if let Ok(event) = authenticate(
+        &mut event_stream,
+        &mut command_writer,
+        command::UserCommand::Resume(command::ResumeCommand {
+            session_id: session.session_id,
+            last_seen_seq: session.last_seen_seq,
+        }),
+    )
+    .await
+    {
+        return Ok(((event_stream, command_writer), event));
+    }
+
+    let event = authenticate(
+        &mut event_stream,
+        &mut command_writer,
+        command::UserCommand::Login(command::LoginCommand {
+            username: session.username,
+            password: session.password,
+        }),
+    )
+    .await?;
+
+    // the new session is in no room, the ones deleted meanwhile can not be joined again
+    if let event::Event::LoginSuccessful(login_event) = &event {
+        for room in joined_rooms
+            .into_iter()
+            .filter(|room| login_event.rooms.iter().any(|detail| detail.name == *room))
+        {
+            command_writer
+                .write(&command::UserCommand::JoinRoom(command::JoinRoomCommand { room }).into())
+                .await
+                .context("could not join room")?;
+        }
+    }
+
+    Ok(((event_stream, command_writer), event))
+}
+
+/// Sends the commands the given action of the UI calls for, noting what is expected back in the state
+async fn send_action(
+    command_writer: &mut CommandWriter,
+    state: &mut State,
+    last_request_id: &mut u64,
+    action: Action,
+) -> anyhow::Result<()> {
+    match action {
+        Action::SendMessage { content } => {
+            if let Some(active_room) = state.active_room.clone() {
+                let direct_user_id = state
+                    .room_data_map
+                    .get(&active_room)
+                    .and_then(|room_data| room_data.direct_user_id.clone());
+                *last_request_id += 1;
+                let request_id = *last_request_id;
+                state.push_pending_message(&active_room, request_id, content.clone());
+
+                command_writer
+                    .write(&command::UserCommandEnvelope {
+                        command: match direct_user_id {
+                            Some(to_user_id) => command::UserCommand::SendDirectMessage(
+                                command::SendDirectMessageCommand {
+                                    to_user_id,
+                                    content,
+                                },
+                            ),
+                            None => {
+                                command::UserCommand::SendMessage(command::SendMessageCommand {
+                                    room: active_room,
+                                    content,
+                                })
+                            }
+                        },
+                        request_id: Some(request_id),
+                    })
+                    .await
+                    .context("could not send message")?;
+            }
+        }
+        Action::SendDirectMessage {
+            to_user_id,
+            content,
+        } => {
+            *last_request_id += 1;
+            let request_id = *last_request_id;
+            state.push_pending_message(
+                &direct_conversation_key(&to_user_id),
+                request_id,
+                content.clone(),
+            );
+
+            command_writer
+                .write(&command::UserCommandEnvelope {
+                    command: command::UserCommand::SendDirectMessage(
+                        command::SendDirectMessageCommand {
+                            to_user_id,
+                            content,
+                        },
+                    ),
+                    request_id: Some(request_id),
+                })
+                .await
+                .context("could not send direct message")?;
+        }
+        Action::SelectRoom { room } => {
+            if let Some(false) = state
+                .try_set_active_room(room.as_str())
+                .map(|room_data| room_data.has_joined)
+            {
+                command_writer
+                    .write(
+                        &command::UserCommand::JoinRoom(command::JoinRoomCommand { room }).into(),
+                    )
+                    .await
+                    .context("could not join room")?;
+            }
+        }
+        Action::FetchHistory { room } => {
+            if let Some(before_message_id) = state.start_history_fetch(&room) {
+                command_writer
+                    .write(
+                        &command::UserCommand::FetchHistory(command::FetchHistoryCommand {
+                            room,
+                            before_message_id: Some(before_message_id),
+                            limit: HISTORY_PAGE_SIZE,
+                        })
+                        .into(),
+                    )
+                    .await
+                    .context("could not fetch history")?;
+            }
+        }
+        Action::CreateRoom { name, description } => {
+            command_writer
+                .write(
+                    &command::UserCommand::CreateRoom(command::CreateRoomCommand {
+                        room: name,
+                        description,
+                    })
+                    .into(),
+                )
+                .await
+                .context("could not create room")?;
+        }
+        Action::DeleteRoom { room } => {
+            command_writer
+                .write(
+                    &command::UserCommand::DeleteRoom(command::DeleteRoomCommand { room }).into(),
+                )
+                .await
+                .context("could not delete room")?;
+        }
+        Action::Moderate {
+            user_id,
+            moderation,
+        } => {
+            if let Some(room) = state.active_chat_room().cloned() {
+                command_writer
+                    .write(
+                        &match moderation {
+                            Moderation::Kick => {
+                                command::UserCommand::Kick(command::KickCommand { room, user_id })
+                            }
+                            Moderation::Ban => {
+                                command::UserCommand::Ban(command::BanCommand { room, user_id })
+                            }
+                            Moderation::Unban => {
+                                command::UserCommand::Unban(command::UnbanCommand { room, user_id })
+                            }
+                            Moderation::Mute { duration_secs } => {
+                                command::UserCommand::Mute(command::MuteCommand {
+                                    room,
+                                    user_id,
+                                    duration_secs,
+                                })
+                            }
+                            Moderation::SetRole { role } => {
+                                command::UserCommand::SetRole(command::SetRoleCommand {
+                                    room,
+                                    user_id,
+                                    role,
+                                })
+                            }
+                        }
+                        .into(),
+                    )
+                    .await
+                    .context("could not moderate user")?;
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
 }
 
 impl StateStore {
@@ -50,62 +496,119 @@ impl StateStore {
         mut interrupt_rx: broadcast::Receiver<Interrupted>,
     ) -> anyhow::Result<Interrupted> {
         let mut opt_server_handle: Option<ServerHandle> = None;
+        // kept while logged in, to pick the session up again once the connection is lost
+        let mut opt_session: Option<Session> = None;
+        // polled alongside the UI while reconnecting, so that the user can still give up or quit
+        let mut opt_reconnection: Option<Reconnection> = None;
         let mut state = State::default();
 
         // the initial state once
         self.state_tx.send(state.clone())?;
 
         let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        // messages are sent with increasing request ids to track their delivery
+        let mut last_request_id: u64 = 0;
 
         let result = loop {
-            if let Some((event_stream, command_writer)) = opt_server_handle.as_mut() {
+            if let (Some((event_stream, command_writer)), Some(session)) =
+                (opt_server_handle.as_mut(), opt_session.as_mut())
+            {
                 tokio::select! {
                     // Handle the server events as they come in
                     maybe_event = event_stream.next() => match maybe_event {
                         Some(Ok(event)) => {
+                            session.last_seen_seq += 1;
                             state.handle_server_event(&event);
                         },
-                        // server disconnected, we need to reset the state
+                        // server disconnected, we keep the state and try to pick the session up again
                         None => {
                             opt_server_handle = None;
-                            state = State::default();
+                            session.schedule_reconnection(&mut state, anyhow::anyhow!("server closed the connection"));
                         },
                         _ => (),
                     },
                     // Handle the actions coming from the UI
                     // and process them to do async operations
                     Some(action) = action_rx.recv() => match action {
-                        Action::SendMessage { content } => {
-                            if let Some(active_room) = state.active_room.as_ref() {
-                                command_writer
-                                    .write(&command::UserCommand::SendMessage(
-                                        command::SendMessageCommand {
-                                            room: active_room.clone(),
-                                            content,
-                                        },
-                                    ))
-                                    .await
-                                    .context("could not send message")?;
+                        Action::Exit => {
+                            let _ = terminator.terminate(Interrupted::UserInt);
+
+                            break Interrupted::UserInt;
+                        },
+                        action => {
+                            if let Err(err) = send_action(command_writer, &mut state, &mut last_request_id, action).await {
+                                opt_server_handle = None;
+                                session.schedule_reconnection(&mut state, err);
                             }
                         },
-                        Action::SelectRoom { room } => {
-                            if let Some(false) = state.try_set_active_room(room.as_str()).map(|room_data| room_data.has_joined) {
-                                command_writer
-                                    .write(&command::UserCommand::JoinRoom(command::JoinRoomCommand {
-                                        room,
-                                    }))
-                                    .await
-                                    .context("could not join room")?;
+                    },
+                    // Tick to terminate the select every N milliseconds
+                    _ = ticker.tick() => {
+                        state.tick_timer();
+                    },
+                    // Catch and handle interrupt signal to gracefully shutdown
+                    Ok(interrupted) = interrupt_rx.recv() => {
+                        break interrupted;
+                    }
+                }
+            } else if let Some(session) = opt_session.as_mut() {
+                tokio::select! {
+                    // Try to connect again once we have waited long enough since the last attempt
+                    _ = tokio::time::sleep_until(session.retry_at), if opt_reconnection.is_none() => {
+                        state.mark_reconnection_attempt_start();
+
+                        let joined_rooms = state
+                            .room_data_map
+                            .values()
+                            .filter(|room_data| room_data.has_joined && room_data.direct_user_id.is_none())
+                            .map(|room_data| room_data.name.clone())
+                            .collect();
+                        let reconnection = tokio::time::timeout(
+                            RECONNECT_TIMEOUT,
+                            reconnect(self.server_trust.as_ref(), self.codec, session.clone(), joined_rooms),
+                        );
+                        opt_reconnection = Some(Box::pin(async move {
+                            reconnection.await.unwrap_or_else(|_| Err(anyhow::anyhow!("server did not answer in time")))
+                        }));
+                    },
+                    result = async { opt_reconnection.as_mut().expect("the branch is disabled without a reconnection").await }, if opt_reconnection.is_some() => {
+                        opt_reconnection = None;
+
+                        match result {
+                            Ok((server_handle, event)) => {
+                                session.start(&event);
+                                let _ = opt_server_handle.insert(server_handle);
+                                // logging in again fails the messages still pending from the lost session
+                                state.handle_server_event(&event);
+                                state.process_connection_request_result(Ok(session.addr.clone()));
+                            },
+                            Err(err) => {
+                                session.failed_attempts += 1;
+                                session.schedule_reconnection(&mut state, err);
                             }
+                        }
+                    },
+                    Some(action) = action_rx.recv() => match action {
+                        Action::GiveUpReconnecting => {
+                            // the attempt in progress is dropped along with its connection
+                            opt_reconnection = None;
+                            opt_session = None;
+                            state = State::default();
+                        },
+                        // rooms can still be browsed, they are joined once connected again
+                        Action::SelectRoom { room } => {
+                            state.try_set_active_room(room.as_str());
                         },
                         Action::Exit => {
                             let _ = terminator.terminate(Interrupted::UserInt);
 
                             break Interrupted::UserInt;
                         },
-                        _ => (),
+                        Action::None => (),
+                        _ => {
+                            state.show_status_error(String::from("Not connected to the server, try again once reconnected"));
+                        },
                     },
-                    // Tick to terminate the select every N milliseconds
                     _ = ticker.tick() => {
                         state.tick_timer();
                     },
@@ -117,15 +620,19 @@ impl StateStore {
             } else {
                 tokio::select! {
                     Some(action) = action_rx.recv() => match action {
-                        Action::ConnectToServerRequest { addr } => {
+                        Action::ConnectToServerRequest { addr, username, password, auth_mode } => {
                             state.mark_connection_request_start();
                             // emit event to re-render any part depending on the connection status
                             self.state_tx.send(state.clone())?;
 
-                            match create_server_handle(&addr).await {
-                                Ok(server_handle) => {
+                            let mut session = Session::new(addr.clone(), username.clone(), password.clone());
+                            match create_server_handle(&addr, self.server_trust.as_ref(), self.codec, username, password, auth_mode).await {
+                                Ok((server_handle, login_event)) => {
                                     // set the server handle and change status for further processing
                                     let _ = opt_server_handle.insert(server_handle);
+                                    session.start(&login_event);
+                                    let _ = opt_session.insert(session);
+                                    state.handle_server_event(&login_event);
                                     state.process_connection_request_result(Ok(addr));
                                     // ticker needs to be resetted to avoid showing time spent inputting and connecting to the server address
                                     ticker.reset();
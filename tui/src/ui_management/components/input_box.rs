@@ -122,11 +122,18 @@ pub struct RenderProps {
     pub area: Rect,
     pub border_color: Color,
     pub show_cursor: bool,
+    /// Renders every character as '*', e.g. for passwords
+    pub is_secret: bool,
 }
 
 impl ComponentRender<RenderProps> for InputBox {
     fn render<B: Backend>(&self, frame: &mut Frame<B>, props: RenderProps) {
-        let input = Paragraph::new(self.text.as_str())
+        let text = if props.is_secret {
+            "*".repeat(self.text.chars().count())
+        } else {
+            self.text.clone()
+        };
+        let input = Paragraph::new(text)
             .style(Style::default().fg(Color::Yellow))
             .block(
                 Block::default()
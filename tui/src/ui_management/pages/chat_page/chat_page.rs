@@ -3,11 +3,12 @@ use std::collections::HashMap;
 use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use ratatui::{prelude::*, widgets::*, Frame};
 
-use crate::state_store::{action::Action, MessageBoxItem, RoomData, State};
+use crate::state_store::{action::Action, RoomData, ServerConnectionStatus, State};
 
 use super::{
     components::{
         message_input_box::{self, MessageInputBox},
+        message_list::{self, MessageList},
         room_list::{self, RoomList},
     },
     section::{
@@ -21,15 +22,17 @@ use crate::ui_management::components::{Component, ComponentRender};
 pub enum Section {
     MessageInput,
     RoomList,
+    MessageList,
 }
 
 impl Section {
-    pub const COUNT: usize = 2;
+    pub const COUNT: usize = 3;
 
     fn to_usize(&self) -> usize {
         match self {
             Section::MessageInput => 0,
             Section::RoomList => 1,
+            Section::MessageList => 2,
         }
     }
 }
@@ -41,6 +44,7 @@ impl TryFrom<usize> for Section {
         match value {
             0 => Ok(Section::MessageInput),
             1 => Ok(Section::RoomList),
+            2 => Ok(Section::MessageList),
             _ => Err(()),
         }
     }
@@ -55,6 +59,10 @@ struct Props {
     timer: usize,
     /// The room data map
     room_data_map: HashMap<String, RoomData>,
+    /// The most recent error reported by the server
+    status_error: Option<String>,
+    /// How picking the session up again is going, while the connection to the server is lost
+    reconnection_status: Option<String>,
 }
 
 impl From<&State> for Props {
@@ -64,6 +72,34 @@ impl From<&State> for Props {
             active_room: state.active_room.clone(),
             timer: state.timer,
             room_data_map: state.room_data_map.clone(),
+            status_error: state
+                .status_error
+                .as_ref()
+                .map(|status_error| status_error.message.clone()),
+            reconnection_status: match &state.server_connection_status {
+                ServerConnectionStatus::Reconnecting {
+                    addr,
+                    failed_attempts,
+                    retry_at,
+                    err,
+                } if *retry_at > state.timer => Some(format!(
+                    "Connection to {} lost ({}), attempt {} in {} secs…",
+                    addr,
+                    err,
+                    failed_attempts + 1,
+                    retry_at - state.timer
+                )),
+                ServerConnectionStatus::Reconnecting {
+                    addr,
+                    failed_attempts,
+                    ..
+                } => Some(format!(
+                    "Reconnecting to {}, attempt {}…",
+                    addr,
+                    failed_attempts + 1
+                )),
+                _ => None,
+            },
         }
     }
 }
@@ -84,6 +120,8 @@ pub struct ChatPage {
     pub room_list: RoomList,
     /// The input box widget that handles the message input
     pub message_input_box: MessageInputBox,
+    /// The message list widget that handles scrolling through the messages
+    pub message_list: MessageList,
 }
 
 impl ChatPage {
@@ -97,6 +135,7 @@ impl ChatPage {
             // child components
             room_list: RoomList::new(state),
             message_input_box: MessageInputBox::new(state),
+            message_list: MessageList::new(state),
         }
     }
 
@@ -108,6 +147,7 @@ impl ChatPage {
         match section {
             Section::MessageInput => &self.message_input_box,
             Section::RoomList => &self.room_list,
+            Section::MessageList => &self.message_list,
         }
     }
 
@@ -115,6 +155,7 @@ impl ChatPage {
         match section {
             Section::MessageInput => &mut self.message_input_box,
             Section::RoomList => &mut self.room_list,
+            Section::MessageList => &mut self.message_list,
         }
     }
 
@@ -125,6 +166,7 @@ impl ChatPage {
         match section {
             Section::MessageInput => &mut self.message_input_box,
             Section::RoomList => &mut self.room_list,
+            Section::MessageList => &mut self.message_list,
         }
     }
 
@@ -165,6 +207,7 @@ impl Component for ChatPage {
         self.props = Props::from(state);
         self.room_list.update_from_state(state);
         self.message_input_box.update_from_state(state);
+        self.message_list.update_from_state(state);
     }
 
     fn name(&self) -> &str {
@@ -197,6 +240,9 @@ impl Component for ChatPage {
                     Action::None
                 }
                 KeyCode::Char('q') => Action::Exit,
+                KeyCode::Char('d') if self.props.reconnection_status.is_some() => {
+                    Action::GiveUpReconnecting
+                }
                 KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Action::Exit,
                 _ => Action::None,
             },
@@ -224,7 +270,7 @@ impl Component for ChatPage {
     }
 }
 
-const NO_ROOM_SELECTED_MESSAGE: &str = "Join at least one room to start chatting!";
+pub(super) const NO_ROOM_SELECTED_MESSAGE: &str = "Join at least one room to start chatting!";
 
 fn calculate_list_offset(height: u16, items_len: usize) -> usize {
     // go back by (container height + 2 for borders) to get the offset
@@ -275,19 +321,21 @@ impl ComponentRender<()> for ChatPage {
         );
         frame.render_widget(user_info, container_user_info);
 
-        let [container_highlight, container_messages, container_input] = *Layout::default()
-            .direction(Direction::Vertical)
-            .constraints(
-                [
-                    Constraint::Length(3),
-                    Constraint::Min(1),
-                    Constraint::Length(3),
-                ]
-                .as_ref(),
-            )
-            .split(middle)
+        let [container_highlight, container_messages, container_input, container_status] =
+            *Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [
+                        Constraint::Length(3),
+                        Constraint::Min(1),
+                        Constraint::Length(3),
+                        Constraint::Length(1),
+                    ]
+                    .as_ref(),
+                )
+                .split(middle)
         else {
-            panic!("The middle layout should have 3 chunks")
+            panic!("The middle layout should have 4 chunks")
         };
 
         let top_line = if let Some(room_data) = self
@@ -296,12 +344,20 @@ impl ComponentRender<()> for ChatPage {
             .as_ref()
             .and_then(|active_room| self.get_room_data(active_room))
         {
-            Line::from(vec![
-                "on ".into(),
-                Span::from(format!("#{}", room_data.name)).bold(),
-                " for ".into(),
-                Span::from(format!(r#""{}""#, room_data.description)).italic(),
-            ])
+            if room_data.direct_user_id.is_some() {
+                Line::from(vec![
+                    "with ".into(),
+                    Span::from(room_data.name.clone()).bold(),
+                    " in a direct conversation".into(),
+                ])
+            } else {
+                Line::from(vec![
+                    "on ".into(),
+                    Span::from(format!("#{}", room_data.name)).bold(),
+                    " for ".into(),
+                    Span::from(format!(r#""{}""#, room_data.description)).italic(),
+                ])
+            }
         } else {
             Line::from(NO_ROOM_SELECTED_MESSAGE)
         };
@@ -314,38 +370,13 @@ impl ComponentRender<()> for ChatPage {
         );
         frame.render_widget(help_message, container_highlight);
 
-        let messages = if let Some(active_room) = self.props.active_room.as_ref() {
-            self.get_room_data(active_room)
-                .map(|room_data| {
-                    let message_offset =
-                        calculate_list_offset(container_messages.height, room_data.messages.len());
-
-                    room_data
-                        .messages
-                        .asc_iter()
-                        .skip(message_offset)
-                        .map(|mbi| {
-                            let line = match mbi {
-                                MessageBoxItem::Message { user_id, content } => {
-                                    Line::from(Span::raw(format!("@{}: {}", user_id, content)))
-                                }
-                                MessageBoxItem::Notification(content) => {
-                                    Line::from(Span::raw(content.clone()).italic())
-                                }
-                            };
-
-                            ListItem::new(line)
-                        })
-                        .collect::<Vec<ListItem>>()
-                })
-                .unwrap_or_default()
-        } else {
-            vec![ListItem::new(Line::from(NO_ROOM_SELECTED_MESSAGE))]
-        };
-
-        let messages =
-            List::new(messages).block(Block::default().borders(Borders::ALL).title("Messages"));
-        frame.render_widget(messages, container_messages);
+        self.message_list.render(
+            frame,
+            message_list::RenderProps {
+                border_color: self.calculate_border_color(Section::MessageList),
+                area: container_messages,
+            },
+        );
 
         self.message_input_box.render(
             frame,
@@ -360,6 +391,17 @@ impl ComponentRender<()> for ChatPage {
             },
         );
 
+        // the connection being lost matters more than any error reported over it
+        if let Some(reconnection_status) = self.props.reconnection_status.as_ref() {
+            let status_line = Paragraph::new(Line::from(reconnection_status.as_str()))
+                .style(Style::default().fg(Color::Yellow));
+            frame.render_widget(status_line, container_status);
+        } else if let Some(status_error) = self.props.status_error.as_ref() {
+            let status_line = Paragraph::new(Line::from(status_error.as_str()))
+                .style(Style::default().fg(Color::Red));
+            frame.render_widget(status_line, container_status);
+        }
+
         let [container_room_users, container_usage] = *Layout::default()
             .direction(Direction::Vertical)
             .constraints([Constraint::Min(1), Constraint::Length(10)].as_ref())
@@ -416,30 +458,39 @@ impl HasUsageInfo for ChatPage {
             let handler: &dyn HasUsageInfo = match section {
                 Section::RoomList => &self.room_list,
                 Section::MessageInput => &self.message_input_box,
+                Section::MessageList => &self.message_list,
             };
 
             handler.usage_info()
         } else {
+            let mut lines = vec![
+                UsageInfoLine {
+                    keys: vec!["q".into()],
+                    description: "to exit".into(),
+                },
+                UsageInfoLine {
+                    keys: vec!["←".into(), "→".into()],
+                    description: "to hover widgets".into(),
+                },
+                UsageInfoLine {
+                    keys: vec!["e".into()],
+                    description: format!(
+                        "to activate {}",
+                        self.get_component_for_section(&self.last_hovered_section)
+                            .name()
+                    ),
+                },
+            ];
+            if self.props.reconnection_status.is_some() {
+                lines.push(UsageInfoLine {
+                    keys: vec!["d".into()],
+                    description: "to give up reconnecting".into(),
+                });
+            }
+
             UsageInfo {
                 description: Some("Select a widget".into()),
-                lines: vec![
-                    UsageInfoLine {
-                        keys: vec!["q".into()],
-                        description: "to exit".into(),
-                    },
-                    UsageInfoLine {
-                        keys: vec!["←".into(), "→".into()],
-                        description: "to hover widgets".into(),
-                    },
-                    UsageInfoLine {
-                        keys: vec!["e".into()],
-                        description: format!(
-                            "to activate {}",
-                            self.get_component_for_section(&self.last_hovered_section)
-                                .name()
-                        ),
-                    },
-                ],
+                lines,
             }
         }
     }
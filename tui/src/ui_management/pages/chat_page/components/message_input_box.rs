@@ -10,8 +10,13 @@ use crate::ui_management::components::{
     input_box::{self, InputBox},
     Component, ComponentRender,
 };
+use comms::event::RoomRole;
+
 use crate::{
-    state_store::{action::Action, State},
+    state_store::{
+        action::{Action, Moderation},
+        State,
+    },
     ui_management::pages::chat_page::section::SectionActivation,
 };
 
@@ -46,15 +51,133 @@ impl MessageInputBox {
     fn submit_message(&mut self) -> Action {
         let mut ret = Action::None;
         if !self.input_box.is_empty() {
-            ret = Action::SendMessage {
-                content: String::from(self.input_box.text()),
-            };
+            ret =
+                parse_slash_command(self.input_box.text()).unwrap_or_else(|| Action::SendMessage {
+                    content: String::from(self.input_box.text()),
+                });
             self.input_box.reset();
         }
         ret
     }
 }
 
+const DIRECT_MESSAGE_PREFIX: &str = "/msg ";
+const CREATE_ROOM_PREFIX: &str = "/create ";
+const DELETE_ROOM_PREFIX: &str = "/delete ";
+const KICK_PREFIX: &str = "/kick ";
+const BAN_PREFIX: &str = "/ban ";
+const UNBAN_PREFIX: &str = "/unban ";
+const MUTE_PREFIX: &str = "/mute ";
+const OP_PREFIX: &str = "/op ";
+const DEOP_PREFIX: &str = "/deop ";
+
+/// Parses an input starting with one of the supported slash commands into its action,
+/// anything else is sent as a plain message
+fn parse_slash_command(text: &str) -> Option<Action> {
+    if let Some(args) = text.strip_prefix(DIRECT_MESSAGE_PREFIX) {
+        parse_direct_message(args)
+    } else if let Some(args) = text.strip_prefix(CREATE_ROOM_PREFIX) {
+        parse_create_room(args)
+    } else if let Some(args) = text.strip_prefix(DELETE_ROOM_PREFIX) {
+        parse_delete_room(args)
+    } else if let Some(args) = text.strip_prefix(KICK_PREFIX) {
+        parse_moderation(args, Moderation::Kick)
+    } else if let Some(args) = text.strip_prefix(BAN_PREFIX) {
+        parse_moderation(args, Moderation::Ban)
+    } else if let Some(args) = text.strip_prefix(UNBAN_PREFIX) {
+        parse_moderation(args, Moderation::Unban)
+    } else if let Some(args) = text.strip_prefix(MUTE_PREFIX) {
+        parse_mute(args)
+    } else if let Some(args) = text.strip_prefix(OP_PREFIX) {
+        parse_moderation(
+            args,
+            Moderation::SetRole {
+                role: RoomRole::Operator,
+            },
+        )
+    } else if let Some(args) = text.strip_prefix(DEOP_PREFIX) {
+        parse_moderation(
+            args,
+            Moderation::SetRole {
+                role: RoomRole::Member,
+            },
+        )
+    } else {
+        None
+    }
+}
+
+/// Parses `<user> <content>` into a direct message action
+fn parse_direct_message(args: &str) -> Option<Action> {
+    let (to_user_id, content) = args.trim_start().split_once(' ')?;
+    let to_user_id = to_user_id.trim_start_matches('@');
+
+    if to_user_id.is_empty() || content.trim().is_empty() {
+        return None;
+    }
+
+    Some(Action::SendDirectMessage {
+        to_user_id: String::from(to_user_id),
+        content: String::from(content),
+    })
+}
+
+/// Parses `<name> [description]` into a create room action
+fn parse_create_room(args: &str) -> Option<Action> {
+    let args = args.trim();
+    let (name, description) = args.split_once(' ').unwrap_or((args, ""));
+    let name = name.trim_start_matches('#');
+
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(Action::CreateRoom {
+        name: String::from(name),
+        description: String::from(description.trim()),
+    })
+}
+
+/// Parses `<name>` into a delete room action
+fn parse_delete_room(args: &str) -> Option<Action> {
+    let room = args.trim().trim_start_matches('#');
+
+    if room.is_empty() || room.contains(' ') {
+        return None;
+    }
+
+    Some(Action::DeleteRoom {
+        room: String::from(room),
+    })
+}
+
+/// Parses `<user>` into a moderation action against the user
+fn parse_moderation(args: &str, moderation: Moderation) -> Option<Action> {
+    let user_id = args.trim().trim_start_matches('@');
+
+    if user_id.is_empty() || user_id.contains(' ') {
+        return None;
+    }
+
+    Some(Action::Moderate {
+        user_id: String::from(user_id),
+        moderation,
+    })
+}
+
+/// Parses `<user> <minutes>` into a mute action, zero minutes lifts the mute
+fn parse_mute(args: &str) -> Option<Action> {
+    let (user_id, minutes) = args.trim().split_once(' ')?;
+    let minutes: u64 = minutes.trim().parse().ok()?;
+
+    parse_moderation(
+        user_id,
+        Moderation::Mute {
+            duration_secs: minutes.saturating_mul(60),
+        },
+    )
+}
+
 impl Component for MessageInputBox {
     fn update_from_state(&mut self, state: &State) {
         self.props = Props::from(state);
@@ -100,6 +223,7 @@ impl ComponentRender<RenderProps> for MessageInputBox {
                 area: props.area,
                 border_color: props.border_color,
                 show_cursor: props.show_cursor,
+                is_secret: false,
             },
         )
     }
@@ -127,6 +251,34 @@ impl HasUsageInfo for MessageInputBox {
                         keys: vec!["Enter".into()],
                         description: "to send your message".into(),
                     },
+                    UsageInfoLine {
+                        keys: vec!["/msg <user> <message>".into()],
+                        description: "to message a user directly".into(),
+                    },
+                    UsageInfoLine {
+                        keys: vec!["/create <room> <description>".into()],
+                        description: "to create a new room".into(),
+                    },
+                    UsageInfoLine {
+                        keys: vec!["/delete <room>".into()],
+                        description: "to delete a room".into(),
+                    },
+                    UsageInfoLine {
+                        keys: vec![
+                            "/kick <user>".into(),
+                            "/ban <user>".into(),
+                            "/unban <user>".into(),
+                        ],
+                        description: "to moderate the active room".into(),
+                    },
+                    UsageInfoLine {
+                        keys: vec!["/mute <user> <minutes>".into()],
+                        description: "to mute a user, 0 minutes to unmute".into(),
+                    },
+                    UsageInfoLine {
+                        keys: vec!["/op <user>".into(), "/deop <user>".into()],
+                        description: "to change the role of a user".into(),
+                    },
                 ],
             }
         }
@@ -0,0 +1,275 @@
+use chrono::Local;
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
+use ratatui::{
+    prelude::{Backend, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+
+use super::super::{
+    chat_page::NO_ROOM_SELECTED_MESSAGE,
+    section::usage::{HasUsageInfo, UsageInfo, UsageInfoLine},
+};
+use crate::{
+    state_store::{action::Action, DeliveryStatus, MessageBoxItem, State},
+    ui_management::pages::chat_page::section::SectionActivation,
+};
+
+use crate::ui_management::components::{Component, ComponentRender};
+
+struct Props {
+    /// Currently active room
+    active_room: Option<String>,
+    /// Messages of the active room, from oldest to newest
+    messages: Vec<MessageBoxItem>,
+    /// Number of older messages put at the start of `messages` so far on scrollback
+    prepended_count: usize,
+    /// Whether the server has older messages for the active room
+    has_more_history: bool,
+    /// Whether older messages are being fetched for the active room
+    is_fetching_history: bool,
+}
+
+impl From<&State> for Props {
+    fn from(state: &State) -> Self {
+        let room_data = state
+            .active_room
+            .as_ref()
+            .and_then(|active_room| state.room_data_map.get(active_room));
+
+        Self {
+            active_room: state.active_room.clone(),
+            messages: room_data
+                .map(|room_data| room_data.iter_messages().cloned().collect())
+                .unwrap_or_default(),
+            prepended_count: room_data
+                .map(|room_data| room_data.prepended_count)
+                .unwrap_or(0),
+            has_more_history: room_data
+                .map(|room_data| room_data.has_more_history)
+                .unwrap_or(false),
+            is_fetching_history: room_data
+                .map(|room_data| room_data.is_fetching_history)
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// MessageList lists the messages of the active room, and lets the user scroll back through them
+pub struct MessageList {
+    /// State Mapped MessageList Props
+    props: Props,
+    // Internal Component State
+    /// List with optional selection, a message is only selected while scrolling
+    pub list_state: ListState,
+}
+
+impl MessageList {
+    pub(crate) fn new(state: &State) -> Self {
+        Self {
+            props: Props::from(state),
+            list_state: ListState::default(),
+        }
+    }
+
+    fn previous(&mut self) -> Action {
+        match self.list_state.selected() {
+            Some(0) => self.fetch_history(),
+            Some(i) => {
+                self.list_state.select(Some(i - 1));
+                Action::None
+            }
+            None => Action::None,
+        }
+    }
+
+    fn next(&mut self) {
+        if let Some(i) = self.list_state.selected() {
+            self.list_state.select(Some(
+                (i + 1).min(self.props.messages.len().saturating_sub(1)),
+            ));
+        }
+    }
+
+    fn select_latest(&mut self) {
+        *self.list_state.offset_mut() = 0;
+        self.list_state
+            .select(self.props.messages.len().checked_sub(1));
+    }
+
+    /// Scrolling past the oldest message we hold requests the previous page from the server
+    fn fetch_history(&self) -> Action {
+        match self.props.active_room.as_ref() {
+            Some(room) if self.props.has_more_history && !self.props.is_fetching_history => {
+                Action::FetchHistory { room: room.clone() }
+            }
+            _ => Action::None,
+        }
+    }
+}
+
+impl Component for MessageList {
+    fn update_from_state(&mut self, state: &State) {
+        let props = Props::from(state);
+
+        if props.active_room != self.props.active_room {
+            // a different room is shown, start from its latest message
+            self.props = props;
+            if self.list_state.selected().is_some() {
+                self.select_latest();
+            }
+        } else {
+            // keep the same message selected when older messages are prepended,
+            // messages moving from the recent ones to the scrollback do not shift it
+            let prepended = props
+                .prepended_count
+                .saturating_sub(self.props.prepended_count);
+            self.props = props;
+            if let Some(i) = self.list_state.selected() {
+                self.list_state.select(Some(i + prepended));
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "Message List"
+    }
+
+    fn handle_key_event(&mut self, key: KeyEvent) -> Action {
+        if key.kind != KeyEventKind::Press {
+            return Action::None;
+        }
+
+        match key.code {
+            KeyCode::Up => self.previous(),
+            KeyCode::Down => {
+                self.next();
+                Action::None
+            }
+            KeyCode::End => {
+                self.select_latest();
+                Action::None
+            }
+            _ => Action::None,
+        }
+    }
+}
+
+impl SectionActivation for MessageList {
+    fn activate(&mut self) {
+        self.select_latest();
+    }
+
+    fn deactivate(&mut self) {
+        *self.list_state.offset_mut() = 0;
+        self.list_state.select(None);
+    }
+}
+
+pub struct RenderProps {
+    pub border_color: Color,
+    pub area: Rect,
+}
+
+fn message_box_item_to_list_item(mbi: &MessageBoxItem) -> ListItem<'_> {
+    let line = match mbi {
+        MessageBoxItem::Message {
+            timestamp,
+            user_id,
+            content,
+            delivery,
+            ..
+        } => Line::from(vec![
+            // messages are stamped in UTC by the server, show them in the local time of the user
+            Span::raw(timestamp.with_timezone(&Local).format("%H:%M ").to_string()).dim(),
+            Span::raw(format!("@{}: {}", user_id, content)),
+            match delivery {
+                DeliveryStatus::Pending { .. } => Span::raw(" (sending...)").dim(),
+                DeliveryStatus::Failed => Span::raw(" (failed)").red(),
+                DeliveryStatus::Sent | DeliveryStatus::Delivered => Span::raw(""),
+            },
+        ]),
+        MessageBoxItem::Notification(content) => Line::from(Span::raw(content.clone()).italic()),
+    };
+
+    ListItem::new(line)
+}
+
+impl ComponentRender<RenderProps> for MessageList {
+    fn render<B: Backend>(&self, frame: &mut Frame<B>, props: RenderProps) {
+        let title = if self.props.is_fetching_history {
+            "Messages (loading older messages...)"
+        } else {
+            "Messages"
+        };
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::new().fg(props.border_color))
+            .title(title);
+
+        if self.props.active_room.is_none() {
+            let list =
+                List::new(vec![ListItem::new(Line::from(NO_ROOM_SELECTED_MESSAGE))]).block(block);
+            frame.render_widget(list, props.area);
+
+            return;
+        }
+
+        if self.list_state.selected().is_some() {
+            let list = List::new(
+                self.props
+                    .messages
+                    .iter()
+                    .map(message_box_item_to_list_item)
+                    .collect::<Vec<ListItem>>(),
+            )
+            .block(block)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+            let mut list_state = self.list_state.clone();
+            frame.render_stateful_widget(list, props.area, &mut list_state);
+        } else {
+            // go back by (container height + 2 for borders) to only show the latest messages
+            let message_offset = self
+                .props
+                .messages
+                .len()
+                .saturating_sub((props.area.height as usize).saturating_sub(2));
+
+            let list = List::new(
+                self.props
+                    .messages
+                    .iter()
+                    .skip(message_offset)
+                    .map(message_box_item_to_list_item)
+                    .collect::<Vec<ListItem>>(),
+            )
+            .block(block);
+            frame.render_widget(list, props.area);
+        }
+    }
+}
+
+impl HasUsageInfo for MessageList {
+    fn usage_info(&self) -> UsageInfo {
+        UsageInfo {
+            description: Some("Scroll through the messages of the active room".into()),
+            lines: vec![
+                UsageInfoLine {
+                    keys: vec!["Esc".into()],
+                    description: "to cancel".into(),
+                },
+                UsageInfoLine {
+                    keys: vec!["↑".into(), "↓".into()],
+                    description: "to scroll, older messages load at the top".into(),
+                },
+                UsageInfoLine {
+                    keys: vec!["End".into()],
+                    description: "to jump to the latest message".into(),
+                },
+            ],
+        }
+    }
+}
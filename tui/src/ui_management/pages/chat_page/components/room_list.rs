@@ -17,6 +17,7 @@ use crate::ui_management::components::{Component, ComponentRender};
 
 pub struct RoomState {
     pub name: String,
+    pub is_direct: bool,
     pub description: String,
     pub has_joined: bool,
     pub has_unread: bool,
@@ -36,13 +37,17 @@ impl From<&State> for Props {
             .iter()
             .map(|(name, room_data)| RoomState {
                 name: name.clone(),
+                is_direct: room_data.direct_user_id.is_some(),
                 description: room_data.description.clone(),
                 has_joined: room_data.has_joined,
                 has_unread: room_data.has_unread,
             })
             .collect::<Vec<RoomState>>();
 
-        rooms.sort_by(|room_a, room_b| room_a.name.cmp(&room_b.name));
+        // rooms first, then the direct message conversations
+        rooms.sort_by(|room_a, room_b| {
+            (room_a.is_direct, &room_a.name).cmp(&(room_b.is_direct, &room_b.name))
+        });
 
         Self {
             rooms,
@@ -118,6 +123,13 @@ impl RoomList {
 impl Component for RoomList {
     fn update_from_state(&mut self, state: &State) {
         self.props = Props::from(state);
+
+        // rooms can be deleted while one of them is selected, keep the selection within the list
+        if let Some(idx) = self.list_state.selected() {
+            if idx >= self.rooms().len() {
+                self.list_state.select(self.rooms().len().checked_sub(1));
+            }
+        }
     }
 
     fn name(&self) -> &str {
@@ -184,8 +196,10 @@ impl ComponentRender<RenderProps> for RoomList {
             .rooms()
             .iter()
             .map(|room_state| {
+                // direct message conversations are already keyed with '@'
                 let room_tag = format!(
-                    "#{}{}",
+                    "{}{}{}",
+                    if room_state.is_direct { "" } else { "#" },
                     room_state.name,
                     if room_state.has_unread { "*" } else { "" }
                 );
@@ -2,7 +2,10 @@ use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use ratatui::{prelude::*, widgets::*, Frame};
 
 use crate::{
-    state_store::{action::Action, ServerConnectionStatus, State},
+    state_store::{
+        action::{Action, AuthMode},
+        ServerConnectionStatus, State,
+    },
     ui_management::components::{
         input_box::{self, InputBox},
         Component, ComponentRender,
@@ -27,12 +30,43 @@ impl From<&State> for Props {
     }
 }
 
+/// The input fields of the connect page, in the order they are cycled through
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Addr,
+    Username,
+    Password,
+}
+
+impl Field {
+    fn next(self) -> Self {
+        match self {
+            Field::Addr => Field::Username,
+            Field::Username => Field::Password,
+            Field::Password => Field::Addr,
+        }
+    }
+
+    fn previous(self) -> Self {
+        match self {
+            Field::Addr => Field::Password,
+            Field::Username => Field::Addr,
+            Field::Password => Field::Username,
+        }
+    }
+}
+
 /// ConnectPage handles the connection to the server
 pub struct ConnectPage {
     // Mapped Props from State
     props: Props,
+    // Internal State
+    /// The field that currently receives the key events
+    focused_field: Field,
     // Internal Components
-    input_box: InputBox,
+    addr_input_box: InputBox,
+    username_input_box: InputBox,
+    password_input_box: InputBox,
 }
 
 impl ConnectPage {
@@ -40,22 +74,47 @@ impl ConnectPage {
     where
         Self: Sized,
     {
-        let mut input_box = InputBox::new();
-        input_box.set_text(DEFAULT_SERVER_ADDR);
+        let mut addr_input_box = InputBox::new();
+        addr_input_box.set_text(DEFAULT_SERVER_ADDR);
 
         ConnectPage {
             props: Props::from(state),
-            input_box,
+            focused_field: Field::Username,
+            addr_input_box,
+            username_input_box: InputBox::new(),
+            password_input_box: InputBox::new(),
+        }
+    }
+
+    fn get_input_box_mut(&mut self, field: Field) -> &mut InputBox {
+        match field {
+            Field::Addr => &mut self.addr_input_box,
+            Field::Username => &mut self.username_input_box,
+            Field::Password => &mut self.password_input_box,
         }
     }
 
-    fn connect_to_server(&mut self) -> Action {
-        if self.input_box.is_empty() {
+    fn connect_to_server(&mut self, auth_mode: AuthMode) -> Action {
+        if self.addr_input_box.is_empty()
+            || self.username_input_box.is_empty()
+            || self.password_input_box.is_empty()
+        {
             return Action::None;
         }
 
         Action::ConnectToServerRequest {
-            addr: self.input_box.text().to_string(),
+            addr: self.addr_input_box.text().to_string(),
+            username: self.username_input_box.text().to_string(),
+            password: self.password_input_box.text().to_string(),
+            auth_mode,
+        }
+    }
+
+    fn calculate_border_color(&self, field: Field) -> Color {
+        if self.focused_field == field {
+            Color::Yellow
+        } else {
+            Color::Reset
         }
     }
 }
@@ -72,18 +131,33 @@ impl Component for ConnectPage {
     }
 
     fn handle_key_event(&mut self, key: KeyEvent) -> Action {
-        let action = self.input_box.handle_key_event(key);
-        assert_eq!(action, Action::None);
-
         if key.kind != KeyEventKind::Press {
             return Action::None;
         }
 
         match key.code {
-            KeyCode::Enter => self.connect_to_server(),
-            KeyCode::Char('q') => Action::Exit,
+            KeyCode::Enter => self.connect_to_server(AuthMode::Login),
+            KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.connect_to_server(AuthMode::Register)
+            }
+            KeyCode::Tab | KeyCode::Down => {
+                self.focused_field = self.focused_field.next();
+                Action::None
+            }
+            KeyCode::BackTab | KeyCode::Up => {
+                self.focused_field = self.focused_field.previous();
+                Action::None
+            }
+            KeyCode::Esc => Action::Exit,
             KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Action::Exit,
-            _ => Action::None,
+            _ => {
+                let action = self
+                    .get_input_box_mut(self.focused_field)
+                    .handle_key_event(key);
+                assert_eq!(action, Action::None);
+
+                Action::None
+            }
         }
     }
 }
@@ -120,11 +194,13 @@ impl ComponentRender<()> for ConnectPage {
             panic!("The horizontal layout should have 3 chunks")
         };
 
-        let [container_addr_input, container_help_text, container_error_message] =
+        let [container_addr_input, container_username_input, container_password_input, container_help_text, container_error_message] =
             *Layout::default()
                 .direction(Direction::Vertical)
                 .constraints(
                     [
+                        Constraint::Length(3),
+                        Constraint::Length(3),
                         Constraint::Length(3),
                         Constraint::Length(3),
                         Constraint::Min(1),
@@ -133,24 +209,57 @@ impl ComponentRender<()> for ConnectPage {
                 )
                 .split(both_centered)
         else {
-            panic!("The left layout should have 3 chunks")
+            panic!("The left layout should have 5 chunks")
         };
 
-        self.input_box.render(
-            frame,
-            input_box::RenderProps {
-                title: "Server Host and Port".into(),
-                area: container_addr_input,
-                border_color: Color::Yellow,
-                show_cursor: true,
-            },
-        );
+        for (field, field_input_box, title, area) in [
+            (
+                Field::Addr,
+                &self.addr_input_box,
+                "Server Host and Port, or unix:/path",
+                container_addr_input,
+            ),
+            (
+                Field::Username,
+                &self.username_input_box,
+                "Username",
+                container_username_input,
+            ),
+            (
+                Field::Password,
+                &self.password_input_box,
+                "Password",
+                container_password_input,
+            ),
+        ] {
+            field_input_box.render(
+                frame,
+                input_box::RenderProps {
+                    title: title.into(),
+                    area,
+                    border_color: self.calculate_border_color(field),
+                    show_cursor: self.focused_field == field,
+                    is_secret: field == Field::Password,
+                },
+            );
+        }
 
-        let help_text = Paragraph::new(Text::from(Line::from(vec![
-            "Press ".into(),
-            "<Enter>".bold(),
-            " to connect".into(),
-        ])));
+        let help_text = Paragraph::new(Text::from(vec![
+            Line::from(vec![
+                "Press ".into(),
+                "<Enter>".bold(),
+                " to log in, ".into(),
+                "<Ctrl+R>".bold(),
+                " to register".into(),
+            ]),
+            Line::from(vec![
+                "Press ".into(),
+                "<Tab>".bold(),
+                " to switch fields, ".into(),
+                "<Esc>".bold(),
+                " to exit".into(),
+            ]),
+        ]));
         frame.render_widget(help_text, container_help_text);
 
         let error_message = Paragraph::new(if let Some(err) = self.props.error_message.as_ref() {
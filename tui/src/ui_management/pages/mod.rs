@@ -23,7 +23,9 @@ impl From<&State> for Props {
     fn from(state: &State) -> Self {
         Props {
             active_page: match state.server_connection_status {
-                ServerConnectionStatus::Connected { .. } => ActivePage::ChatPage,
+                // the chat stays on screen while the lost connection is being picked up again
+                ServerConnectionStatus::Connected { .. }
+                | ServerConnectionStatus::Reconnecting { .. } => ActivePage::ChatPage,
                 _ => ActivePage::ConnectPage,
             },
         }